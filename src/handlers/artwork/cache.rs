@@ -0,0 +1,126 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Remembers paths without artwork, to avoid querying the API
+/// again for them until the ttl expires
+pub struct MissingArtCache {
+    ttl: Duration,
+    entries: HashMap<String, Instant>,
+}
+
+impl MissingArtCache {
+    pub fn new(ttl: Duration) -> Self {
+        MissingArtCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the path was recently found to have no artwork
+    pub fn contains(&mut self, path: &str) -> bool {
+        match self.entries.get(path) {
+            Some(inserted) if inserted.elapsed() < self.ttl => true,
+            Some(_) => {
+                self.entries.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, path: String) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, inserted| inserted.elapsed() < ttl);
+        self.entries.insert(path, Instant::now());
+    }
+}
+
+/// Keeps the most recently served images in memory, to avoid reading them
+/// from disk for every chunk. Images larger than max_image_size are not kept.
+pub struct MemoryArtCache {
+    capacity: usize,
+    max_image_size: usize,
+    size: usize,
+    entries: VecDeque<(String, Arc<[u8]>)>, // Most recently used last
+}
+
+impl MemoryArtCache {
+    pub fn new(capacity: usize, max_image_size: usize) -> Self {
+        MemoryArtCache {
+            capacity,
+            max_image_size: max_image_size.min(capacity),
+            size: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn max_image_size(&self) -> usize {
+        self.max_image_size
+    }
+
+    pub fn get(&mut self, art_id: &str) -> Option<Arc<[u8]>> {
+        let index = self.entries.iter().position(|(id, _)| id == art_id)?;
+        let entry = self.entries.remove(index)?;
+        let data = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(data)
+    }
+
+    /// Returns false if the image is too large to be kept
+    pub fn insert(&mut self, art_id: String, data: Arc<[u8]>) -> bool {
+        if data.len() > self.max_image_size {
+            return false;
+        }
+        if let Some(index) = self.entries.iter().position(|(id, _)| *id == art_id) {
+            if let Some((_, old)) = self.entries.remove(index) {
+                self.size -= old.len();
+            }
+        }
+        self.size += data.len();
+        self.entries.push_back((art_id, data));
+        while self.size > self.capacity {
+            match self.entries.pop_front() {
+                Some((_, evicted)) => self.size -= evicted.len(),
+                None => break,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "internal/album/4IOXEu8EgItKI8J9JDaEr4";
+
+    #[test]
+    fn it_expires_entries() {
+        let mut cache = MissingArtCache::new(Duration::from_millis(10));
+        cache.insert(PATH.to_string());
+        assert!(cache.contains(PATH));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!cache.contains(PATH));
+    }
+
+    #[test]
+    fn it_evicts_least_recently_used_images() {
+        let mut cache = MemoryArtCache::new(10, 5);
+        assert!(cache.insert("a".to_string(), Arc::from(&b"aaaa"[..])));
+        assert!(cache.insert("b".to_string(), Arc::from(&b"bbbb"[..])));
+        assert!(cache.get("a").is_some());
+        assert!(cache.insert("c".to_string(), Arc::from(&b"cccc"[..])));
+
+        assert_eq!(Some(Arc::from(&b"aaaa"[..])), cache.get("a"));
+        assert_eq!(None, cache.get("b"));
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn it_skips_large_images() {
+        let mut cache = MemoryArtCache::new(10, 5);
+        assert!(!cache.insert("a".to_string(), Arc::from(&b"aaaaaa"[..])));
+        assert_eq!(None, cache.get("a"));
+    }
+}
@@ -1,4 +1,4 @@
-use aspotify::{Album, Artist, Response, Show};
+use aspotify::{Album, Artist, Episode, Response, Show, Track};
 
 pub trait ExtractArt {
     fn get_art(&self) -> Option<String>;
@@ -12,18 +12,30 @@ impl<T: ExtractArt> ExtractArt for Response<T> {
 
 impl ExtractArt for Album {
     fn get_art(&self) -> Option<String> {
-        self.images.get(0).map(|i| i.url.clone())
+        self.images.first().map(|i| i.url.clone())
     }
 }
 
 impl ExtractArt for Show {
     fn get_art(&self) -> Option<String> {
-        self.images.get(0).map(|i| i.url.clone())
+        self.images.first().map(|i| i.url.clone())
     }
 }
 
 impl ExtractArt for Artist {
     fn get_art(&self) -> Option<String> {
-        self.images.get(0).map(|i| i.url.clone())
+        self.images.first().map(|i| i.url.clone())
+    }
+}
+
+impl ExtractArt for Track {
+    fn get_art(&self) -> Option<String> {
+        self.album.images.first().map(|i| i.url.clone())
+    }
+}
+
+impl ExtractArt for Episode {
+    fn get_art(&self) -> Option<String> {
+        self.images.first().map(|i| i.url.clone())
     }
 }
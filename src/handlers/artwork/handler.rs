@@ -1,35 +1,57 @@
+use crate::handlers::artwork::cache::{MemoryArtCache, MissingArtCache};
 use crate::handlers::artwork::extract::ExtractArt;
 use crate::mpd_protocol::*;
-use crate::util::Settings;
+use crate::util::{Settings, METRICS};
 use aspotify::Client;
 use log::{debug, warn};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
+use std::future::Future;
+use std::io::{Cursor, Read, Seek, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::SeekFrom;
 use tokio::sync::mpsc;
 
+/// Served when no artwork is found, if enabled in the settings
+static PLACEHOLDER: &[u8] = include_bytes!("placeholder.png");
+
 pub struct ArtworkHandler {
     command_rx: mpsc::Receiver<HandlerInput>,
     client: Arc<Client>,
     cache_path: PathBuf,
     max_chunk_size: u64,
+    use_placeholder: bool,
+    missing_art: MissingArtCache,
+    memory_art: MemoryArtCache,
 }
 
+/// Artwork source, either a file or an image held in memory
+trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
 impl ArtworkHandler {
     pub async fn new(
         settings: &Settings,
         client: Arc<Client>,
     ) -> (Self, mpsc::Sender<HandlerInput>) {
-        let (command_tx, command_rx) = mpsc::channel(16);
-        let cache_path = settings.cache_root_path().join("artwork");
+        let (command_tx, command_rx) = mpsc::channel(settings.handler_queue_size);
+        let cache_path = settings.artwork_cache_path();
+        METRICS.artwork_disk_usage(dir_size(&cache_path));
         (
             ArtworkHandler {
                 command_rx,
                 client,
                 cache_path,
                 max_chunk_size: settings.artwork_chunk_size(),
+                use_placeholder: settings.artwork_placeholder,
+                missing_art: MissingArtCache::new(Duration::from_secs(
+                    settings.artwork_missing_ttl_seconds,
+                )),
+                memory_art: MemoryArtCache::new(
+                    settings.artwork_memory_cache_size(),
+                    settings.artwork_memory_max_image_size(),
+                ),
             },
             command_tx,
         )
@@ -47,27 +69,25 @@ impl ArtworkHandler {
 
     async fn execute(&mut self, command: Command) -> HandlerResult {
         match command {
-            Command::AlbumArt(path, offset) => {
-                let mut art = self.get_art(path).await?;
-                let size = art.seek(SeekFrom::End(0))?;
-                let chunk_size = self.max_chunk_size.min(size - offset) as usize;
-                let mut data = vec![0; chunk_size];
-
-                art.seek(SeekFrom::Start(offset))?;
-                art.read_exact(data.as_mut())?;
-
-                return Ok(HandlerOutput::Binary(size, data));
-            }
+            Command::AlbumArt(path, offset) => match self.get_art(path).await {
+                Ok(art) => read_chunk(art, offset, self.max_chunk_size),
+                Err(HandlerError::Unsupported) if self.use_placeholder => {
+                    read_chunk(Cursor::new(PLACEHOLDER), offset, self.max_chunk_size)
+                }
+                Err(err) => Err(err),
+            },
             _ => Err(HandlerError::Unsupported),
         }
     }
 
-    async fn get_art(&mut self, path: Path) -> Result<File, HandlerError> {
+    async fn get_art(&mut self, path: Path) -> Result<Box<dyn ReadSeek>, HandlerError> {
         let (art_id, art_url) = self.resolve_art_url(&path).await?;
-        let path = self.cache_path.join(art_id);
+        let path = cache_file(&self.cache_path, &art_id)?;
 
-        if path.exists() {
-            return File::open(&path).map_err(HandlerError::IoError);
+        let cached = open_cached(&mut self.memory_art, &path, &art_id)?;
+        METRICS.artwork_cache(cached.is_some());
+        if let Some(art) = cached {
+            return Ok(art);
         }
 
         let art = reqwest::get(&art_url).await?.bytes().await?;
@@ -77,23 +97,277 @@ impl ArtworkHandler {
             .create(true)
             .open(&path)?;
         file.write_all(&art)?;
-        Ok(file)
+        METRICS.artwork_written(art.len() as u64);
+        match self.memory_art.insert(art_id, Arc::from(art.as_ref())) {
+            true => Ok(Box::new(Cursor::new(art))),
+            false => Ok(Box::new(file)),
+        }
     }
 
+    /// Resolves the artwork url, remembering paths without artwork
     async fn resolve_art_url(&mut self, path: &Path) -> Result<(String, String), HandlerError> {
-        if let Path::Internal(items) = path {
-            for (item_type, id) in items.iter().rev() {
-                let artwork = match item_type {
-                    ItemType::Album => self.client.albums().get_album(id, None).await?.get_art(),
-                    ItemType::Show => self.client.shows().get_show(id, None).await?.get_art(),
-                    ItemType::Artist => self.client.artists().get_artist(id).await?.get_art(),
-                    _ => None,
-                };
-                if let Some(url) = artwork {
-                    return Ok((id.to_string(), url));
+        let client = self.client.clone();
+        self.resolve_art_url_with(path, |path| lookup_art_url(client, path))
+            .await
+    }
+
+    /// Implements resolve_art_url with the given lookup
+    async fn resolve_art_url_with<'a, F, R>(
+        &mut self,
+        path: &'a Path,
+        lookup: F,
+    ) -> Result<(String, String), HandlerError>
+    where
+        F: FnOnce(&'a Path) -> R,
+        R: Future<Output = Result<(String, String), HandlerError>>,
+    {
+        let key = path.to_string();
+        if self.missing_art.contains(&key) {
+            debug!["No artwork for {}, cached", key];
+            return Err(HandlerError::Unsupported);
+        }
+        let result = lookup(path).await;
+        match result {
+            Err(HandlerError::Unsupported) => self.missing_art.insert(key),
+            _ => METRICS.spotify_request(result.is_err()),
+        }
+        result
+    }
+}
+
+/// Returns the artwork id and url, see art_lookup_order for the precedence.
+/// Track artwork is keyed by its album id, to share the cached file with the album.
+async fn lookup_art_url(
+    client: Arc<Client>,
+    path: &Path,
+) -> Result<(String, String), HandlerError> {
+    if let Path::Internal(items) = path {
+        for (item_type, id) in art_lookup_order(items) {
+            let artwork = match item_type {
+                ItemType::Album => client.albums().get_album(id, None).await?.get_art(),
+                ItemType::Show => client.shows().get_show(id, None).await?.get_art(),
+                ItemType::Artist => client.artists().get_artist(id).await?.get_art(),
+                ItemType::Episode => client.episodes().get_episode(id, None).await?.get_art(),
+                ItemType::Track => {
+                    let track = client.tracks().get_track(id, None).await?.data;
+                    let art_id = track.album.id.as_deref().unwrap_or(id);
+                    if let Some(url) = track.get_art() {
+                        return Ok((art_id.to_string(), url));
+                    }
+                    None
                 }
+            };
+            if let Some(url) = artwork {
+                return Ok((id.to_string(), url));
             }
         }
-        Err(HandlerError::Unsupported)
+    }
+    Err(HandlerError::Unsupported)
+}
+
+/// Orders the path items to look artwork up from: albums, shows and artists first,
+/// innermost first, as their cover is what clients expect. Tracks and episodes come
+/// last, as fallbacks for paths without a container holding artwork.
+fn art_lookup_order(items: &[(ItemType, String)]) -> Vec<(&ItemType, &str)> {
+    let (items, containers): (Vec<_>, Vec<_>) = items
+        .iter()
+        .rev()
+        .map(|(item_type, id)| (item_type, id.as_str()))
+        .partition(|(item_type, _)| matches!(item_type, ItemType::Track | ItemType::Episode));
+    containers.into_iter().chain(items).collect()
+}
+
+/// Returns the cache file for an artwork id. Spotify ids are base62, anything else
+/// is rejected so that crafted ids cannot point outside of the cache directory.
+fn cache_file(cache_path: &std::path::Path, art_id: &str) -> Result<PathBuf, HandlerError> {
+    match !art_id.is_empty() && art_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Ok(cache_path.join(art_id)),
+        false => Err(HandlerError::InvalidArgument(format!(
+            "Invalid artwork id {}",
+            art_id
+        ))),
+    }
+}
+
+/// Sums the size of the files in a directory, ignoring unreadable entries
+fn dir_size(path: &std::path::Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.and_then(|e| e.metadata()).ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Opens a cached image, trying the memory cache before the disk one.
+/// Small images read from disk are kept in memory for the next chunks.
+fn open_cached(
+    memory_art: &mut MemoryArtCache,
+    path: &std::path::Path,
+    art_id: &str,
+) -> Result<Option<Box<dyn ReadSeek>>, HandlerError> {
+    if let Some(data) = memory_art.get(art_id) {
+        return Ok(Some(Box::new(Cursor::new(data))));
+    }
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() as usize <= memory_art.max_image_size() {
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        let data: Arc<[u8]> = Arc::from(data);
+        memory_art.insert(art_id.to_string(), data.clone());
+        return Ok(Some(Box::new(Cursor::new(data))));
+    }
+    Ok(Some(Box::new(file)))
+}
+
+/// Reads a chunk of at most max_chunk_size bytes, starting at offset.
+/// Like MPD, reading at the end returns an empty chunk, reading past it fails.
+fn read_chunk(mut art: impl Read + Seek, offset: u64, max_chunk_size: u64) -> HandlerResult {
+    let size = art.seek(SeekFrom::End(0))?;
+    if offset > size {
+        return Err(HandlerError::InvalidArgument("Offset too large".into()));
+    }
+    let chunk_size = max_chunk_size.min(size - offset) as usize;
+    let mut data = vec![0; chunk_size];
+
+    art.seek(SeekFrom::Start(offset))?;
+    art.read_exact(data.as_mut())?;
+
+    Ok(HandlerOutput::Binary(size, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_chunk(result: HandlerResult, expected_size: u64, expected_data: &[u8]) {
+        match result {
+            Ok(HandlerOutput::Binary(size, data)) => {
+                assert_eq!(expected_size, size);
+                assert_eq!(expected_data, data.as_slice());
+            }
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[test]
+    fn it_reads_chunks() {
+        let art = Cursor::new(b"0123456789");
+        assert_chunk(read_chunk(art.clone(), 0, 4), 10, b"0123");
+        assert_chunk(read_chunk(art.clone(), 4, 4), 10, b"4567");
+        assert_chunk(read_chunk(art, 8, 4), 10, b"89");
+    }
+
+    #[test]
+    fn it_reads_past_the_end() {
+        let art = Cursor::new(b"0123456789");
+        assert_chunk(read_chunk(art.clone(), 10, 4), 10, b"");
+        assert!(matches!(
+            read_chunk(art, 11, 4),
+            Err(HandlerError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_huge_offsets() {
+        let art = Cursor::new(b"0123456789");
+        let err = read_chunk(art, u64::MAX, 4).expect_err("Offset should be rejected");
+        assert_eq!(AckCode::Arg, err.ack_code());
+    }
+
+    #[test]
+    fn it_prefers_album_art_for_tracks() {
+        let path = Path::for_track("album_id", "track_id");
+        let items = match &path {
+            Path::Internal(items) => items,
+            Path::Empty => unreachable!(),
+        };
+        assert_eq!(
+            vec![
+                (&ItemType::Album, "album_id"),
+                (&ItemType::Track, "track_id")
+            ],
+            art_lookup_order(items)
+        );
+    }
+
+    #[test]
+    fn it_keeps_cache_files_in_the_cache_dir() {
+        let cache_path = PathBuf::from("/cache/artwork");
+        assert_eq!(
+            cache_path.join("4IOXEu8EgItKI8J9JDaEr4"),
+            cache_file(&cache_path, "4IOXEu8EgItKI8J9JDaEr4").unwrap()
+        );
+        for art_id in &["../../etc/passwd", "..", "a/b", "/etc/passwd", ""] {
+            assert!(
+                matches!(
+                    cache_file(&cache_path, art_id),
+                    Err(HandlerError::InvalidArgument(_))
+                ),
+                "{} should be rejected",
+                art_id
+            );
+        }
+    }
+
+    #[test]
+    fn it_serves_small_images_from_memory() {
+        let cache_path =
+            std::env::temp_dir().join(format!["mpdify-artwork-{}", std::process::id()]);
+        std::fs::create_dir_all(&cache_path).unwrap();
+        let path = cache_path.join("art_id");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        assert_eq!(10, dir_size(&cache_path));
+
+        let mut memory_art = MemoryArtCache::new(1024, 1024);
+        let art = open_cached(&mut memory_art, &path, "art_id").unwrap();
+        assert_chunk(read_chunk(art.unwrap(), 0, 4), 10, b"0123");
+
+        // Second read must not touch the disk
+        std::fs::remove_dir_all(&cache_path).unwrap();
+        let art = open_cached(&mut memory_art, &path, "art_id").unwrap();
+        assert_chunk(read_chunk(art.unwrap(), 4, 4), 10, b"4567");
+        assert!(open_cached(&mut memory_art, &path, "other_id")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_serves_the_whole_placeholder() {
+        let size = PLACEHOLDER.len() as u64;
+        assert_chunk(
+            read_chunk(Cursor::new(PLACEHOLDER), 0, size),
+            size,
+            PLACEHOLDER,
+        );
+    }
+
+    #[tokio::test]
+    async fn it_only_looks_up_once() {
+        let settings = Settings::with(config::Config::new()).unwrap();
+        let client = Arc::new(Client::new(aspotify::ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let (mut handler, _) = ArtworkHandler::new(&settings, client).await;
+        let path: Path = "internal/album/4IOXEu8EgItKI8J9JDaEr4".parse().unwrap();
+
+        let mut lookups = 0;
+        for _ in 0..2 {
+            let lookup = |_: &Path| {
+                lookups += 1;
+                async { Err(HandlerError::Unsupported) }
+            };
+            let result = handler.resolve_art_url_with(&path, lookup).await;
+            assert!(matches!(result, Err(HandlerError::Unsupported)));
+        }
+        assert_eq!(1, lookups);
     }
 }
@@ -1,8 +1,9 @@
-use crate::mpd_protocol::{HandlerError, HandlerOutput, HandlerResult};
+use crate::mpd_protocol::{AuthStatusResponse, HandlerError, HandlerOutput, HandlerResult};
 use crate::util::Settings;
-use aspotify::Scope;
-use log::debug;
+use aspotify::{Scope, Subscription};
+use log::{debug, warn};
 use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 static REFRESH_TOKEN_FILE: &str = ".refresh_token";
@@ -11,57 +12,132 @@ pub struct AuthStatus {
     client: Arc<aspotify::Client>,
     auth_path: String,
     auth_state: Option<String>,
+    product: Option<Subscription>,
+    authenticated: Arc<AtomicBool>,
 }
 
 impl AuthStatus {
     pub async fn new(settings: &Settings, client: Arc<aspotify::Client>) -> Self {
         // Try to read refresh token from file
-        if let Ok(token) = fs::read_to_string(REFRESH_TOKEN_FILE) {
+        let restored = if let Ok(token) = fs::read_to_string(REFRESH_TOKEN_FILE) {
             debug!["Restoring refresh token from file"];
             client.set_refresh_token(Some(token)).await;
+            true
         } else {
             debug!["No refresh token found, we will need user input"];
+            false
+        };
+
+        AuthStatus {
+            client,
+            auth_path: settings.auth_path(),
+            auth_state: None,
+            product: None,
+            authenticated: Arc::new(AtomicBool::new(restored)),
         }
+    }
 
+    /// Builds the status of a user already authenticated on the given product, for tests
+    #[cfg(test)]
+    pub async fn signed_in(
+        settings: &Settings,
+        client: Arc<aspotify::Client>,
+        product: Subscription,
+    ) -> Self {
+        client.set_refresh_token(Some("refresh_token".into())).await;
         AuthStatus {
             client,
             auth_path: settings.auth_path(),
             auth_state: None,
+            product: Some(product),
+            authenticated: Arc::new(AtomicBool::new(true)),
         }
     }
 
+    /// Returns a flag reflecting the latest known auth state, for health checks
+    pub fn authenticated(&self) -> Arc<AtomicBool> {
+        self.authenticated.clone()
+    }
+
+    /// Returns AuthNeeded if the user needs to authenticate
     pub async fn check(&mut self) -> HandlerResult {
         match self.client.refresh_token().await {
             None => {
-                let (url, state) = aspotify::authorization_url(
-                    &self.client.credentials.id,
-                    vec![
-                        Scope::UserReadPlaybackState,
-                        Scope::UserModifyPlaybackState,
-                        Scope::UserReadCurrentlyPlaying,
-                        Scope::Streaming,
-                        Scope::AppRemoteControl,
-                        Scope::PlaylistReadCollaborative,
-                        Scope::PlaylistModifyPublic,
-                        Scope::PlaylistReadPrivate,
-                        Scope::PlaylistModifyPrivate,
-                        Scope::UserLibraryModify,
-                        Scope::UserLibraryRead,
-                        Scope::UserTopRead,
-                        Scope::UserReadRecentlyPlayed,
-                        Scope::UserReadPlaybackPosition,
-                        Scope::UserFollowRead,
-                        Scope::UserFollowModify,
-                    ]
-                    .iter()
-                    .copied(),
-                    true,
-                    self.auth_path.as_str(),
-                );
-                self.auth_state = Some(state);
-                Err(HandlerError::AuthNeeded(url))
+                self.authenticated.store(false, Ordering::Release);
+                Err(HandlerError::AuthNeeded(self.authorization_url()))
+            }
+            Some(_) => {
+                self.authenticated.store(true, Ordering::Release);
+                if self.product.is_none() {
+                    self.retrieve_product().await;
+                }
+                Ok(HandlerOutput::Ok)
+            }
+        }
+    }
+
+    /// Reports the authentication status without erroring, for UIs
+    pub async fn status(&mut self) -> HandlerResult {
+        let auth_url = match self.check().await {
+            Ok(_) => None,
+            Err(HandlerError::AuthNeeded(url)) => Some(url),
+            Err(err) => return Err(err),
+        };
+        Ok(HandlerOutput::from(AuthStatusResponse {
+            authenticated: auth_url.is_none(),
+            auth_url,
+        }))
+    }
+
+    fn authorization_url(&mut self) -> String {
+        let (url, state) = aspotify::authorization_url(
+            &self.client.credentials.id,
+            vec![
+                Scope::UserReadPlaybackState,
+                Scope::UserModifyPlaybackState,
+                Scope::UserReadCurrentlyPlaying,
+                Scope::Streaming,
+                Scope::AppRemoteControl,
+                Scope::PlaylistReadCollaborative,
+                Scope::PlaylistModifyPublic,
+                Scope::PlaylistReadPrivate,
+                Scope::PlaylistModifyPrivate,
+                Scope::UserLibraryModify,
+                Scope::UserLibraryRead,
+                Scope::UserTopRead,
+                Scope::UserReadRecentlyPlayed,
+                Scope::UserReadPlaybackPosition,
+                Scope::UserFollowRead,
+                Scope::UserFollowModify,
+                Scope::UserReadPrivate,
+            ]
+            .iter()
+            .copied(),
+            true,
+            self.auth_path.as_str(),
+        );
+        self.auth_state = Some(state);
+        url
+    }
+
+    /// Returns the subscription level of the user, if known
+    pub fn product(&self) -> Option<Subscription> {
+        self.product
+    }
+
+    /// Returns false only if the user is known to be on a free account,
+    /// that cannot control playback through the Web API
+    pub fn is_premium(&self) -> bool {
+        self.product != Some(Subscription::Free)
+    }
+
+    async fn retrieve_product(&mut self) {
+        match self.client.users_profile().get_current_user().await {
+            Ok(user) => {
+                debug!["Current user has product {:?}", user.data.product];
+                self.product = user.data.product;
             }
-            Some(_) => Ok(HandlerOutput::Ok),
+            Err(err) => warn!["Cannot retrieve user profile: {}", err],
         }
     }
 
@@ -84,6 +160,7 @@ impl AuthStatus {
                 .unwrap();
 
                 debug!["Successfully authenticated"];
+                self.authenticated.store(true, Ordering::Release);
                 Ok(HandlerOutput::Ok)
             }
             Err(err) => {
@@ -1,15 +1,23 @@
-use crate::mpd_protocol::IdleSubsystem;
-use crate::util::IdleBus;
+use crate::handlers::aspotify::retry::{retry_once, RETRY_DELAY};
+use crate::mpd_protocol::{song_pos_for_id, IdleSubsystem};
+use crate::util::{IdleBus, METRICS};
 use aspotify::Market::FromToken;
-use aspotify::{model, Error, ItemType, Track};
-use std::sync::Arc;
+use aspotify::{model, AlbumGroup, Client, Error, ItemType, Track, TrackSimplified};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 // Maximum number of items we can pull at once from the public API
-const PAGE_SIZE: usize = 50;
+pub const PAGE_SIZE: usize = 50;
+// Maximum number of ids accepted by the several albums endpoint
+const MAX_ALBUMS_PER_REQUEST: usize = 20;
 
 #[derive(Debug)]
 pub enum PlayContext {
     Album(model::Album),
+    /// Top tracks of the artist, in the order returned by Spotify, optionally followed
+    /// by the tracks of their latest albums. Positions are indexes in this list.
     Artist(model::Artist, Vec<Track>),
     Playlist(model::Playlist),
     Track(model::Track),
@@ -33,14 +41,13 @@ impl PlayContext {
 
     /// Scans the playing context and returns the position (starting at zero)
     /// of the item with the given ID, if found.
-    /// Returns zero if the item is not found.
     /// TODO: we assume IDs are globally unique and don't check the item type (track/episode)
-    pub fn position_for_id(&self, id: &str) -> usize {
+    pub fn position_for_id(&self, id: &str) -> Option<usize> {
         match self {
             PlayContext::Album(album) => {
                 for (pos, track) in album.tracks.items.iter().enumerate() {
                     if track.id.is_some() && track.id.as_ref().unwrap().eq(id) {
-                        return pos;
+                        return Some(pos);
                     }
                 }
             }
@@ -49,12 +56,12 @@ impl PlayContext {
                     match &item.item {
                         Some(model::PlaylistItemType::Episode(ep)) => {
                             if ep.id.eq(id) {
-                                return pos;
+                                return Some(pos);
                             }
                         }
                         Some(model::PlaylistItemType::Track(track)) => {
                             if track.id.is_some() && track.id.as_ref().unwrap().eq(id) {
-                                return pos;
+                                return Some(pos);
                             }
                         }
                         None => {}
@@ -64,84 +71,219 @@ impl PlayContext {
             PlayContext::Show(show) => {
                 for (pos, item) in show.episodes.items.iter().enumerate() {
                     if item.id.eq(id) {
-                        return pos;
+                        return Some(pos);
                     }
                 }
             }
             PlayContext::Artist(_, tracks) => {
                 for (pos, track) in tracks.iter().enumerate() {
                     if track.id.is_some() && track.id.as_ref().unwrap().eq(id) {
-                        return pos;
+                        return Some(pos);
                     }
                 }
             }
             _ => {}
         };
-        // Default to 0 if not found
-        0
+        None
+    }
+
+    /// Resolves a song id, as listed by playlistinfo, back to the position
+    /// of the item in the context. Returns None if no item has this id.
+    pub fn position_for_song_id(&self, song_id: usize) -> Option<usize> {
+        let pos = song_pos_for_id(song_id)?;
+        let found = match self {
+            PlayContext::Album(album) => pos < album.tracks.items.len(),
+            PlayContext::Playlist(playlist) => playlist
+                .tracks
+                .items
+                .get(pos)
+                .is_some_and(|i| i.item.is_some()),
+            PlayContext::Show(show) => pos < show.episodes.items.len(),
+            PlayContext::Artist(_, tracks) => pos < tracks.len(),
+            PlayContext::Track(_) | PlayContext::Episode(_) => pos == 0,
+            PlayContext::Empty => false,
+        };
+        if found {
+            Some(pos)
+        } else {
+            None
+        }
     }
 }
 
+/// Latest retrieved context, shared by the handler workers. Concurrent gets of a
+/// context that is not cached yet wait for a single retrieval.
 pub struct ContextCache {
     client: Arc<aspotify::Client>,
     idle_bus: Arc<IdleBus>,
-    data: Arc<PlayContext>,
-    key: Option<model::Context>,
+    current: Mutex<Option<(model::Context, Arc<PlayContext>)>>,
+    // Retrievals in progress, waiters find the result in the slot once it is unlocked
+    pending: Mutex<HashMap<ContextKey, PendingSlot>>,
+    // Bumped each time a context is loaded, reported as the playlist version in status
+    version: AtomicU32,
     empty: Arc<PlayContext>,
+    artist_albums: usize,
+    // Replaces retrievals from the Spotify API in tests
+    #[cfg(test)]
+    stub_retrieval: Option<StubRetrieval>,
 }
 
+#[cfg(test)]
+type StubRetrieval = Box<dyn Fn(&model::Context) -> PlayContext + Send + Sync>;
+
+type ContextKey = (ItemType, String);
+type PendingSlot = Arc<tokio::sync::Mutex<Option<Arc<PlayContext>>>>;
+
 impl ContextCache {
-    pub fn new(client: Arc<aspotify::Client>, idle_bus: Arc<IdleBus>) -> ContextCache {
+    pub fn new(
+        client: Arc<aspotify::Client>,
+        idle_bus: Arc<IdleBus>,
+        artist_albums: usize,
+    ) -> ContextCache {
         ContextCache {
             client,
             idle_bus,
-            data: Arc::new(PlayContext::Empty),
-            key: None,
+            current: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            version: AtomicU32::new(0),
             empty: Arc::new(PlayContext::Empty),
+            artist_albums,
+            #[cfg(test)]
+            stub_retrieval: None,
         }
     }
 
-    pub async fn get(&mut self, key: Option<&model::Context>) -> Result<Arc<PlayContext>, Error> {
-        match key {
-            None => Ok(self.empty.clone()),
-            Some(key) => {
-                let hit = self.key.as_ref().map_or(false, |k| k.eq(key));
-                if !hit {
-                    self.data = Arc::new(self.retrieve(key).await?);
-                    self.key = Some(key.clone());
-                    self.idle_bus.notify(IdleSubsystem::PlayQueue);
-                }
-                Ok(self.data.clone())
+    /// Builds a cache retrieving contexts through the given function, for tests
+    #[cfg(test)]
+    pub fn with_retrieval(
+        client: Arc<aspotify::Client>,
+        idle_bus: Arc<IdleBus>,
+        retrieve: impl Fn(&model::Context) -> PlayContext + Send + Sync + 'static,
+    ) -> ContextCache {
+        ContextCache {
+            stub_retrieval: Some(Box::new(retrieve)),
+            ..ContextCache::new(client, idle_bus, 0)
+        }
+    }
+
+    /// Returns the context for the given key, only retrieving it if it changed. Commands
+    /// of a list, like status and currentsong, share the same cached context.
+    pub async fn get(&self, key: Option<&model::Context>) -> Result<Arc<PlayContext>, Error> {
+        #[cfg(test)]
+        if let Some(retrieve) = &self.stub_retrieval {
+            return self
+                .get_with(key, |key| async move { Ok(retrieve(key)) })
+                .await;
+        }
+        self.get_with(key, |key| async move {
+            let result = retry_once(RETRY_DELAY, || self.retrieve(key)).await;
+            METRICS.spotify_request(result.is_err());
+            result
+        })
+        .await
+    }
+
+    /// Implements get with the given retrieval. If it fails, the next waiter tries again.
+    async fn get_with<'a, F, R>(
+        &self,
+        key: Option<&'a model::Context>,
+        retrieve: F,
+    ) -> Result<Arc<PlayContext>, Error>
+    where
+        F: FnOnce(&'a model::Context) -> R,
+        R: Future<Output = Result<PlayContext, Error>>,
+    {
+        let key = match key {
+            None => return Ok(self.empty.clone()),
+            Some(key) => key,
+        };
+        let cached = self.cached(key);
+        METRICS.context_cache(cached.is_some());
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+
+        let pending_key = (key.context_type, key.id.clone());
+        let slot = self
+            .lock_pending()
+            .entry(pending_key.clone())
+            .or_default()
+            .clone();
+        let mut retrieved = slot.lock().await;
+        if let Some(data) = retrieved.as_ref() {
+            return Ok(data.clone());
+        }
+        let result = retrieve(key).await.map(Arc::new);
+        if let Ok(data) = &result {
+            *retrieved = Some(data.clone());
+            self.store(key.clone(), data.clone());
+            self.idle_bus.notify(IdleSubsystem::PlayQueue);
+        }
+        let mut pending = self.lock_pending();
+        if pending
+            .get(&pending_key)
+            .is_some_and(|s| Arc::ptr_eq(s, &slot))
+        {
+            pending.remove(&pending_key);
+        }
+        result
+    }
+
+    /// Contexts are identified by their type and id, other fields are cosmetic
+    fn cached(&self, key: &model::Context) -> Option<Arc<PlayContext>> {
+        match self.lock_current().as_ref() {
+            Some((k, data)) if k.context_type == key.context_type && k.id == key.id => {
+                Some(data.clone())
             }
+            _ => None,
         }
     }
 
+    fn store(&self, key: model::Context, data: Arc<PlayContext>) {
+        let mut current = self.lock_current();
+        self.version.fetch_add(1, Ordering::SeqCst);
+        *current = Some((key, data));
+    }
+
+    /// Version of the queue, contexts returned by previous gets are at most this version
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Drops the cached context, the next get call will retrieve it again
+    pub fn clear(&self) {
+        *self.lock_current() = None;
+    }
+
     pub fn get_latest_key(&self) -> Option<model::Context> {
-        self.key.clone()
+        self.lock_current().as_ref().map(|(key, _)| key.clone())
+    }
+
+    fn lock_current(&self) -> MutexGuard<'_, Option<(model::Context, Arc<PlayContext>)>> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, HashMap<ContextKey, PendingSlot>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    async fn retrieve(&mut self, key: &model::Context) -> Result<PlayContext, Error> {
+    async fn retrieve(&self, key: &model::Context) -> Result<PlayContext, Error> {
         let id = &key.id;
         Ok(match key.context_type {
             ItemType::Album => {
                 let mut album = self.client.albums().get_album(id, None).await?.data;
-                while album.tracks.total > album.tracks.items.len() {
-                    album.tracks.items.append(
-                        &mut self
-                            .client
-                            .albums()
-                            .get_album_tracks(id, PAGE_SIZE, album.tracks.items.len(), None)
-                            .await?
-                            .data
-                            .items,
-                    );
-                }
+                retrieve_album_tracks(&self.client, &mut album).await?;
                 PlayContext::Album(album)
             }
             ItemType::Artist => {
                 let client = self.client.artists();
                 let artist = client.get_artist(id).await?.data;
-                let tracks = client.get_artist_top(id, FromToken).await?.data;
+                let mut tracks = client.get_artist_top(id, FromToken).await?.data;
+                if self.artist_albums > 0 {
+                    let albums =
+                        retrieve_artist_albums(&self.client, id, self.artist_albums).await?;
+                    append_album_tracks(&mut tracks, albums);
+                }
                 PlayContext::Artist(artist, tracks)
             }
             ItemType::Playlist => {
@@ -169,3 +311,327 @@ impl ContextCache {
         })
     }
 }
+
+/// Albums are returned with the first page of tracks, retrieve the remaining ones
+pub async fn retrieve_album_tracks(client: &Client, album: &mut model::Album) -> Result<(), Error> {
+    while album.tracks.total > album.tracks.items.len() {
+        album.tracks.items.append(
+            &mut client
+                .albums()
+                .get_album_tracks(&album.id, PAGE_SIZE, album.tracks.items.len(), None)
+                .await?
+                .data
+                .items,
+        );
+    }
+    Ok(())
+}
+
+/// Retrieves up to max albums and singles of an artist, latest first, with all their tracks.
+/// This takes one request per page of albums and per 20 albums, plus one per album with
+/// more tracks than its first page. Albums are completed one after the other, for the
+/// requests of a context load to stay bounded by max rather than run all at once.
+async fn retrieve_artist_albums(
+    client: &Client,
+    id: &str,
+    max: usize,
+) -> Result<Vec<model::Album>, Error> {
+    let groups = [AlbumGroup::Album, AlbumGroup::Single];
+    let mut ids = Vec::new();
+    loop {
+        let page = client
+            .artists()
+            .get_artist_albums(
+                id,
+                Some(&groups),
+                PAGE_SIZE.min(max - ids.len()),
+                ids.len(),
+                Some(FromToken),
+            )
+            .await?
+            .data;
+        let fetched = page.items.len();
+        ids.extend(page.items.into_iter().map(|album| album.id));
+        if fetched == 0 || ids.len() >= max.min(page.total) {
+            break;
+        }
+    }
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut albums = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(MAX_ALBUMS_PER_REQUEST) {
+        albums.extend(
+            client
+                .albums()
+                .get_albums(chunk, Some(FromToken))
+                .await?
+                .data,
+        );
+    }
+    for album in albums.iter_mut() {
+        retrieve_album_tracks(client, album).await?;
+    }
+    Ok(albums)
+}
+
+/// Appends the tracks of the albums after the top tracks, skipping the ones already listed
+fn append_album_tracks(tracks: &mut Vec<Track>, albums: Vec<model::Album>) {
+    let mut seen: HashSet<String> = tracks.iter().filter_map(|t| t.id.clone()).collect();
+    for mut album in albums {
+        let items = std::mem::take(&mut album.tracks.items);
+        let album = album.simplify();
+        for track in items {
+            if let Some(id) = &track.id {
+                if !seen.insert(id.clone()) {
+                    continue;
+                }
+            }
+            tracks.push(album_track(track, album.clone()));
+        }
+    }
+}
+
+/// Album tracks don't hold their album, popularity and external ids, only the former is known
+fn album_track(track: TrackSimplified, album: model::AlbumSimplified) -> Track {
+    Track {
+        album,
+        external_ids: Default::default(),
+        popularity: 0,
+        artists: track.artists,
+        available_markets: track.available_markets,
+        disc_number: track.disc_number,
+        duration: track.duration,
+        explicit: track.explicit,
+        external_urls: track.external_urls,
+        id: track.id,
+        is_playable: track.is_playable,
+        linked_from: track.linked_from,
+        restrictions: track.restrictions,
+        name: track.name,
+        preview_url: track.preview_url,
+        track_number: track.track_number,
+        item_type: track.item_type,
+        is_local: track.is_local,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::aspotify::fixtures::{
+        build_album, build_artist, build_context, build_episode, build_playlist, build_track,
+        build_track_simplified,
+    };
+    use crate::handlers::aspotify::playlist::build_playlistinfo_result;
+    use crate::mpd_protocol::{to_string, HandlerOutput};
+    use aspotify::{ClientCredentials, PlaylistItemType};
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn it_compares_contexts_by_id() {
+        let client = Arc::new(aspotify::Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let cache = ContextCache::new(client, IdleBus::new(), 0);
+        let album = build_context(ItemType::Album, "album_id", "https://open.spotify.com/1");
+        assert!(cache.cached(&album).is_none());
+
+        cache.store(album, Arc::new(PlayContext::Empty));
+        assert!(cache
+            .cached(&build_context(
+                ItemType::Album,
+                "album_id",
+                "https://open.spotify.com/2"
+            ))
+            .is_some());
+        assert!(cache
+            .cached(&build_context(
+                ItemType::Album,
+                "other_id",
+                "https://open.spotify.com/1"
+            ))
+            .is_none());
+        assert!(cache
+            .cached(&build_context(
+                ItemType::Playlist,
+                "album_id",
+                "https://open.spotify.com/1"
+            ))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn it_retrieves_contexts_once() {
+        let client = Arc::new(aspotify::Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let cache = ContextCache::new(client, IdleBus::new(), 0);
+        let album = build_context(ItemType::Album, "album_id", "https://open.spotify.com/1");
+        let data = Arc::new(PlayContext::Album(build_album("album_id", vec![])));
+        cache.store(album.clone(), data.clone());
+
+        // Any retrieval would fail, as the client has no credentials
+        let status_context = cache.get(Some(&album)).await.expect("Cache miss");
+        let song_context = cache.get(Some(&album)).await.expect("Cache miss");
+        assert!(Arc::ptr_eq(&status_context, &song_context));
+        assert!(Arc::ptr_eq(&data, &song_context));
+    }
+
+    #[tokio::test]
+    async fn it_retrieves_contexts_once_for_concurrent_gets() {
+        let client = Arc::new(aspotify::Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let cache = ContextCache::new(client, IdleBus::new(), 0);
+        let album = build_context(ItemType::Album, "album_id", "https://open.spotify.com/1");
+        let retrievals = AtomicUsize::new(0);
+        let retrieve = |_: &model::Context| async {
+            retrievals.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(PlayContext::Album(build_album("album_id", vec![])))
+        };
+
+        let results = join_all((0..4).map(|_| cache.get_with(Some(&album), retrieve))).await;
+        assert_eq!(1, retrievals.load(Ordering::SeqCst));
+        let first = results[0].as_ref().expect("Retrieval failed");
+        for result in results.iter() {
+            assert!(Arc::ptr_eq(
+                first,
+                result.as_ref().expect("Retrieval failed")
+            ));
+        }
+        assert!(cache.lock_pending().is_empty());
+
+        // Waiters retry when the retrieval fails
+        cache.clear();
+        let attempts = AtomicUsize::new(0);
+        let failing = |key: &model::Context| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            let id = key.id.clone();
+            async move {
+                match attempt {
+                    0 => Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
+                    _ => Ok(PlayContext::Album(build_album(&id, vec![]))),
+                }
+            }
+        };
+        let results = join_all((0..3).map(|_| cache.get_with(Some(&album), failing))).await;
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_eq!(2, results.iter().filter(|r| r.is_ok()).count());
+    }
+
+    #[tokio::test]
+    async fn it_bumps_the_version_when_the_context_changes() {
+        let client = Arc::new(aspotify::Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let cache = ContextCache::new(client, IdleBus::new(), 0);
+        let retrieve = |key: &model::Context| {
+            let id = key.id.clone();
+            async move { Ok(PlayContext::Album(build_album(&id, vec![]))) }
+        };
+        let first = build_context(ItemType::Album, "first", "https://open.spotify.com/1");
+        let second = build_context(ItemType::Album, "second", "https://open.spotify.com/2");
+        assert_eq!(0, cache.version());
+
+        cache.get_with(Some(&first), retrieve).await.unwrap();
+        assert_eq!(1, cache.version());
+        cache.get_with(Some(&first), retrieve).await.unwrap();
+        cache.get_with(None, retrieve).await.unwrap();
+        assert_eq!(1, cache.version());
+
+        cache.get_with(Some(&second), retrieve).await.unwrap();
+        assert_eq!(2, cache.version());
+
+        // Contexts retrieved again after an update may have changed
+        cache.clear();
+        cache.get_with(Some(&second), retrieve).await.unwrap();
+        assert_eq!(3, cache.version());
+    }
+
+    #[test]
+    fn it_resolves_song_ids_in_mixed_playlists() {
+        let duration = Duration::from_secs(60);
+        let context = Arc::new(PlayContext::Playlist(build_playlist(
+            "playlist_id",
+            vec![
+                Some(PlaylistItemType::Track(build_track(
+                    Some("track1"),
+                    duration,
+                ))),
+                None,
+                Some(PlaylistItemType::Episode(build_episode(
+                    "episode1", duration,
+                ))),
+                Some(PlaylistItemType::Track(build_track(
+                    Some("track2"),
+                    duration,
+                ))),
+            ],
+        )));
+
+        assert_eq!(None, context.position_for_song_id(0));
+        assert_eq!(Some(0), context.position_for_song_id(1));
+        assert_eq!(None, context.position_for_song_id(2));
+        assert_eq!(Some(2), context.position_for_song_id(3));
+        assert_eq!(Some(3), context.position_for_song_id(4));
+        assert_eq!(None, context.position_for_song_id(5));
+
+        // Ids listed by playlistinfo resolve back to the items they describe
+        let listed = match build_playlistinfo_result(None, context.clone(), None) {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        };
+        for (spotify_id, song_id) in &[("track1", 1), ("episode1", 3), ("track2", 4)] {
+            let pos = context.position_for_song_id(*song_id);
+            assert_eq!(context.position_for_id(spotify_id), pos);
+            assert!(listed.contains(&format!("Id: {}\n", song_id)));
+        }
+    }
+
+    #[test]
+    fn it_lists_top_tracks_before_album_tracks() {
+        let duration = Duration::from_secs(60);
+        let mut tracks = vec![
+            build_track(Some("top1"), duration),
+            build_track(Some("top2"), duration),
+        ];
+        append_album_tracks(
+            &mut tracks,
+            vec![
+                build_album(
+                    "album1",
+                    vec![
+                        build_track_simplified(Some("top2"), duration),
+                        build_track_simplified(Some("track3"), duration),
+                    ],
+                ),
+                build_album(
+                    "album2",
+                    vec![build_track_simplified(Some("track4"), duration)],
+                ),
+            ],
+        );
+        let context = PlayContext::Artist(build_artist("artist_id"), tracks);
+
+        assert_eq!(4, context.size());
+        for (pos, id) in ["top1", "top2", "track3", "track4"].iter().enumerate() {
+            assert_eq!(Some(pos), context.position_for_id(id));
+            assert_eq!(Some(pos), context.position_for_song_id(pos + 1));
+        }
+        assert_eq!(None, context.position_for_song_id(5));
+        if let PlayContext::Artist(_, tracks) = &context {
+            assert_eq!(Some("album_id"), tracks[1].album.id.as_deref());
+            assert_eq!(Some("album1"), tracks[2].album.id.as_deref());
+            assert_eq!(Some("album2"), tracks[3].album.id.as_deref());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::handlers::aspotify::playback::CachedPlayback;
+use aspotify::{
+    Actions, Album, AlbumSimplified, AlbumType, Artist, ArtistSimplified, Context, CurrentPlayback,
+    CurrentlyPlaying, DatePrecision, Device, DeviceType, Episode, Followers, ItemType, Page,
+    PlayingType, Playlist, PlaylistItem, PlaylistItemType, RepeatState, ShowSimplified, Track,
+    TrackSimplified, TypeAlbum, TypeArtist, TypeEpisode, TypePlaylist, TypeShow, TypeTrack,
+    TypeUser, UserSimplified,
+};
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Builds a minimal track, as returned by the public API
+pub fn build_track(id: Option<&str>, duration: Duration) -> Track {
+    Track {
+        album: AlbumSimplified {
+            artists: vec![],
+            available_markets: None,
+            external_urls: HashMap::new(),
+            images: vec![],
+            name: "Album".to_string(),
+            restrictions: None,
+            item_type: TypeAlbum,
+            album_type: None,
+            id: Some("album_id".to_string()),
+            release_date: None,
+            release_date_precision: None,
+        },
+        external_ids: HashMap::new(),
+        popularity: 0,
+        artists: vec![ArtistSimplified {
+            external_urls: HashMap::new(),
+            id: None,
+            name: "Artist".to_string(),
+            item_type: TypeArtist,
+        }],
+        available_markets: None,
+        disc_number: 1,
+        duration,
+        explicit: false,
+        external_urls: HashMap::new(),
+        id: id.map(String::from),
+        is_playable: None,
+        linked_from: None,
+        restrictions: None,
+        name: "Title".to_string(),
+        preview_url: None,
+        track_number: 1,
+        item_type: TypeTrack,
+        is_local: false,
+    }
+}
+
+/// Builds a full album, holding the given tracks
+pub fn build_album(id: &str, tracks: Vec<TrackSimplified>) -> Album {
+    Album {
+        copyrights: vec![],
+        external_ids: HashMap::new(),
+        genres: vec![],
+        label: "Label".to_string(),
+        popularity: 0,
+        tracks: Page {
+            limit: tracks.len(),
+            offset: 0,
+            total: tracks.len(),
+            items: tracks,
+        },
+        artists: vec![],
+        available_markets: None,
+        external_urls: HashMap::new(),
+        images: vec![],
+        name: "Album".to_string(),
+        restrictions: None,
+        item_type: TypeAlbum,
+        album_type: AlbumType::Album,
+        id: id.to_string(),
+        release_date: NaiveDate::from_ymd(2020, 1, 1),
+        release_date_precision: DatePrecision::Day,
+    }
+}
+
+/// Builds a track as part of an album
+pub fn build_track_simplified(id: Option<&str>, duration: Duration) -> TrackSimplified {
+    build_track(id, duration).simplify()
+}
+
+/// Builds a full artist, without images
+pub fn build_artist(id: &str) -> Artist {
+    Artist {
+        id: id.to_string(),
+        followers: Followers { total: 0 },
+        genres: vec![],
+        images: vec![],
+        popularity: 0,
+        external_urls: HashMap::new(),
+        name: "Artist".to_string(),
+        item_type: TypeArtist,
+    }
+}
+
+/// Builds a podcast episode, as returned by the public API
+pub fn build_episode(id: &str, duration: Duration) -> Episode {
+    Episode {
+        audio_preview_url: None,
+        description: "Description".to_string(),
+        duration,
+        explicit: false,
+        external_urls: HashMap::new(),
+        id: id.to_string(),
+        images: vec![],
+        is_externally_hosted: false,
+        is_playable: true,
+        languages: vec![],
+        name: "Episode".to_string(),
+        release_date: NaiveDate::from_ymd(2020, 1, 1),
+        release_date_precision: DatePrecision::Day,
+        resume_point: None,
+        item_type: TypeEpisode,
+        show: ShowSimplified {
+            available_markets: vec![],
+            copyrights: vec![],
+            description: "Description".to_string(),
+            explicit: false,
+            external_urls: HashMap::new(),
+            id: "show_id".to_string(),
+            images: vec![],
+            is_externally_hosted: None,
+            languages: vec![],
+            media_type: "audio".to_string(),
+            name: "Show".to_string(),
+            publisher: "Publisher".to_string(),
+            item_type: TypeShow,
+        },
+    }
+}
+
+/// Builds a playlist holding the given items, None being unavailable items
+pub fn build_playlist(id: &str, items: Vec<Option<PlaylistItemType<Track, Episode>>>) -> Playlist {
+    Playlist {
+        description: None,
+        followers: Followers { total: 0 },
+        tracks: Page {
+            limit: items.len(),
+            offset: 0,
+            total: items.len(),
+            items: items
+                .into_iter()
+                .map(|item| PlaylistItem {
+                    added_at: None,
+                    added_by: None,
+                    is_local: false,
+                    item,
+                })
+                .collect(),
+        },
+        collaborative: false,
+        external_urls: HashMap::new(),
+        id: id.to_string(),
+        images: vec![],
+        name: "Playlist".to_string(),
+        owner: UserSimplified {
+            display_name: None,
+            external_urls: HashMap::new(),
+            id: "user_id".to_string(),
+            item_type: TypeUser,
+        },
+        public: None,
+        snapshot_id: "snapshot".to_string(),
+        item_type: TypePlaylist,
+    }
+}
+
+/// Builds an inactive device, as listed by the devices endpoint
+pub fn build_device(id: &str, name: &str, volume: Option<u32>) -> Device {
+    Device {
+        id: Some(id.to_string()),
+        is_active: false,
+        is_private_session: false,
+        is_restricted: false,
+        name: name.to_string(),
+        device_type: DeviceType::Computer,
+        volume_percent: volume,
+    }
+}
+
+/// Builds a playback state on an active device, with an optional item
+pub fn build_playback(
+    item: Option<PlayingType>,
+    progress: Option<Duration>,
+    is_playing: bool,
+    retrieved: Instant,
+) -> CachedPlayback {
+    let mut device = build_device("device_id", "Device", Some(20));
+    device.is_active = true;
+    let mut playback = CachedPlayback::new(Some(CurrentPlayback {
+        device,
+        repeat_state: RepeatState::Off,
+        shuffle_state: false,
+        currently_playing: CurrentlyPlaying {
+            context: None,
+            progress,
+            is_playing,
+            item,
+            actions: Actions { disallows: vec![] },
+        },
+    }));
+    playback.set_retrieved(retrieved);
+    playback
+}
+
+/// Builds a playback context, its url is not used to identify it
+pub fn build_context(context_type: ItemType, id: &str, url: &str) -> Context {
+    let mut external_urls = HashMap::new();
+    external_urls.insert("spotify".to_string(), url.to_string());
+    Context {
+        context_type,
+        external_urls,
+        id: id.to_string(),
+    }
+}
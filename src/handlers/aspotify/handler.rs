@@ -1,56 +1,160 @@
 use crate::handlers::aspotify::auth::AuthStatus;
-use crate::handlers::aspotify::context::ContextCache;
+use crate::handlers::aspotify::context::{ContextCache, PlayContext};
+use crate::handlers::aspotify::library::{build_listall_result, retrieve_albums_for_path};
+use crate::handlers::aspotify::playback::CachedPlayback;
 use crate::handlers::aspotify::playback_watcher::PlaybackClient;
-use crate::handlers::aspotify::playlist::build_playlistinfo_result;
+use crate::handlers::aspotify::playlist::{build_playlistinfo_result, build_plchanges_result};
+use crate::handlers::aspotify::retry::{retry_once, RETRY_DELAY};
 use crate::handlers::aspotify::song::build_song_from_playing;
-use crate::handlers::aspotify::status::{build_outputs_result, build_status_result};
-use crate::handlers::aspotify::utils::{compute_repeat, compute_seek};
+use crate::handlers::aspotify::status::{
+    build_outputs_result, build_status_result, extract_extensions, merge_outputs, StatusQueue,
+};
+use crate::handlers::aspotify::utils::{
+    check_position, compute_repeat, compute_seek, compute_seek_percent, compute_volume,
+    current_track_id, default_volume_target, is_pause_state, is_reported_error, is_serialized,
+    known_saved_state, needs_pause_after_seek, requires_premium,
+};
+use crate::handlers::workers::SharedReceiver;
 use crate::mpd_protocol::*;
-use crate::util::{IdleBus, Settings};
-use aspotify::{Client, Play};
+use crate::util::{IdleBus, LastError, Settings, UpdateJobs, METRICS};
+use aspotify::{Client, ItemType, Play, Subscription};
 use log::{debug, warn};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::macros::support::Future;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
 
+/// Executes commands through the Spotify Web API. Clones share the command queue,
+/// caches and auth state, and can run as additional workers: commands then run in
+/// parallel, except the ones changing the player state that run one at a time.
+#[derive(Clone)]
 pub struct SpotifyHandler {
-    command_rx: mpsc::Receiver<HandlerInput>,
+    command_rx: SharedReceiver<HandlerInput>,
     client: Arc<Client>,
-    context_cache: ContextCache,
-    auth_status: AuthStatus,
+    context_cache: Arc<ContextCache>,
+    auth_status: Arc<Mutex<AuthStatus>>,
+    authenticated: Arc<AtomicBool>,
+    // Held while running commands that must not run concurrently
+    writes: Arc<Mutex<()>>,
     playback: PlaybackClient,
+    idle_bus: Arc<IdleBus>,
+    library_max_albums: usize,
+    extensions: bool,
+    // Saved state of the last track looked up for the status extension, shared by workers
+    saved_track: Arc<Mutex<Option<(String, bool, Instant)>>>,
+    saved_track_max_age: Duration,
+    last_error: Arc<LastError>,
+    started: Instant,
+    audio_format: String,
+    bitrate_free: u32,
+    bitrate_premium: u32,
+    // Volume reported in status when the device does not expose it
+    unknown_volume: Option<i32>,
+    update_jobs: Arc<UpdateJobs>,
+    // Device id or name receiving volume changes when no device is active
+    default_device: Option<String>,
 }
 
 // Alias for aspotify simple return value
 type AResult = Result<(), aspotify::model::Error>;
 
+/// Player call decided by a command from the playback, executed through exec
+#[derive(Debug, PartialEq)]
+enum PlayerCall {
+    Resume,
+    Pause,
+    // Plays the item at a position of a context, from the given progress
+    PlayAt(ItemType, String, usize, Duration),
+}
+
 impl SpotifyHandler {
     pub async fn new(
         settings: &Settings,
         client: Arc<Client>,
         idle_bus: Arc<IdleBus>,
     ) -> (Self, mpsc::Sender<HandlerInput>) {
-        let (command_tx, command_rx) = mpsc::channel(16);
-        let context_cache = ContextCache::new(client.clone(), idle_bus.clone());
+        let context_cache = ContextCache::new(
+            client.clone(),
+            idle_bus.clone(),
+            settings.artist_context_albums,
+        );
         let auth_status = AuthStatus::new(settings, client.clone()).await;
-        let playback = PlaybackClient::new(settings, client.clone(), idle_bus);
+        let playback = PlaybackClient::new(settings, client.clone(), idle_bus.clone());
+        Self::with_parts(
+            settings,
+            client,
+            idle_bus,
+            context_cache,
+            auth_status,
+            playback,
+        )
+    }
+
+    /// Builds a handler around its caches and clients, tests replace the ones calling Spotify
+    fn with_parts(
+        settings: &Settings,
+        client: Arc<Client>,
+        idle_bus: Arc<IdleBus>,
+        context_cache: ContextCache,
+        auth_status: AuthStatus,
+        playback: PlaybackClient,
+    ) -> (Self, mpsc::Sender<HandlerInput>) {
+        let (command_tx, command_rx) = mpsc::channel(settings.handler_queue_size);
+        let authenticated = auth_status.authenticated();
         (
             SpotifyHandler {
-                command_rx,
+                command_rx: SharedReceiver::new(command_rx),
                 client,
-                auth_status,
-                context_cache,
+                auth_status: Arc::new(Mutex::new(auth_status)),
+                authenticated,
+                writes: Arc::new(Mutex::new(())),
+                context_cache: Arc::new(context_cache),
                 playback,
+                idle_bus,
+                library_max_albums: settings.library_max_albums,
+                extensions: settings.mpd_extensions,
+                saved_track: Arc::new(Mutex::new(None)),
+                saved_track_max_age: settings.playback_pool_freq_base(),
+                last_error: Arc::new(LastError::default()),
+                started: Instant::now(),
+                audio_format: settings.status_audio_format.clone(),
+                bitrate_free: settings.status_bitrate_free,
+                bitrate_premium: settings.status_bitrate_premium,
+                unknown_volume: match settings.status_volume_unknown_as_minus_one {
+                    true => Some(-1),
+                    false => None,
+                },
+                update_jobs: Arc::new(UpdateJobs::default()),
+                default_device: settings.default_device.clone(),
             },
             command_tx,
         )
     }
+    /// Returns the auth state flag, updated as commands are handled
+    pub fn authenticated(&self) -> Arc<AtomicBool> {
+        self.authenticated.clone()
+    }
+
     pub async fn run(&mut self) {
         debug!["aspotify handler entered loop"];
         // Loop in incoming commands
         while let Some(input) = self.command_rx.recv().await {
-            if let Err(err) = input.resp.send(self.execute(input.command).await) {
+            let writes = self.writes.clone();
+            let _writing = match is_serialized(&input.command) {
+                true => Some(writes.lock().await),
+                false => None,
+            };
+            let result = self.execute(input.command).await;
+            match &result {
+                // Reported in status to all clients, until one sends clearerror
+                Err(err) if is_reported_error(err) => {
+                    self.last_error.record(err.to_string());
+                    self.idle_bus.notify(IdleSubsystem::Player);
+                }
+                _ => {}
+            }
+            if let Err(err) = input.resp.send(result) {
                 warn!["Cannot send response: {:?}", err];
             }
         }
@@ -58,16 +162,30 @@ impl SpotifyHandler {
     }
 
     async fn execute(&mut self, command: Command) -> HandlerResult {
+        if requires_premium(&command) {
+            // Avoid surfacing the 403 returned by the API for free accounts
+            self.check_auth().await?;
+            if !self.auth_status.lock().await.is_premium() {
+                return Err(HandlerError::PremiumRequired);
+            }
+        }
+
         let client = self.client.clone();
         match command {
             // Auth support
             Command::SpotifyAuth(token) => match token {
-                None => self.auth_status.check().await,
-                Some(url) => self.auth_status.callback(url).await,
+                None => self.auth_status.lock().await.status().await,
+                Some(url) => self.auth_status.lock().await.callback(url).await,
             },
             // Playback status
             Command::Status => self.execute_status().await,
+            Command::Stats => self.execute_stats().await,
+            Command::ClearError => {
+                self.last_error.clear();
+                Ok(HandlerOutput::Ok)
+            }
             Command::CurrentSong => self.execute_currentsong().await,
+            Command::NowPlaying if self.extensions => self.execute_now_playing().await,
             Command::Outputs => self.execute_outputs().await,
             Command::EnableOutput(pos) => self.execute_enable_output(pos).await,
 
@@ -85,62 +203,112 @@ impl SpotifyHandler {
             Command::PlayId(Some(0)) => Err(HandlerError::FromString(String::from(
                 "songID must be higher and 0",
             ))),
-            Command::PlayId(Some(pos)) => self.execute_play(pos - 1).await,
-            Command::Pause(Some(false)) => self.exec(client.player().resume(None)).await,
-            Command::Pause(Some(true)) => self.exec(client.player().pause(None)).await,
+            Command::PlayId(Some(id)) => self.execute_play_id(id).await,
+            Command::Pause(Some(paused)) => self.execute_pause(paused).await,
             Command::Pause(None) => self.execute_play_pause().await,
             Command::Stop => self.exec(client.player().pause(None)).await,
             Command::SeekCur(time) => self.execute_seek_cur(time).await,
+            Command::SeekPercent(percent) => self.execute_seek_percent(percent).await,
             Command::SeekPos(pos, time) => self.execute_seek(pos, time).await,
             Command::SeekId(0, _) => Err(HandlerError::FromString(String::from(
                 "songID must be higher and 0",
             ))),
-            Command::SeekId(pos, time) => self.execute_seek(pos - 1, time).await,
+            Command::SeekId(id, time) => self.execute_seek_id(id, time).await,
+
+            // Music database
+            Command::ListAll(path) => self.execute_listall(path, false).await,
+            Command::ListAllInfo(path) => self.execute_listall(path, true).await,
+            Command::Update(_) | Command::Rescan(_) => self.execute_update().await,
 
             // Volume
             Command::GetVolume => self.execute_get_volume().await,
             Command::ChangeVolume(delta) => self.execute_change_volume(delta).await,
-            Command::SetVolume(v) => self.exec(client.player().set_volume(v as i32, None)).await,
+            Command::SetVolume(v) => self.execute_set_volume(v).await,
 
             // Playlist info
             Command::PlaylistInfo(range) => self.execute_playlist_info(range).await,
             Command::PlaylistId(None) => self.execute_playlist_info(None).await,
-            Command::PlaylistId(Some(0)) => Err(HandlerError::FromString(String::from(
-                "songID must be higher and 0",
-            ))),
-            Command::PlaylistId(Some(id)) => {
-                self.execute_playlist_info(Some(PositionRange::one(id - 1)))
-                    .await
-            }
+            Command::PlaylistId(Some(id)) => match song_pos_for_id(id) {
+                Some(pos) => {
+                    self.execute_playlist_info(Some(PositionRange::one(pos)))
+                        .await
+                }
+                None => Err(HandlerError::FromString(String::from(
+                    "songID must be higher and 0",
+                ))),
+            },
+
+            Command::PlChanges(since) => self.execute_playlist_changes(since, false).await,
+            Command::PlChangesPosId(since) => self.execute_playlist_changes(since, true).await,
+
+            // Saved tracks
+            Command::SaveCurrent => self.execute_save_current(true).await,
+            Command::UnsaveCurrent => self.execute_save_current(false).await,
 
             // Unsupported
             _ => Err(HandlerError::Unsupported),
         }
     }
 
+    async fn check_auth(&self) -> HandlerResult {
+        self.auth_status.lock().await.check().await
+    }
+
     /// Authenticates and executes a simple aspotify call (empty return value).
     async fn exec(&mut self, f: impl Future<Output = AResult>) -> HandlerResult {
-        self.auth_status.check().await?;
-        f.await?;
+        self.check_auth().await?;
+        let result = f.await;
+        METRICS.spotify_request(result.is_err());
+        result?;
         self.playback.expect_changes().await;
         Ok(HandlerOutput::Ok)
     }
 
+    async fn execute_pause(&mut self, paused: bool) -> HandlerResult {
+        self.check_auth().await?;
+        // Spotify rejects pausing a paused player, skip the call if we are already there
+        if is_pause_state(self.playback.get().await?.get_playing(), paused) {
+            return Ok(HandlerOutput::Ok);
+        }
+        let client = self.client.clone();
+        match paused {
+            true => self.exec(client.player().pause(None)).await,
+            false => self.exec(client.player().resume(None)).await,
+        }
+    }
+
     async fn execute_play_pause(&mut self) -> HandlerResult {
-        self.auth_status.check().await?;
-        let playback = self.playback.get().await?;
-        match playback.get_playing().map(|p| p.is_playing) {
-            None => self.client.player().resume(None).await?,
-            Some(false) => self.client.player().resume(None).await?,
-            Some(true) => self.client.player().pause(None).await?,
+        let call = self.plan_play_pause().await?;
+        self.exec_player_call(call).await
+    }
+
+    /// Toggles from a fresh playback, as a stale cache could get us to toggle the wrong way
+    async fn plan_play_pause(&mut self) -> Result<PlayerCall, HandlerError> {
+        self.check_auth().await?;
+        let playback = self.playback.get_fresh().await?;
+        match playback.get_playing().is_some_and(|p| p.is_playing) {
+            true => Ok(PlayerCall::Pause),
+            false => Ok(PlayerCall::Resume),
+        }
+    }
+
+    async fn exec_player_call(&mut self, call: PlayerCall) -> HandlerResult {
+        let client = self.client.clone();
+        match call {
+            PlayerCall::Resume => self.exec(client.player().resume(None)).await,
+            PlayerCall::Pause => self.exec(client.player().pause(None)).await,
+            PlayerCall::PlayAt(context_type, id, pos, progress) => {
+                let play = Play::<'_, &[u8]>::Context(context_type, &id, pos);
+                self.exec(client.player().play(Some(play), Some(progress), None))
+                    .await
+            }
         }
-        self.playback.expect_changes().await;
-        Ok(HandlerOutput::Ok)
     }
 
     async fn execute_play(&mut self, pos: usize) -> HandlerResult {
-        self.auth_status.check().await?;
-        if let Some(context) = self.context_cache.get_latest_key() {
+        self.check_auth().await?;
+        let latest = self.context_cache.get_latest_key();
+        if let Some(context) = latest {
             let target = Play::<'_, &[u8]>::Context(context.context_type, context.id.as_str(), pos);
             self.client.player().play(Some(target), None, None).await?;
         }
@@ -148,50 +316,189 @@ impl SpotifyHandler {
         Ok(HandlerOutput::Ok)
     }
 
+    async fn execute_play_id(&mut self, id: usize) -> HandlerResult {
+        let pos = self.resolve_song_id(id).await?;
+        self.execute_play(pos).await
+    }
+
+    /// Maps a song id back to its position in the current context
+    async fn resolve_song_id(&mut self, id: usize) -> Result<usize, HandlerError> {
+        self.check_auth().await?;
+        let playback = self.playback.get().await?;
+        let context = self.context_cache.get(playback.get_context()).await?;
+        context
+            .position_for_song_id(id)
+            .ok_or(HandlerError::NoSuchSong)
+    }
+
     async fn execute_seek_cur(&mut self, time: RelativeFloat) -> HandlerResult {
-        self.auth_status.check().await?;
+        self.check_auth().await?;
         let elapsed = self.playback.get().await?.get_elapsed();
-        self.client
-            .player()
-            .seek(compute_seek(elapsed, time), None)
-            .await?;
-        self.playback.expect_changes().await;
-        Ok(HandlerOutput::Ok)
+        let client = self.client.clone();
+        self.exec(client.player().seek(compute_seek(elapsed, time), None))
+            .await
+    }
+
+    async fn execute_seek_percent(&mut self, percent: f64) -> HandlerResult {
+        self.check_auth().await?;
+        let duration = self.playback.get().await?.get_duration();
+        let position = compute_seek_percent(duration, percent)?;
+        let client = self.client.clone();
+        self.exec(client.player().seek(position, None)).await
     }
 
     async fn execute_seek(&mut self, pos: usize, time: f64) -> HandlerResult {
-        self.auth_status.check().await?;
-        let playback = self.playback.get().await?;
-        match playback.get_context() {
-            None => return Err(HandlerError::FromString("empty playlist".into())),
-            Some(context) => {
-                let play = Play::<'_, &[u8]>::Context(context.context_type, &context.id, pos);
-                self.client
-                    .player()
-                    .play(Some(play), Some(Duration::from_secs_f64(time)), None)
-                    .await?;
-            }
+        for call in self.plan_seek(pos, time).await? {
+            self.exec_player_call(call).await?;
         }
-        self.playback.expect_changes().await;
         Ok(HandlerOutput::Ok)
     }
 
+    /// Seeking to a position starts playback, a paused player is paused again afterwards
+    async fn plan_seek(&mut self, pos: usize, time: f64) -> Result<Vec<PlayerCall>, HandlerError> {
+        self.check_auth().await?;
+        let playback = self.playback.get().await?;
+        let key = playback.get_context().ok_or(HandlerError::NoSuchSong)?;
+        check_position(pos, self.context_cache.get(Some(key)).await?.size())?;
+        let progress = Duration::from_secs_f64(time);
+        let mut calls = vec![PlayerCall::PlayAt(
+            key.context_type,
+            key.id.clone(),
+            pos,
+            progress,
+        )];
+        if needs_pause_after_seek(playback.get_playing()) {
+            calls.push(PlayerCall::Pause);
+        }
+        Ok(calls)
+    }
+
+    async fn execute_seek_id(&mut self, id: usize, time: f64) -> HandlerResult {
+        let pos = self.resolve_song_id(id).await?;
+        self.execute_seek(pos, time).await
+    }
+
     async fn execute_status(&mut self) -> HandlerResult {
-        self.auth_status.check().await?;
+        let (playback, context) = self.get_status_playback().await?;
+        self.build_status(playback, context).await
+    }
+
+    /// Returns status and currentsong fields, from a single playback and context lookup
+    async fn execute_now_playing(&mut self) -> HandlerResult {
+        let (playback, context) = self.get_status_playback().await?;
+        let status = self.build_status(playback.clone(), context.clone()).await?;
+        let song = build_song_from_playing(playback.get_playing(), context)?;
+        Ok(merge_outputs(status, song))
+    }
+
+    async fn get_status_playback(
+        &mut self,
+    ) -> Result<(Arc<CachedPlayback>, Arc<PlayContext>), HandlerError> {
+        self.check_auth().await?;
         let playback = self.playback.get().await?;
+        if playback.has_reached_end() {
+            // Our cached playback is stale, pool again to detect the next item
+            self.playback.expect_changes().await;
+        }
         let context = self.context_cache.get(playback.get_context()).await?;
-        build_status_result(playback, context)
+        Ok((playback, context))
+    }
+
+    async fn build_status(
+        &mut self,
+        playback: Arc<CachedPlayback>,
+        context: Arc<PlayContext>,
+    ) -> HandlerResult {
+        let extensions = match self.extensions {
+            true => {
+                let saved = self.is_current_saved(&playback).await;
+                Some(extract_extensions(&playback, saved))
+            }
+            false => None,
+        };
+        let queue = StatusQueue {
+            context,
+            version: self.context_cache.version(),
+        };
+        build_status_result(
+            playback,
+            queue,
+            self.audio_info().await,
+            extensions,
+            self.last_error.get(),
+            self.unknown_volume,
+            self.update_jobs.running(),
+        )
+    }
+
+    async fn execute_save_current(&mut self, save: bool) -> HandlerResult {
+        self.check_auth().await?;
+        let playback = self.playback.get().await?;
+        let id = current_track_id(playback.get_playing())?.to_string();
+        let client = self.client.clone();
+        match save {
+            true => self.exec(client.library().save_tracks(&[&id])).await?,
+            false => self.exec(client.library().unsave_tracks(&[&id])).await?,
+        };
+        *self.saved_track.lock().await = Some((id, save, Instant::now()));
+        Ok(HandlerOutput::Ok)
+    }
+
+    /// Returns whether the current track is saved, querying the API when the track changes
+    /// or when the known state gets older than the base pooling frequency
+    async fn is_current_saved(&mut self, playback: &CachedPlayback) -> Option<bool> {
+        let id = current_track_id(playback.get_playing()).ok()?;
+        let known = self.saved_track.lock().await;
+        if let Some(saved) = known_saved_state(known.as_ref(), id, self.saved_track_max_age) {
+            return Some(saved);
+        }
+        drop(known);
+        let result = self.client.library().user_saved_tracks(&[id]).await;
+        METRICS.spotify_request(result.is_err());
+        match result.map(|r| r.data.first().copied()) {
+            Ok(Some(saved)) => {
+                *self.saved_track.lock().await = Some((id.to_string(), saved, Instant::now()));
+                Some(saved)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!["Cannot check saved tracks: {}", err];
+                None
+            }
+        }
+    }
+
+    async fn execute_stats(&mut self) -> HandlerResult {
+        self.check_auth().await?;
+        let product = self.auth_status.lock().await.product();
+        Ok(HandlerOutput::from(StatsResponse {
+            uptime: self.started.elapsed().as_secs(),
+            spotify_product: product.map(|p| match p {
+                Subscription::Premium => "premium".to_string(),
+                Subscription::Free => "free".to_string(),
+            }),
+        }))
+    }
+
+    async fn audio_info(&self) -> StatusAudioInfo {
+        StatusAudioInfo {
+            bitrate: match self.auth_status.lock().await.product() {
+                Some(Subscription::Premium) => self.bitrate_premium,
+                _ => self.bitrate_free,
+            },
+            audio: self.audio_format.clone(),
+        }
     }
 
     async fn execute_outputs(&mut self) -> HandlerResult {
-        self.auth_status.check().await?;
-        let devices = self.client.player().get_devices().await?;
+        self.check_auth().await?;
+        let devices = retry_once(RETRY_DELAY, || self.client.player().get_devices()).await?;
         build_outputs_result(devices.data)
     }
 
     async fn execute_enable_output(&mut self, pos: usize) -> HandlerResult {
-        self.auth_status.check().await?;
-        let devices = self.client.player().get_devices().await?;
+        self.check_auth().await?;
+        let devices = retry_once(RETRY_DELAY, || self.client.player().get_devices()).await?;
         if let Some(Some(dest_id)) = devices.data.get(pos).map(|d| d.id.clone()) {
             self.client.player().transfer(&dest_id, true).await?;
             self.playback.expect_changes().await;
@@ -206,7 +513,7 @@ impl SpotifyHandler {
         repeat: Option<bool>,
         single: Option<bool>,
     ) -> HandlerResult {
-        self.auth_status.check().await?;
+        self.check_auth().await?;
         let playback = self.playback.get().await?;
         if let Some(current) = playback.data.as_ref().map(|d| d.repeat_state) {
             self.client
@@ -220,21 +527,66 @@ impl SpotifyHandler {
     }
 
     async fn execute_currentsong(&mut self) -> HandlerResult {
-        self.auth_status.check().await?;
+        self.check_auth().await?;
         let playback = self.playback.get().await?;
         let context = self.context_cache.get(playback.get_context()).await?;
         build_song_from_playing(playback.get_playing(), context)
     }
 
     async fn execute_playlist_info(&mut self, range: Option<PositionRange>) -> HandlerResult {
-        self.auth_status.check().await?;
+        self.check_auth().await?;
         let playback = self.playback.get().await?;
         let context = self.context_cache.get(playback.get_context()).await?;
         build_playlistinfo_result(playback.get_playing(), context, range)
     }
 
+    async fn execute_playlist_changes(
+        &mut self,
+        since: u32,
+        positions_only: bool,
+    ) -> HandlerResult {
+        self.check_auth().await?;
+        let playback = self.playback.get().await?;
+        let context = self.context_cache.get(playback.get_context()).await?;
+        let version = self.context_cache.version();
+        build_plchanges_result(
+            playback.get_playing(),
+            context,
+            version,
+            since,
+            positions_only,
+        )
+    }
+
+    async fn execute_listall(&mut self, path: Path, with_info: bool) -> HandlerResult {
+        self.check_auth().await?;
+        let albums = retrieve_albums_for_path(&self.client, &path, self.library_max_albums).await?;
+        build_listall_result(&albums, with_info)
+    }
+
+    /// Drops our caches so that the next commands retrieve fresh data from Spotify.
+    /// The job is reported in status until the playback is retrieved again.
+    async fn execute_update(&mut self) -> HandlerResult {
+        self.check_auth().await?;
+        self.context_cache.clear();
+        let job = self.update_jobs.start();
+
+        let mut playback = self.playback.clone();
+        let update_jobs = self.update_jobs.clone();
+        let idle_bus = self.idle_bus.clone();
+        tokio::spawn(async move {
+            playback.expect_changes().await;
+            if let Err(err) = playback.get_fresh().await {
+                warn!["Cannot refresh playback: {}", err];
+            }
+            update_jobs.finish(job);
+            idle_bus.notify(IdleSubsystem::Database);
+        });
+        Ok(HandlerOutput::from(UpdateResponse { updating_db: job }))
+    }
+
     async fn get_volume(&mut self) -> Result<Option<u32>, HandlerError> {
-        self.auth_status.check().await?;
+        self.check_auth().await?;
         Ok(self
             .playback
             .get()
@@ -251,12 +603,329 @@ impl SpotifyHandler {
         }))
     }
 
+    /// Returns the current volume and the device to change it on: the active one,
+    /// or the default device if set, for users to set the volume before playing
+    async fn get_volume_target(&mut self) -> Result<(Option<u32>, Option<String>), HandlerError> {
+        self.check_auth().await?;
+        if let Some(data) = self.playback.get().await?.data.as_ref() {
+            return Ok((data.device.volume_percent, None));
+        }
+        let devices = retry_once(RETRY_DELAY, || self.client.player().get_devices()).await?;
+        let (volume, id) = default_volume_target(&devices.data, self.default_device.as_deref())?;
+        Ok((volume, Some(id)))
+    }
+
+    async fn execute_set_volume(&mut self, volume: u32) -> HandlerResult {
+        let (current, device) = self.get_volume_target().await?;
+        if current.is_none() {
+            return Err(HandlerError::NoMixer);
+        }
+        let client = self.client.clone();
+        self.exec(client.player().set_volume(volume as i32, device.as_deref()))
+            .await
+    }
+
     async fn execute_change_volume(&mut self, delta: i32) -> HandlerResult {
-        if let Some(current) = self.get_volume().await? {
-            let target = 100.min(0.max(current as i32 + delta));
-            self.client.player().set_volume(target, None).await?
+        let (current, device) = self.get_volume_target().await?;
+        let target = compute_volume(current, delta)?;
+        let client = self.client.clone();
+        self.exec(client.player().set_volume(target, device.as_deref()))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::aspotify::fixtures::{
+        build_album, build_context, build_playback, build_track, build_track_simplified,
+    };
+    use crate::handlers::aspotify::playback_watcher::WatcherCommands;
+    use crate::handlers::client::HandlerClient;
+    use crate::listeners::http::listener::HttpListener;
+    use crate::util::ConnectionTracker;
+    use aspotify::{model, ClientCredentials, PlayingType};
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Semaphore;
+    use tokio::time::timeout;
+
+    const SONG: Duration = Duration::from_secs(180);
+
+    /// Answers playback requests without calling Spotify: get returns the cached playback,
+    /// get_fresh the fresh one. Also returns the count of expect_changes calls.
+    fn stub_playback(
+        cached: CachedPlayback,
+        fresh: CachedPlayback,
+    ) -> (PlaybackClient, Arc<AtomicUsize>) {
+        let (tx, mut rx) = mpsc::channel(8);
+        let (cached, fresh) = (Arc::new(cached), Arc::new(fresh));
+        let expected_changes = Arc::new(AtomicUsize::new(0));
+        let counter = expected_changes.clone();
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                match command {
+                    WatcherCommands::Get(resp) => {
+                        let _ = resp.send(Ok(cached.clone()));
+                    }
+                    WatcherCommands::GetFresh(resp) => {
+                        let _ = resp.send(Ok(fresh.clone()));
+                    }
+                    WatcherCommands::FastSpeed => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (PlaybackClient::with_sender(tx), expected_changes)
+    }
+
+    /// Builds a handler for a premium user, retrieving contexts through the given function
+    async fn test_handler(
+        playback: PlaybackClient,
+        retrieve: impl Fn(&model::Context) -> PlayContext + Send + Sync + 'static,
+    ) -> (SpotifyHandler, mpsc::Sender<HandlerInput>) {
+        let settings = Settings::with(config::Config::new()).unwrap();
+        let client = Arc::new(Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        let idle_bus = IdleBus::new();
+        let context_cache =
+            ContextCache::with_retrieval(client.clone(), idle_bus.clone(), retrieve);
+        let auth_status =
+            AuthStatus::signed_in(&settings, client.clone(), Subscription::Premium).await;
+        SpotifyHandler::with_parts(
+            &settings,
+            client,
+            idle_bus,
+            context_cache,
+            auth_status,
+            playback,
+        )
+    }
+
+    /// Runs clones of the handler as workers, as the server does
+    fn run_workers(
+        handler: &SpotifyHandler,
+        tx: mpsc::Sender<HandlerInput>,
+        count: usize,
+    ) -> HandlerClient {
+        for _ in 0..count {
+            let mut worker = handler.clone();
+            tokio::spawn(async move { worker.run().await });
         }
-        self.playback.expect_changes().await;
-        Ok(HandlerOutput::Ok)
+        HandlerClient::new(vec![tx])
+    }
+
+    /// Playback of the first track of an album
+    fn album_playback(is_playing: bool) -> CachedPlayback {
+        let track = build_track(Some("track_id"), SONG);
+        let progress = Some(Duration::from_secs(10));
+        let mut playback = build_playback(
+            Some(PlayingType::Track(track)),
+            progress,
+            is_playing,
+            Instant::now(),
+        );
+        if let Some(data) = playback.data.as_mut() {
+            data.currently_playing.context = Some(build_context(
+                ItemType::Album,
+                "album_id",
+                "https://open.spotify.com/album/album_id",
+            ));
+        }
+        playback
+    }
+
+    fn album_context(key: &model::Context) -> PlayContext {
+        let tracks = vec![build_track_simplified(Some("track_id"), SONG)];
+        PlayContext::Album(build_album(&key.id, tracks))
+    }
+
+    async fn exec_status(client: &HandlerClient) -> String {
+        match client.exec(Command::Status).await {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[tokio::test]
+    async fn it_retrieves_the_context_once_for_status_and_currentsong() {
+        let (playback, _) = stub_playback(album_playback(true), album_playback(true));
+        let retrievals = Arc::new(AtomicUsize::new(0));
+        let counter = retrievals.clone();
+        let (handler, tx) = test_handler(playback, move |key| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            album_context(key)
+        })
+        .await;
+
+        // Commands of a list can be taken by different workers
+        let client = run_workers(&handler, tx, 2);
+        let results = join_all(vec![
+            client.exec(Command::Status),
+            client.exec(Command::CurrentSong),
+        ])
+        .await;
+        for result in results {
+            assert!(matches!(result, Ok(HandlerOutput::Data(_))), "{:?}", result);
+        }
+        assert_eq!(1, retrievals.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn it_toggles_playback_from_the_fresh_state() {
+        // The cached playback is stale, the player was paused meanwhile
+        let (playback, _) = stub_playback(album_playback(true), album_playback(false));
+        let (mut handler, _) = test_handler(playback, album_context).await;
+        assert_eq!(PlayerCall::Resume, handler.plan_play_pause().await.unwrap());
+
+        let (playback, _) = stub_playback(album_playback(false), album_playback(true));
+        let (mut handler, _) = test_handler(playback, album_context).await;
+        assert_eq!(PlayerCall::Pause, handler.plan_play_pause().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_pauses_again_after_seeking_a_paused_player() {
+        let progress = Duration::from_secs(30);
+        let play_at = PlayerCall::PlayAt(ItemType::Album, "album_id".into(), 0, progress);
+
+        let (playback, _) = stub_playback(album_playback(false), album_playback(false));
+        let (mut handler, _) = test_handler(playback, album_context).await;
+        let calls = handler.plan_seek(0, 30.0).await.unwrap();
+        assert_eq!(vec![play_at, PlayerCall::Pause], calls);
+
+        let (playback, _) = stub_playback(album_playback(true), album_playback(true));
+        let (mut handler, _) = test_handler(playback, album_context).await;
+        let calls = handler.plan_seek(0, 30.0).await.unwrap();
+        assert!(matches!(calls.as_slice(), [PlayerCall::PlayAt(..)]));
+
+        // The album only holds one track
+        let err = handler.plan_seek(1, 30.0).await.unwrap_err();
+        assert!(matches!(err, HandlerError::BadSongIndex), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn it_shares_saved_tracks_between_workers() {
+        let (playback, _) = stub_playback(album_playback(true), album_playback(true));
+        let (mut handler, tx) = test_handler(playback, album_context).await;
+        handler.extensions = true;
+        let client = run_workers(&handler, tx, 2);
+
+        // Recorded once workers are running, as saving the current track does
+        *handler.saved_track.lock().await = Some(("track_id".into(), true, Instant::now()));
+        for _ in 0..2 {
+            let status = exec_status(&client).await;
+            assert!(status.contains("\nspotify_saved: 1\n"), "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_serializes_writes_between_workers() {
+        // Nothing is playing, saving fails without calling Spotify
+        let idle = || CachedPlayback::new(None);
+        let (playback, _) = stub_playback(idle(), idle());
+        let (handler, tx) = test_handler(playback, album_context).await;
+        let client = run_workers(&handler, tx, 2);
+
+        let writing = handler.writes.lock().await;
+        let mut save = tokio::spawn({
+            let client = client.clone();
+            async move { client.exec(Command::SaveCurrent).await }
+        });
+        let status = timeout(Duration::from_secs(1), client.exec(Command::Status)).await;
+        assert!(matches!(status, Ok(Ok(_))), "{:?}", status);
+        assert!(timeout(Duration::from_millis(50), &mut save).await.is_err());
+
+        drop(writing);
+        assert!(save.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn it_reports_updates_until_the_playback_is_refreshed() {
+        // Refreshes wait for the test to release them, gets are answered right away
+        let (playback_tx, mut playback_rx) = mpsc::channel(8);
+        let refreshes = Arc::new(Semaphore::new(0));
+        let permits = refreshes.clone();
+        tokio::spawn(async move {
+            let playback = Arc::new(album_playback(true));
+            while let Some(command) = playback_rx.recv().await {
+                match command {
+                    WatcherCommands::Get(resp) => {
+                        let _ = resp.send(Ok(playback.clone()));
+                    }
+                    WatcherCommands::GetFresh(resp) => {
+                        let (playback, permits) = (playback.clone(), permits.clone());
+                        tokio::spawn(async move {
+                            permits.acquire().await.unwrap().forget();
+                            let _ = resp.send(Ok(playback));
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+        let playback = PlaybackClient::with_sender(playback_tx);
+        let (handler, tx) = test_handler(playback, album_context).await;
+        let mut messages = handler.idle_bus.subscribe();
+        let client = run_workers(&handler, tx, 1);
+
+        match client.exec(Command::Update(None)).await {
+            Ok(HandlerOutput::Data(data)) => {
+                assert_eq!("updating_db: 1\n", to_string(&data).unwrap());
+            }
+            other => panic!["Unexpected result {:?}", other],
+        }
+        let status = exec_status(&client).await;
+        assert!(status.contains("\nupdating_db: 1\n"), "{}", status);
+
+        refreshes.add_permits(1);
+        let updated = timeout(Duration::from_secs(1), async {
+            while messages.recv().await.unwrap().what != IdleSubsystem::Database {}
+        });
+        updated.await.expect("Update not finished");
+        let status = exec_status(&client).await;
+        assert!(!status.contains("updating_db"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_playback_warm_between_http_status_calls() {
+        let mut config = config::Config::new();
+        config.set("http_port", 0).unwrap();
+        config.set("bind_address", "127.0.0.1").unwrap();
+        config.set("playback_pool_freq_base_ms", 200).unwrap();
+        let settings = Settings::with(config).unwrap();
+        let idle_bus = IdleBus::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let playback = PlaybackClient::with_fetch(&settings, idle_bus.clone(), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            album_playback(false).data
+        });
+        let (handler, tx) = test_handler(playback, album_context).await;
+        let mut listener = HttpListener::new(
+            &settings,
+            run_workers(&handler, tx, 1),
+            idle_bus,
+            ConnectionTracker::new(None),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .expect("Cannot bind HTTP listener");
+        let url = format!["http://{}/command/status", listener.get_address()];
+        tokio::spawn(async move { listener.run().await });
+
+        // The first call finds an empty cache, pools keep it filled afterwards
+        let response = reqwest::get(&url).await.expect("Request failed");
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        let cold = fetches.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let pooled = fetches.load(Ordering::SeqCst);
+        assert!(pooled > cold, "No pool while warm");
+
+        let response = reqwest::get(&url).await.expect("Request failed");
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        assert_eq!(pooled, fetches.load(Ordering::SeqCst));
     }
 }
@@ -0,0 +1,152 @@
+use crate::handlers::aspotify::context::{retrieve_album_tracks, PAGE_SIZE};
+use crate::handlers::aspotify::song::build_song_from_tracksimplified;
+use crate::mpd_protocol::{
+    DirectoryResponse, FileResponse, HandlerError, HandlerOutput, HandlerResult, ItemType,
+    OutputData, Path,
+};
+use aspotify::{Album, Client, Error};
+
+/// Retrieves the albums saved in the user library, stopping after max_albums
+pub async fn retrieve_saved_albums(
+    client: &Client,
+    max_albums: usize,
+) -> Result<Vec<Album>, Error> {
+    let mut albums = vec![];
+    while albums.len() < max_albums {
+        let limit = PAGE_SIZE.min(max_albums - albums.len());
+        let page = client
+            .library()
+            .get_saved_albums(limit, albums.len(), None)
+            .await?
+            .data;
+        let is_last = page.items.len() < limit || albums.len() + page.items.len() >= page.total;
+        albums.extend(page.items.into_iter().map(|saved| saved.album));
+        if is_last {
+            break;
+        }
+    }
+    for album in albums.iter_mut() {
+        retrieve_album_tracks(client, album).await?;
+    }
+    Ok(albums)
+}
+
+/// Retrieves the albums to list for a given path: the saved albums
+/// for the library root, or a single album
+pub async fn retrieve_albums_for_path(
+    client: &Client,
+    path: &Path,
+    max_albums: usize,
+) -> Result<Vec<Album>, HandlerError> {
+    match path {
+        Path::Empty => Ok(retrieve_saved_albums(client, max_albums).await?),
+        Path::Internal(items) => match items.as_slice() {
+            [(ItemType::Album, id)] => {
+                let mut album = client.albums().get_album(id, None).await?.data;
+                retrieve_album_tracks(client, &mut album).await?;
+                Ok(vec![album])
+            }
+            // Only albums are browsable directories
+            _ => Err(HandlerError::NotImplemented(format![
+                "listing {}",
+                path.to_string()
+            ])),
+        },
+    }
+}
+
+/// Lists albums as directories holding their tracks, with full song
+/// information if with_info is true
+pub fn build_listall_result(albums: &[Album], with_info: bool) -> HandlerResult {
+    let mut out = OutputData::empty();
+    for album in albums {
+        out.push(DirectoryResponse {
+            directory: Path::Internal(vec![(ItemType::Album, album.id.clone())]),
+            last_modified: Some(album_last_modified(album)),
+        });
+        for (pos, track) in album.tracks.items.iter().enumerate() {
+            if with_info {
+                out.push(build_song_from_tracksimplified(track, album, pos));
+            } else if let Some(id) = &track.id {
+                out.push(FileResponse {
+                    file: Path::for_track(&album.id, id),
+                });
+            }
+        }
+    }
+    Ok(HandlerOutput::Data(out))
+}
+
+/// Albums don't change once released, their release date is a stable modification time
+fn album_last_modified(album: &Album) -> String {
+    album.release_date.format("%Y-%m-%dT00:00:00Z").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::aspotify::fixtures::{build_album, build_track_simplified};
+    use crate::handlers::aspotify::library::{build_listall_result, retrieve_albums_for_path};
+    use crate::mpd_protocol::{to_string, AckCode, HandlerError, HandlerOutput, Path};
+    use aspotify::{Client, ClientCredentials};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn render(with_info: bool) -> String {
+        let albums = vec![
+            build_album(
+                "album1",
+                vec![
+                    build_track_simplified(Some("track1"), Duration::from_secs(60)),
+                    build_track_simplified(Some("track2"), Duration::from_secs(60)),
+                ],
+            ),
+            build_album("album2", vec![]),
+        ];
+        match build_listall_result(&albums, with_info) {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[test]
+    fn it_lists_albums_and_tracks() {
+        assert_eq!(
+            render(false),
+            "directory: internal/album/album1\n\
+             Last-Modified: 2020-01-01T00:00:00Z\n\
+             file: internal/album/album1/track/track1\n\
+             file: internal/album/album1/track/track2\n\
+             directory: internal/album/album2\n\
+             Last-Modified: 2020-01-01T00:00:00Z\n"
+        );
+    }
+
+    #[test]
+    fn it_lists_songs_with_info() {
+        let output = render(true);
+        assert!(output.starts_with(
+            "directory: internal/album/album1\nLast-Modified: 2020-01-01T00:00:00Z\nfile: "
+        ));
+        assert!(output.contains("Title: Title\n"));
+        assert!(output
+            .ends_with("directory: internal/album/album2\nLast-Modified: 2020-01-01T00:00:00Z\n"));
+    }
+
+    #[tokio::test]
+    async fn it_refuses_to_list_other_items() {
+        let client = Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        });
+        let path = Path::from_str("internal/artist/artist_id").unwrap();
+        let err = retrieve_albums_for_path(&client, &path, 10)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HandlerError::NotImplemented(_)));
+        assert_eq!(AckCode::Unknown, err.ack_code());
+        assert_eq!(
+            "listing internal/artist/artist_id is not supported by Spotify",
+            err.to_string()
+        );
+    }
+}
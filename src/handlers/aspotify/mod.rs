@@ -1,11 +1,16 @@
 mod auth;
 mod context;
+#[cfg(test)]
+mod fixtures;
 mod handler;
+mod library;
 mod playback;
 mod playback_watcher;
 mod playlist;
+mod retry;
 mod song;
 mod status;
 mod utils;
 
+pub(crate) use auth::AuthStatus;
 pub use handler::SpotifyHandler;
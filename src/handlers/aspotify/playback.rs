@@ -1,11 +1,13 @@
 use crate::mpd_protocol::IdleSubsystem;
-use aspotify::{model, CurrentPlayback};
+use aspotify::{model, CurrentPlayback, PlayingType};
 use enumset::EnumSet;
 use std::time::{Duration, Instant};
 
 pub struct CachedPlayback {
     pub data: Option<CurrentPlayback>,
     retrieved: Instant,
+    // Progress reported before this poll, for the elapsed time not to go backward
+    elapsed_floor: Option<Duration>,
 }
 
 impl CachedPlayback {
@@ -13,6 +15,24 @@ impl CachedPlayback {
         CachedPlayback {
             data: playback,
             retrieved: Instant::now(),
+            elapsed_floor: None,
+        }
+    }
+
+    /// Keeps the elapsed time from going backward when polls report a slightly lower
+    /// progress than extrapolated from the previous one. Seeks, pauses and item changes
+    /// reset it to the reported progress.
+    pub fn keep_monotonic(&mut self, previous: &CachedPlayback) {
+        let (old, new) = match (previous.get_playing(), self.get_playing()) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return,
+        };
+        let previous_elapsed = previous.get_elapsed();
+        if new.is_playing
+            && same_item(old.item.as_ref(), new.item.as_ref())
+            && !CachedPlayback::detect_seek(previous_elapsed, new.progress)
+        {
+            self.elapsed_floor = previous_elapsed;
         }
     }
 
@@ -27,7 +47,52 @@ impl CachedPlayback {
         self.data.as_ref().map(|d| &d.currently_playing)
     }
 
+    /// Returns true if the data is missing or older than max_age,
+    /// meaning it might not reflect the current player state.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.data.is_none() || self.retrieved.elapsed() > max_age
+    }
+
+    #[cfg(test)]
+    pub fn set_retrieved(&mut self, retrieved: Instant) {
+        self.retrieved = retrieved;
+    }
+
+    pub fn get_duration(&self) -> Option<Duration> {
+        self.data
+            .as_ref()
+            .and_then(|d| d.currently_playing.item.as_ref())
+            .map(|item| match item {
+                PlayingType::Track(track) => track.duration,
+                PlayingType::Episode(ep) => ep.duration,
+                PlayingType::Ad(ad) => ad.duration,
+                PlayingType::Unknown(u) => u.duration,
+            })
+    }
+
+    /// Returns the playback progress, extrapolated since the last pool if playing,
+    /// capped to the item's duration.
     pub fn get_elapsed(&self) -> Option<Duration> {
+        let elapsed = match (self.extrapolate_elapsed(), self.elapsed_floor) {
+            (Some(elapsed), Some(floor)) => Some(elapsed.max(floor)),
+            (elapsed, _) => elapsed,
+        };
+        match self.get_duration() {
+            Some(duration) => elapsed.map(|e| e.min(duration)),
+            None => elapsed,
+        }
+    }
+
+    /// Returns true if the extrapolated progress reached the end of the item,
+    /// meaning our cached data is stale.
+    pub fn has_reached_end(&self) -> bool {
+        match (self.extrapolate_elapsed(), self.get_duration()) {
+            (Some(elapsed), Some(duration)) => elapsed >= duration,
+            _ => false,
+        }
+    }
+
+    fn extrapolate_elapsed(&self) -> Option<Duration> {
         match &self.data {
             None => None,
             Some(playing) => {
@@ -58,7 +123,10 @@ impl CachedPlayback {
                     if old.repeat_state != new.repeat_state {
                         changed.insert(IdleSubsystem::Options);
                     }
-                    if old.device.volume_percent != new.device.volume_percent {
+                    // Switching devices changes the volume too, only report the new output
+                    if old.device.id != new.device.id {
+                        changed.insert(IdleSubsystem::Outputs);
+                    } else if old.device.volume_percent != new.device.volume_percent {
                         changed.insert(IdleSubsystem::Mixer);
                     }
                     if old.currently_playing.is_playing != new.currently_playing.is_playing {
@@ -67,7 +135,10 @@ impl CachedPlayback {
                     if old.currently_playing.context != new.currently_playing.context {
                         changed.insert(IdleSubsystem::PlayQueue);
                     }
-                    if old.currently_playing.item != new.currently_playing.item {
+                    if !same_item(
+                        old.currently_playing.item.as_ref(),
+                        new.currently_playing.item.as_ref(),
+                    ) {
                         changed.insert(IdleSubsystem::Player);
                     }
                     if old.device.name != new.device.name {
@@ -92,11 +163,38 @@ impl CachedPlayback {
     }
 }
 
+/// Compares items by type and id, instead of deep comparing them on every poll.
+/// Items without id, like local tracks, are still fully compared.
+fn same_item(old: Option<&PlayingType>, new: Option<&PlayingType>) -> bool {
+    match (old, new) {
+        (Some(old), Some(new)) => match (item_id(old), item_id(new)) {
+            (Some(old_id), Some(new_id)) => {
+                std::mem::discriminant(old) == std::mem::discriminant(new) && old_id == new_id
+            }
+            _ => old == new,
+        },
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn item_id(item: &PlayingType) -> Option<&str> {
+    match item {
+        PlayingType::Track(track) | PlayingType::Ad(track) | PlayingType::Unknown(track) => {
+            track.id.as_deref()
+        }
+        PlayingType::Episode(ep) => Some(&ep.id),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::handlers::aspotify::playback::CachedPlayback;
+    use crate::handlers::aspotify::fixtures::{build_episode, build_playback, build_track};
+    use crate::handlers::aspotify::playback::{same_item, CachedPlayback};
     use crate::mpd_protocol::IdleSubsystem;
-    use aspotify::{Actions, CurrentPlayback, CurrentlyPlaying, Device, DeviceType, RepeatState};
+    use aspotify::{
+        Actions, CurrentPlayback, CurrentlyPlaying, Device, DeviceType, PlayingType, RepeatState,
+    };
     use enumset::EnumSet;
     use std::time::{Duration, Instant};
 
@@ -130,6 +228,7 @@ mod tests {
                 },
             }),
             retrieved,
+            elapsed_floor: None,
         }
     }
 
@@ -178,6 +277,95 @@ mod tests {
         assert_properties(p, PLAYED_SECONDS + DELTA_SECONDS, true)
     }
 
+    #[test]
+    fn it_caps_elapsed_to_duration() {
+        let track = build_track(Some("id"), Duration::from_secs(PLAYED_SECONDS + 5));
+        let p = build_playback(
+            Some(PlayingType::Track(track)),
+            Some(Duration::from_secs(PLAYED_SECONDS)),
+            true,
+            Instant::now() - Duration::from_secs(DELTA_SECONDS),
+        );
+        assert_eq!(PLAYED_SECONDS + 5, p.get_elapsed().unwrap().as_secs());
+        assert!(p.has_reached_end());
+    }
+
+    #[test]
+    fn it_does_not_reach_end_before_duration() {
+        let track = build_track(Some("id"), Duration::from_secs(PLAYED_SECONDS * 2));
+        let p = build_playback(
+            Some(PlayingType::Track(track)),
+            Some(Duration::from_secs(PLAYED_SECONDS)),
+            true,
+            Instant::now() - Duration::from_secs(DELTA_SECONDS),
+        );
+        assert_eq!(
+            PLAYED_SECONDS + DELTA_SECONDS,
+            p.get_elapsed().unwrap().as_secs()
+        );
+        assert!(!p.has_reached_end());
+    }
+
+    #[test]
+    fn it_keeps_elapsed_monotonic() {
+        let track = |id| {
+            Some(PlayingType::Track(build_track(
+                Some(id),
+                Duration::from_secs(PLAYED_SECONDS * 2),
+            )))
+        };
+        let old = build_playback(
+            track("id"),
+            Some(Duration::from_secs(PLAYED_SECONDS)),
+            true,
+            Instant::now() - Duration::from_secs(DELTA_SECONDS),
+        );
+        let old_elapsed = old.get_elapsed().unwrap();
+
+        // Server progress lags behind the extrapolated one
+        let lagging = || Some(old_elapsed - Duration::from_millis(300));
+        let mut new = build_playback(track("id"), lagging(), true, Instant::now());
+        new.keep_monotonic(&old);
+        assert!(new.get_elapsed().unwrap() >= old_elapsed);
+
+        // Reset on track change, pause and seek
+        let mut new = build_playback(track("other"), lagging(), true, Instant::now());
+        new.keep_monotonic(&old);
+        assert!(new.get_elapsed().unwrap() < old_elapsed);
+        let mut new = build_playback(track("id"), lagging(), false, Instant::now());
+        new.keep_monotonic(&old);
+        assert_eq!(lagging(), new.get_elapsed());
+        let seeked = Some(Duration::from_secs(10));
+        let mut new = build_playback(track("id"), seeked, true, Instant::now());
+        new.keep_monotonic(&old);
+        assert_eq!(10, new.get_elapsed().unwrap().as_secs());
+    }
+
+    #[test]
+    fn it_detects_stale_playback() {
+        let max_age = Duration::from_secs(1);
+        let track = || {
+            Some(PlayingType::Track(build_track(
+                Some("id"),
+                Duration::from_secs(60),
+            )))
+        };
+        let progress = Some(Duration::from_secs(PLAYED_SECONDS));
+
+        // Cache says playing, but was retrieved before the last fast pool
+        let old = build_playback(
+            track(),
+            progress,
+            true,
+            Instant::now() - Duration::from_secs(DELTA_SECONDS),
+        );
+        assert!(old.is_stale(max_age));
+
+        let fresh = build_playback(track(), progress, true, Instant::now());
+        assert!(!fresh.is_stale(max_age));
+        assert!(CachedPlayback::new(None).is_stale(max_age));
+    }
+
     #[test]
     fn it_detects_seek() {
         let p1 = build_current_playback(
@@ -222,4 +410,51 @@ mod tests {
         );
         assert_changes(p1, p2, vec![])
     }
+
+    #[test]
+    fn it_detects_device_changes() {
+        let now = Instant::now();
+        let p1 = build_current_playback(None, false, now);
+        let mut p2 = build_current_playback(None, false, now);
+        p2.data.as_mut().unwrap().device.name = "Speaker".to_string();
+        p2.data.as_mut().unwrap().device.volume_percent = Some(50);
+        assert_changes(p1, p2, vec![IdleSubsystem::Outputs, IdleSubsystem::Mixer])
+    }
+
+    #[test]
+    fn it_does_not_report_volume_on_device_switch() {
+        let now = Instant::now();
+        let p1 = build_current_playback(None, false, now);
+        let mut p2 = build_current_playback(None, false, now);
+        p2.data.as_mut().unwrap().device.id = Some("other_device".to_string());
+        p2.data.as_mut().unwrap().device.volume_percent = Some(50);
+        assert_changes(p1, p2, vec![IdleSubsystem::Outputs])
+    }
+
+    #[test]
+    fn it_compares_items_like_a_full_comparison() {
+        let duration = Duration::from_secs(60);
+        let mut renamed = build_track(Some("track1"), duration);
+        renamed.name = "Renamed".to_string();
+        let mut local = build_track(None, duration);
+        local.name = "Local".to_string();
+        let items = [
+            Some(PlayingType::Track(build_track(Some("track1"), duration))),
+            Some(PlayingType::Track(build_track(Some("track2"), duration))),
+            Some(PlayingType::Ad(build_track(Some("track1"), duration))),
+            Some(PlayingType::Episode(build_episode("track1", duration))),
+            Some(PlayingType::Track(build_track(None, duration))),
+            Some(PlayingType::Track(local)),
+            None,
+        ];
+        for old in items.iter() {
+            for new in items.iter() {
+                assert_eq!(old == new, same_item(old.as_ref(), new.as_ref()));
+            }
+        }
+
+        // Only the id matters for tracks that have one
+        let track = PlayingType::Track(build_track(Some("track1"), duration));
+        assert!(same_item(Some(&track), Some(&PlayingType::Track(renamed))));
+    }
 }
@@ -1,8 +1,9 @@
 use crate::handlers::aspotify::playback::CachedPlayback;
 use crate::handlers::aspotify::playback_watcher::WatcherCommands::*;
+use crate::handlers::aspotify::retry::{retry_once, RETRY_DELAY};
 use crate::mpd_protocol::HandlerError;
-use crate::util::{IdleBus, Settings};
-use aspotify::Response;
+use crate::util::{IdleBus, Settings, METRICS};
+use aspotify::{CurrentPlayback, Response};
 use enumset::EnumSet;
 use futures::TryFutureExt;
 use log::{debug, warn};
@@ -14,6 +15,7 @@ use tokio_util::time::delay_queue::DelayQueue;
 
 type GetResult = Result<Arc<CachedPlayback>, HandlerError>;
 
+#[derive(Clone)]
 pub struct PlaybackClient {
     tx: mpsc::Sender<WatcherCommands>,
 }
@@ -28,19 +30,50 @@ impl PlaybackClient {
         Self { tx }
     }
 
+    /// Builds a client sending its requests to the given channel, for tests to answer them
+    #[cfg(test)]
+    pub fn with_sender(tx: mpsc::Sender<WatcherCommands>) -> Self {
+        Self { tx }
+    }
+
+    /// Builds a client whose watcher fetches the playback through the given function, for tests
+    #[cfg(test)]
+    pub fn with_fetch(
+        settings: &Settings,
+        idle_bus: Arc<IdleBus>,
+        fetch: impl Fn() -> Option<CurrentPlayback> + Send + Sync + 'static,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        let mut watcher = PlaybackWatcher::with_fetch(settings, idle_bus, fetch);
+
+        tokio::spawn(async move { watcher.run(rx).await });
+
+        Self { tx }
+    }
+
     pub async fn expect_changes(&mut self) {
         let _ = self.tx.send(WatcherCommands::FastSpeed).await;
     }
 
     pub async fn get(&mut self) -> GetResult {
         let (tx, rx) = oneshot::channel();
-        let _ = self
-            .tx
-            .send(WatcherCommands::Get(tx))
-            .map_err(|e| HandlerError::FromString(e.to_string()))
-            .await?;
+        self.send(WatcherCommands::Get(tx)).await?;
         rx.await.unwrap()
     }
+
+    /// Returns the playback, refreshed first if older than the fast pooling frequency
+    pub async fn get_fresh(&mut self) -> GetResult {
+        let (tx, rx) = oneshot::channel();
+        self.send(WatcherCommands::GetFresh(tx)).await?;
+        rx.await.unwrap()
+    }
+
+    async fn send(&mut self, command: WatcherCommands) -> Result<(), HandlerError> {
+        self.tx
+            .send(command)
+            .map_err(|e| HandlerError::FromString(e.to_string()))
+            .await
+    }
 }
 
 pub enum WatcherCommands {
@@ -48,6 +81,7 @@ pub enum WatcherCommands {
     SlowSpeed,
     Pool,
     Get(oneshot::Sender<GetResult>),
+    GetFresh(oneshot::Sender<GetResult>),
 }
 
 pub struct PlaybackWatcher {
@@ -56,10 +90,17 @@ pub struct PlaybackWatcher {
     cache: Arc<CachedPlayback>,
     messages: DelayQueue<WatcherCommands>,
     fast_pool: bool,
+    monotonic_elapsed: bool,
     pool_freq_base: Duration,
     pool_freq_fast: Duration,
+    // Replaces playback requests to the Spotify API in tests
+    #[cfg(test)]
+    stub_fetch: Option<StubFetch>,
 }
 
+#[cfg(test)]
+type StubFetch = Box<dyn Fn() -> Option<CurrentPlayback> + Send + Sync>;
+
 impl PlaybackWatcher {
     pub fn new(settings: &Settings, client: Arc<aspotify::Client>, idle_bus: Arc<IdleBus>) -> Self {
         PlaybackWatcher {
@@ -68,8 +109,27 @@ impl PlaybackWatcher {
             cache: Arc::new(CachedPlayback::new(None)),
             messages: DelayQueue::new(),
             fast_pool: false,
-            pool_freq_base: Duration::from_secs(settings.playback_pool_freq_base_seconds),
-            pool_freq_fast: Duration::from_secs(settings.playback_pool_freq_fast_seconds),
+            monotonic_elapsed: settings.status_monotonic_elapsed,
+            pool_freq_base: settings.playback_pool_freq_base(),
+            pool_freq_fast: settings.playback_pool_freq_fast(),
+            #[cfg(test)]
+            stub_fetch: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_fetch(
+        settings: &Settings,
+        idle_bus: Arc<IdleBus>,
+        fetch: impl Fn() -> Option<CurrentPlayback> + Send + Sync + 'static,
+    ) -> Self {
+        let client = Arc::new(aspotify::Client::new(aspotify::ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }));
+        PlaybackWatcher {
+            stub_fetch: Some(Box::new(fetch)),
+            ..PlaybackWatcher::new(settings, client, idle_bus)
         }
     }
 
@@ -112,6 +172,14 @@ impl PlaybackWatcher {
                     warn!["Cannot send response"];
                 }
             }
+            GetFresh(sender) => {
+                if self.cache.is_stale(self.pool_freq_fast) {
+                    self.do_get().await;
+                }
+                if sender.send(Ok(self.cache.clone())).is_err() {
+                    warn!["Cannot send response"];
+                }
+            }
         }
     }
 
@@ -143,18 +211,31 @@ impl PlaybackWatcher {
         }
     }
 
+    async fn fetch(&self) -> Result<Option<CurrentPlayback>, aspotify::Error> {
+        #[cfg(test)]
+        if let Some(fetch) = &self.stub_fetch {
+            return Ok(fetch());
+        }
+        let result = retry_once(RETRY_DELAY, || self.client.player().get_playback(None)).await;
+        METRICS.spotify_request(result.is_err());
+        result.map(|Response { data, .. }| data)
+    }
+
     async fn do_get(&mut self) {
         debug!("Retrieving status...");
-        let changed = match self.client.player().get_playback(None).await {
+        let changed = match self.fetch().await {
             Err(err) => {
                 warn!("Error fetching playback state: {}", err);
                 EnumSet::empty()
             }
-            Ok(Response { data: new, .. }) => {
+            Ok(new) => {
+                // Stored even if unchanged, for get_fresh to know it was just retrieved
                 let changed = self.cache.compare(&new);
-                if !changed.is_empty() {
-                    self.cache = CachedPlayback::new(new).into();
+                let mut playback = CachedPlayback::new(new);
+                if self.monotonic_elapsed {
+                    playback.keep_monotonic(&self.cache);
                 }
+                self.cache = playback.into();
                 changed
             }
         };
@@ -170,3 +251,46 @@ impl PlaybackWatcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::aspotify::fixtures::build_playback;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    /// Builds a watcher returning a paused playback, counting its fetches
+    fn counting_watcher(pool_freq_fast_ms: u64) -> (PlaybackWatcher, Arc<AtomicUsize>) {
+        let mut config = config::Config::new();
+        config
+            .set("playback_pool_freq_fast_ms", pool_freq_fast_ms as i64)
+            .unwrap();
+        let settings = Settings::with(config).unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let watcher = PlaybackWatcher::with_fetch(&settings, IdleBus::new(), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            build_playback(None, None, false, Instant::now()).data
+        });
+        (watcher, fetches)
+    }
+
+    async fn get_fresh(watcher: &mut PlaybackWatcher) -> Arc<CachedPlayback> {
+        let (tx, rx) = oneshot::channel();
+        watcher.on_command(GetFresh(tx)).await;
+        rx.await.unwrap().expect("Cannot get playback")
+    }
+
+    #[tokio::test]
+    async fn it_refreshes_the_cache_when_the_playback_is_unchanged() {
+        let (mut watcher, fetches) = counting_watcher(50);
+        assert!(get_fresh(&mut watcher).await.data.is_some());
+        assert_eq!(1, fetches.load(Ordering::SeqCst));
+
+        // Once the cache is stale, only the first call fetches the unchanged playback
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        get_fresh(&mut watcher).await;
+        get_fresh(&mut watcher).await;
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+    }
+}
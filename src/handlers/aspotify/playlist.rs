@@ -3,7 +3,9 @@ use crate::handlers::aspotify::song::{
     build_song_from_episode, build_song_from_episodesimplified, build_song_from_playing,
     build_song_from_track, build_song_from_tracksimplified,
 };
-use crate::mpd_protocol::{HandlerOutput, HandlerResult, OutputData, PositionRange};
+use crate::mpd_protocol::{
+    song_id_for_pos, HandlerOutput, HandlerResult, OutputData, PositionRange, QueuePosIdResponse,
+};
 use aspotify::{CurrentlyPlaying, PlaylistItemType};
 use std::sync::Arc;
 
@@ -34,7 +36,7 @@ pub fn build_playlistinfo_result(
         PlayContext::Playlist(playlist) => {
             for (pos, item) in playlist.tracks.items.iter().enumerate() {
                 if include(pos) {
-                    let pos_provider = |_: &str| pos;
+                    let pos_provider = |_: &str| Some(pos);
                     match &item.item {
                         Some(PlaylistItemType::Track(track)) => {
                             songs.push(build_song_from_track(track, pos_provider))
@@ -50,14 +52,14 @@ pub fn build_playlistinfo_result(
         PlayContext::Artist(_, tracks) => {
             for (pos, track) in tracks.iter().enumerate() {
                 if include(pos) {
-                    let pos_provider = |_: &str| pos;
+                    let pos_provider = |_: &str| Some(pos);
                     songs.push(build_song_from_track(track, pos_provider));
                 }
             }
         }
 
-        PlayContext::Track(track) => songs.push(build_song_from_track(track, |_| 0)),
-        PlayContext::Episode(ep) => songs.push(build_song_from_episode(ep, |_| 0)),
+        PlayContext::Track(track) => songs.push(build_song_from_track(track, |_| Some(0))),
+        PlayContext::Episode(ep) => songs.push(build_song_from_episode(ep, |_| Some(0))),
 
         // Fallback to a single item playlist when the context is not supported (radio)
         PlayContext::Empty => return build_song_from_playing(playing, context),
@@ -65,3 +67,112 @@ pub fn build_playlistinfo_result(
 
     Ok(HandlerOutput::Data(songs))
 }
+
+/// Lists the queue for plchanges and plchangesposid. Loading a new context replaces
+/// the whole queue, so every song changed unless the client knows the current version.
+/// Versions newer than ours are from before a restart, and are stale too.
+pub fn build_plchanges_result(
+    playing: Option<&CurrentlyPlaying>,
+    context: Arc<PlayContext>,
+    version: u32,
+    since: u32,
+    positions_only: bool,
+) -> HandlerResult {
+    if since == version {
+        return Ok(HandlerOutput::Ok);
+    }
+    if !positions_only {
+        return build_playlistinfo_result(playing, context, None);
+    }
+
+    let positions: Vec<usize> = match context.as_ref() {
+        PlayContext::Album(album) => (0..album.tracks.items.len()).collect(),
+        PlayContext::Show(show) => (0..show.episodes.items.len()).collect(),
+        PlayContext::Playlist(playlist) => playlist
+            .tracks
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.item.is_some())
+            .map(|(pos, _)| pos)
+            .collect(),
+        PlayContext::Artist(_, tracks) => (0..tracks.len()).collect(),
+        PlayContext::Track(_) | PlayContext::Episode(_) => vec![0],
+        PlayContext::Empty => match playing.and_then(|p| p.item.as_ref()) {
+            Some(_) => vec![0],
+            None => vec![],
+        },
+    };
+    let mut songs = OutputData::empty();
+    for pos in positions {
+        songs.push(QueuePosIdResponse {
+            cpos: pos,
+            id: song_id_for_pos(pos),
+        });
+    }
+    Ok(HandlerOutput::Data(songs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::aspotify::fixtures::{build_album, build_track_simplified};
+    use crate::mpd_protocol::{to_string, Command};
+    use std::time::Duration;
+
+    #[test]
+    fn it_lists_a_single_position() {
+        let duration = Duration::from_secs(60);
+        let tracks = ["track1", "track2", "track3", "track4"]
+            .iter()
+            .map(|id| build_track_simplified(Some(id), duration))
+            .collect();
+        let context = Arc::new(PlayContext::Album(build_album("album_id", tracks)));
+
+        let range = match "playlistinfo 2".parse() {
+            Ok(Command::PlaylistInfo(range)) => range,
+            other => panic!["Unexpected command {:?}", other],
+        };
+        let listed = match build_playlistinfo_result(None, context, range) {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        };
+        assert_eq!(1, listed.matches("file: ").count());
+        assert!(listed.contains("X-SPOTIFY-ID: track3\n"));
+        assert!(listed.contains("Pos: 2\n"));
+        assert!(listed.contains("Id: 3\n"));
+    }
+
+    #[test]
+    fn it_lists_changes_for_stale_versions() {
+        let duration = Duration::from_secs(60);
+        let tracks = ["track1", "track2"]
+            .iter()
+            .map(|id| build_track_simplified(Some(id), duration))
+            .collect();
+        let context = Arc::new(PlayContext::Album(build_album("album_id", tracks)));
+        let render = |since: u32, positions_only: bool| match build_plchanges_result(
+            None,
+            context.clone(),
+            2,
+            since,
+            positions_only,
+        ) {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            Ok(HandlerOutput::Ok) => String::new(),
+            other => panic!["Unexpected result {:?}", other],
+        };
+
+        // Up to date clients get an empty response
+        assert_eq!("", render(2, false));
+        assert_eq!("", render(2, true));
+
+        // Older and unknown versions get the whole queue
+        for since in [0, 1, 3] {
+            let listed = render(since, false);
+            assert_eq!(2, listed.matches("file: ").count(), "{}", listed);
+            assert!(listed.contains("X-SPOTIFY-ID: track2\n"));
+            assert_eq!("cpos: 0\nId: 1\ncpos: 1\nId: 2\n", render(since, true));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use aspotify::Error;
+use log::warn;
+use std::future::Future;
+use std::time::Duration;
+
+// Delay before retrying a call that failed with a server error
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Runs a call, retrying it once after the given delay if Spotify returned a transient
+/// server error. Only use it for idempotent calls, never for ones changing the player state.
+pub async fn retry_once<T, F, Fut>(delay: Duration, mut call: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    match call().await {
+        Err(err) if is_transient(&err) => {
+            warn!["Transient Spotify error, retrying: {}", err];
+            tokio::time::sleep(delay).await;
+            call().await
+        }
+        result => result,
+    }
+}
+
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Endpoint(err) => err.status.is_server_error(),
+        Error::Http(err) => err.status().is_some_and(|s| s.is_server_error()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aspotify::EndpointError;
+    use reqwest::StatusCode;
+    use std::cell::Cell;
+
+    fn endpoint_error(status: StatusCode) -> Error {
+        Error::Endpoint(EndpointError {
+            status,
+            message: "error".to_string(),
+            reason: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn it_retries_server_errors_once() {
+        let calls = Cell::new(0);
+        let result = retry_once(Duration::default(), || {
+            calls.set(calls.get() + 1);
+            let result = match calls.get() {
+                1 => Err(endpoint_error(StatusCode::SERVICE_UNAVAILABLE)),
+                _ => Ok(calls.get()),
+            };
+            async { result }
+        })
+        .await;
+        assert_eq!(2, result.expect("Retry should succeed"));
+        assert_eq!(2, calls.get());
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_after_one_retry() {
+        let calls = Cell::new(0);
+        let result: Result<(), Error> = retry_once(Duration::default(), || {
+            calls.set(calls.get() + 1);
+            async { Err(endpoint_error(StatusCode::BAD_GATEWAY)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(2, calls.get());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_client_errors() {
+        let calls = Cell::new(0);
+        let result: Result<(), Error> = retry_once(Duration::default(), || {
+            calls.set(calls.get() + 1);
+            async { Err(endpoint_error(StatusCode::NOT_FOUND)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(1, calls.get());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::handlers::aspotify::context::PlayContext;
-use crate::mpd_protocol::{HandlerOutput, HandlerResult, Path, SongResponse};
+use crate::mpd_protocol::{
+    song_id_for_pos, HandlerOutput, HandlerResult, ItemType, Path, SongDuration, SongResponse,
+};
 use aspotify::{
     Album, ArtistSimplified, CurrentlyPlaying, Episode, EpisodeSimplified, PlayingType, Show,
     Track, TrackSimplified,
@@ -27,21 +29,26 @@ pub fn build_song_from_playing(
         },
     })
 }
-pub fn build_song_from_track(track: &Track, pos_provider: impl Fn(&str) -> usize) -> SongResponse {
+pub fn build_song_from_track(
+    track: &Track,
+    pos_provider: impl Fn(&str) -> Option<usize>,
+) -> SongResponse {
     let spotify_id = track.id.clone().unwrap_or_else(String::new);
     let pos = pos_provider(spotify_id.as_str());
 
     SongResponse {
-        file: Path::for_track(unwrap(&track.album.id), unwrap(&track.id)),
+        file: track_path(track.album.id.as_deref(), track.id.as_deref()),
         artist: flatten_artists(track.artists.as_ref()),
         album: track.album.name.clone(),
         title: track.name.clone(),
         date: track.album.release_date.map(|d| d.year() as u32),
         pos,
-        id: pos + 1,
-        duration: track.duration.as_secs_f64(),
+        id: pos.map(song_id_for_pos),
+        duration: SongDuration(track.duration),
         track: Some(track.track_number),
         disc: Some(track.disc_number),
+        spotify_id: track.id.clone(),
+        isrc: track.external_ids.get("isrc").cloned(),
     }
 }
 
@@ -51,20 +58,25 @@ pub fn build_song_from_tracksimplified(
     pos: usize,
 ) -> SongResponse {
     SongResponse {
-        file: Path::for_track(&album.id, unwrap(&track.id)),
+        file: track_path(Some(&album.id), track.id.as_deref()),
         artist: flatten_artists(track.artists.as_ref()),
         album: album.name.clone(),
         title: track.name.clone(),
         date: Some(album.release_date.year() as u32),
-        pos,
-        id: pos + 1,
-        duration: track.duration.as_secs_f64(),
+        pos: Some(pos),
+        id: Some(song_id_for_pos(pos)),
+        duration: SongDuration(track.duration),
         track: Some(track.track_number),
         disc: Some(track.disc_number),
+        spotify_id: track.id.clone(),
+        isrc: None,
     }
 }
 
-pub fn build_song_from_episode(ep: &Episode, pos_provider: impl Fn(&str) -> usize) -> SongResponse {
+pub fn build_song_from_episode(
+    ep: &Episode,
+    pos_provider: impl Fn(&str) -> Option<usize>,
+) -> SongResponse {
     let spotify_id = ep.id.as_str();
     let pos = pos_provider(spotify_id);
 
@@ -75,10 +87,12 @@ pub fn build_song_from_episode(ep: &Episode, pos_provider: impl Fn(&str) -> usiz
         title: ep.name.clone(),
         date: Some(ep.release_date.year() as u32),
         pos,
-        id: pos + 1,
-        duration: ep.duration.as_secs_f64(),
+        id: pos.map(song_id_for_pos),
+        duration: SongDuration(ep.duration),
         track: None,
         disc: None,
+        spotify_id: Some(ep.id.clone()),
+        isrc: None,
     }
 }
 
@@ -93,11 +107,13 @@ pub fn build_song_from_episodesimplified(
         album: show.name.clone(),
         title: ep.name.clone(),
         date: Some(ep.release_date.year() as u32),
-        pos,
-        id: pos + 1,
-        duration: ep.duration.as_secs_f64(),
+        pos: Some(pos),
+        id: Some(song_id_for_pos(pos)),
+        duration: SongDuration(ep.duration),
         track: None,
         disc: None,
+        spotify_id: Some(ep.id.clone()),
+        isrc: None,
     }
 }
 
@@ -109,10 +125,106 @@ pub fn flatten_artists(artists: &[ArtistSimplified]) -> String {
         .join(", ")
 }
 
-/// Unwrap optional track and album IDs, assuming they are filled
-pub fn unwrap(v: &Option<String>) -> &str {
-    match v {
-        None => "unknown",
-        Some(s) => s.as_ref(),
+/// Local and unavailable tracks miss their ids, only known ids are kept in the path
+/// for artwork lookups not to query Spotify with made-up ids
+pub fn track_path(album_id: Option<&str>, track_id: Option<&str>) -> Path {
+    match (album_id, track_id) {
+        (Some(album_id), Some(track_id)) => Path::for_track(album_id, track_id),
+        (Some(album_id), None) => Path::Internal(vec![(ItemType::Album, album_id.to_string())]),
+        (None, Some(track_id)) => Path::Internal(vec![(ItemType::Track, track_id.to_string())]),
+        (None, None) => Path::Empty,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::aspotify::context::PlayContext;
+    use crate::handlers::aspotify::fixtures::{
+        build_album, build_playback, build_track, build_track_simplified,
+    };
+    use crate::handlers::aspotify::song::{
+        build_song_from_playing, build_song_from_track, track_path,
+    };
+    use crate::mpd_protocol::{to_string, HandlerOutput, Path};
+    use aspotify::PlayingType;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn it_exposes_spotify_ids() {
+        let mut track = build_track(Some("track_id"), Duration::from_secs(60));
+        let song =
+            to_string(&build_song_from_track(&track, |_| Some(0))).expect("Serializer error");
+        assert!(
+            song.ends_with("X-SPOTIFY-ID: track_id\n"),
+            "Unexpected song {}",
+            song
+        );
+
+        track
+            .external_ids
+            .insert("isrc".to_string(), "USUM71703861".to_string());
+        let song =
+            to_string(&build_song_from_track(&track, |_| Some(0))).expect("Serializer error");
+        assert!(song.ends_with("X-SPOTIFY-ID: track_id\nISRC: USUM71703861\n"));
+    }
+
+    #[test]
+    fn it_omits_positions_of_unknown_songs() {
+        let duration = Duration::from_secs(60);
+        let context = Arc::new(PlayContext::Album(build_album(
+            "album_id",
+            vec![build_track_simplified(Some("track1"), duration)],
+        )));
+        let current_song = |id: Option<&str>| {
+            let item = PlayingType::Track(build_track(id, duration));
+            let playback = build_playback(Some(item), None, true, Instant::now());
+            match build_song_from_playing(playback.get_playing(), context.clone()) {
+                Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+                other => panic!["Unexpected result {:?}", other],
+            }
+        };
+
+        let found = current_song(Some("track1"));
+        assert!(
+            found.contains("Pos: 0\nId: 1\n"),
+            "Unexpected song {}",
+            found
+        );
+        for id in &[None, Some("other")] {
+            let song = current_song(*id);
+            assert!(!song.contains("Pos:"), "Unexpected song {}", song);
+            assert!(!song.contains("Id:"), "Unexpected song {}", song);
+        }
+    }
+
+    #[test]
+    fn it_builds_paths_for_local_tracks() {
+        let mut track = build_track(None, Duration::from_secs(60));
+        track.album.id = None;
+        track.is_local = true;
+        let song = build_song_from_track(&track, |_| None);
+        assert_eq!(Path::Empty, song.file);
+        let rendered = to_string(&song).expect("Serializer error");
+        assert!(
+            rendered.starts_with("file: \n"),
+            "Unexpected song {}",
+            rendered
+        );
+        assert!(
+            !rendered.contains("unknown"),
+            "Unexpected song {}",
+            rendered
+        );
+
+        // Known ids are kept for artwork lookups
+        assert_eq!(
+            "internal/album/album_id",
+            track_path(Some("album_id"), None).to_string()
+        );
+        assert_eq!(
+            "internal/track/track_id",
+            track_path(None, Some("track_id")).to_string()
+        );
     }
 }
@@ -1,12 +1,11 @@
 use crate::handlers::aspotify::context::PlayContext;
 use crate::handlers::aspotify::playback::CachedPlayback;
 use crate::mpd_protocol::{
-    HandlerOutput, HandlerResult, OutputData, OutputsResponse, PlaybackStatus, StatusDurations,
-    StatusPlaylistInfo, StatusResponse,
+    HandlerOutput, HandlerResult, OutputData, OutputsResponse, PlaybackStatus, StatusAudioInfo,
+    StatusDurations, StatusExtensions, StatusPlaylistInfo, StatusResponse,
 };
 use aspotify::{CurrentPlayback, Device, PlayingType, RepeatState};
 use std::sync::Arc;
-use std::time::Duration;
 
 pub fn build_outputs_result(devices: Vec<Device>) -> HandlerResult {
     let mut out = OutputData::empty();
@@ -21,16 +20,35 @@ pub fn build_outputs_result(devices: Vec<Device>) -> HandlerResult {
     Ok(HandlerOutput::Data(out))
 }
 
-pub fn build_status_result(input: Arc<CachedPlayback>, context: Arc<PlayContext>) -> HandlerResult {
+/// Current context, with the version of the queue it was loaded as
+pub struct StatusQueue {
+    pub context: Arc<PlayContext>,
+    pub version: u32,
+}
+
+pub fn build_status_result(
+    input: Arc<CachedPlayback>,
+    queue: StatusQueue,
+    audio_info: StatusAudioInfo,
+    extensions: Option<StatusExtensions>,
+    error: Option<String>,
+    unknown_volume: Option<i32>,
+    updating_db: Option<usize>,
+) -> HandlerResult {
     match &input.data {
         None => Ok(HandlerOutput::from(StatusResponse {
-            volume: None,
+            volume: unknown_volume,
             state: PlaybackStatus::Stop,
             random: false,
             repeat: false,
             single: false,
+            playlist: queue.version,
             durations: None,
+            audio_info: None,
             playlist_info: None,
+            extensions: None,
+            updating_db,
+            error,
         })),
         Some(data) => {
             let spotify_id = data
@@ -40,9 +58,14 @@ pub fn build_status_result(input: Arc<CachedPlayback>, context: Arc<PlayContext>
                 .map(extract_id)
                 .flatten()
                 .unwrap_or_else(|| String::from("unknown"));
+            let context = queue.context;
             let pos = context.position_for_id(spotify_id.as_str());
             Ok(HandlerOutput::from(StatusResponse {
-                volume: data.device.volume_percent,
+                volume: data
+                    .device
+                    .volume_percent
+                    .map(|v| v as i32)
+                    .or(unknown_volume),
                 state: if data.currently_playing.is_playing {
                     PlaybackStatus::Play
                 } else {
@@ -51,29 +74,56 @@ pub fn build_status_result(input: Arc<CachedPlayback>, context: Arc<PlayContext>
                 random: data.shuffle_state,
                 repeat: RepeatState::Off.ne(&data.repeat_state),
                 single: RepeatState::Track.eq(&data.repeat_state),
-                durations: extract_durations(&data, input.get_elapsed()),
+                playlist: queue.version,
+                durations: extract_durations(&input),
+                audio_info: extract_audio_info(data, audio_info),
                 playlist_info: Some(StatusPlaylistInfo::new(context.size(), pos)),
+                extensions,
+                updating_db,
+                error,
             }))
         }
     }
 }
 
-pub fn extract_durations(
-    data: &CurrentPlayback,
-    elapsed: Option<Duration>,
-) -> Option<StatusDurations> {
-    let duration = data.currently_playing.item.as_ref().map(|item| match item {
-        PlayingType::Track(track) => track.duration,
-        PlayingType::Episode(ep) => ep.duration,
-        PlayingType::Ad(ad) => ad.duration,
-        PlayingType::Unknown(u) => u.duration,
-    });
-    if let Some(elapsed) = elapsed {
-        if let Some(duration) = duration {
-            return Some(StatusDurations { elapsed, duration });
+/// Appends the items of the second output to the first one,
+/// for them to be written as a single response block
+pub fn merge_outputs(first: HandlerOutput, second: HandlerOutput) -> HandlerOutput {
+    match (first, second) {
+        (HandlerOutput::Data(mut first), HandlerOutput::Data(second)) => {
+            first.data.extend(second.data);
+            HandlerOutput::Data(first)
         }
+        (HandlerOutput::Ok, second) => second,
+        (first, _) => first,
+    }
+}
+
+pub fn extract_durations(input: &CachedPlayback) -> Option<StatusDurations> {
+    match (input.get_elapsed(), input.get_duration()) {
+        (Some(elapsed), Some(duration)) => Some(StatusDurations { elapsed, duration }),
+        _ => None,
+    }
+}
+
+/// Audio format and bitrate are only reported while a track is playing
+pub fn extract_audio_info(
+    data: &CurrentPlayback,
+    audio_info: StatusAudioInfo,
+) -> Option<StatusAudioInfo> {
+    match data.currently_playing.item {
+        Some(_) if data.currently_playing.is_playing => Some(audio_info),
+        _ => None,
+    }
+}
+
+pub fn extract_extensions(input: &CachedPlayback, saved: Option<bool>) -> StatusExtensions {
+    StatusExtensions {
+        spotify_context: input
+            .get_context()
+            .map(|c| format!["{}:{}", c.context_type.as_str(), c.id]),
+        spotify_saved: saved,
     }
-    None
 }
 
 pub fn extract_id(item: &PlayingType) -> Option<String> {
@@ -84,3 +134,388 @@ pub fn extract_id(item: &PlayingType) -> Option<String> {
         PlayingType::Unknown(track) => track.id.clone(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::aspotify::context::PlayContext;
+    use crate::handlers::aspotify::fixtures;
+    use crate::handlers::aspotify::playback::CachedPlayback;
+    use crate::handlers::aspotify::song::build_song_from_playing;
+    use crate::handlers::aspotify::status::{
+        build_status_result, extract_extensions, merge_outputs, StatusQueue,
+    };
+    use crate::mpd_protocol::{to_string, HandlerOutput, StatusAudioInfo, StatusExtensions};
+    use aspotify::{
+        Actions, Context, CurrentPlayback, CurrentlyPlaying, Device, DeviceType, ItemType,
+        PlayingType, RepeatState,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn build_playback(repeat_state: RepeatState) -> Arc<CachedPlayback> {
+        Arc::new(CachedPlayback::new(Some(CurrentPlayback {
+            device: Device {
+                id: None,
+                is_active: true,
+                is_private_session: false,
+                is_restricted: false,
+                name: "".to_string(),
+                device_type: DeviceType::Computer,
+                volume_percent: None,
+            },
+            repeat_state,
+            shuffle_state: false,
+            currently_playing: CurrentlyPlaying {
+                context: None,
+                progress: None,
+                is_playing: false,
+                item: None,
+                actions: Actions { disallows: vec![] },
+            },
+        })))
+    }
+
+    fn render_status(playback: Arc<CachedPlayback>, extensions: bool) -> String {
+        let extensions = match extensions {
+            true => Some(extract_extensions(&playback, None)),
+            false => None,
+        };
+        render_status_with(playback, extensions, None)
+    }
+
+    fn render_status_with(
+        playback: Arc<CachedPlayback>,
+        extensions: Option<StatusExtensions>,
+        error: Option<String>,
+    ) -> String {
+        render_status_volume(playback, extensions, error, None)
+    }
+
+    fn render_status_volume(
+        playback: Arc<CachedPlayback>,
+        extensions: Option<StatusExtensions>,
+        error: Option<String>,
+        unknown_volume: Option<i32>,
+    ) -> String {
+        let audio_info = StatusAudioInfo {
+            bitrate: 320,
+            audio: "44100:16:2".to_string(),
+        };
+        let queue = StatusQueue {
+            context: Arc::new(PlayContext::Empty),
+            version: 0,
+        };
+        match build_status_result(
+            playback,
+            queue,
+            audio_info,
+            extensions,
+            error,
+            unknown_volume,
+            None,
+        ) {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[test]
+    fn it_maps_repeat_states() {
+        let cases = vec![
+            (RepeatState::Off, "repeat: 0\nrandom: 0\nsingle: 0\n"),
+            (RepeatState::Context, "repeat: 1\nrandom: 0\nsingle: 0\n"),
+            (RepeatState::Track, "repeat: 1\nrandom: 0\nsingle: 1\n"),
+        ];
+        for (state, expected) in cases {
+            let status = render_status(build_playback(state), false);
+            assert!(
+                status.contains(expected),
+                "Expected {:?} in {:?}",
+                expected,
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn it_exposes_the_context_as_extension() {
+        let mut playback = fixtures::build_playback(None, None, false, Instant::now());
+        if let Some(data) = playback.data.as_mut() {
+            data.currently_playing.context = Some(Context {
+                context_type: ItemType::Playlist,
+                external_urls: HashMap::new(),
+                id: "playlist_id".to_string(),
+            });
+        }
+        let playback = Arc::new(playback);
+
+        let status = render_status(playback.clone(), true);
+        assert!(status.ends_with("spotify_context: playlist:playlist_id\n"));
+
+        let status = render_status(playback, false);
+        assert!(!status.contains("spotify_context"));
+    }
+
+    #[test]
+    fn it_exposes_the_saved_state_as_extension() {
+        let playback = Arc::new(fixtures::build_playback(None, None, false, Instant::now()));
+        let extensions = Some(extract_extensions(&playback, Some(true)));
+        let status = render_status_with(playback.clone(), extensions, None);
+        assert!(
+            status.ends_with("spotify_saved: 1\n"),
+            "Unexpected status {}",
+            status
+        );
+
+        let status = render_status(playback, true);
+        assert!(!status.contains("spotify_saved"));
+    }
+
+    #[test]
+    fn it_reports_the_last_error() {
+        let playback = Arc::new(fixtures::build_playback(None, None, false, Instant::now()));
+        let status = render_status_with(playback.clone(), None, Some("failed".to_string()));
+        assert!(
+            status.contains("\nerror: failed\n"),
+            "Unexpected status {}",
+            status
+        );
+
+        let status = render_status(playback, false);
+        assert!(!status.contains("error:"));
+    }
+
+    #[test]
+    fn it_reports_unknown_volumes_if_enabled() {
+        let playback = build_playback(RepeatState::Off);
+        let status = render_status_volume(playback.clone(), None, None, None);
+        assert!(
+            status.starts_with("repeat: 0\n"),
+            "Unexpected status {}",
+            status
+        );
+        let status = render_status_volume(playback, None, None, Some(-1));
+        assert!(
+            status.starts_with("volume: -1\n"),
+            "Unexpected status {}",
+            status
+        );
+
+        let stopped = Arc::new(CachedPlayback::new(None));
+        let status = render_status_volume(stopped.clone(), None, None, None);
+        assert!(
+            status.starts_with("repeat: 0\n"),
+            "Unexpected status {}",
+            status
+        );
+        let status = render_status_volume(stopped, None, None, Some(-1));
+        assert!(
+            status.starts_with("volume: -1\n"),
+            "Unexpected status {}",
+            status
+        );
+    }
+
+    #[test]
+    fn it_reports_known_volumes() {
+        let playback = fixtures::build_playback(None, None, false, Instant::now());
+        let status = render_status_volume(Arc::new(playback), None, None, Some(-1));
+        assert!(
+            status.starts_with("volume: 20\n"),
+            "Unexpected status {}",
+            status
+        );
+
+        let mut playback = fixtures::build_playback(None, None, false, Instant::now());
+        if let Some(data) = playback.data.as_mut() {
+            data.device.volume_percent = None;
+        }
+        let status = render_status_volume(Arc::new(playback), None, None, None);
+        assert!(!status.contains("volume"), "Unexpected status {}", status);
+    }
+
+    #[test]
+    fn it_reports_running_updates() {
+        let playback = Arc::new(fixtures::build_playback(None, None, false, Instant::now()));
+        let audio_info = StatusAudioInfo {
+            bitrate: 320,
+            audio: "44100:16:2".to_string(),
+        };
+        let queue = StatusQueue {
+            context: Arc::new(PlayContext::Empty),
+            version: 0,
+        };
+        let status = build_status_result(
+            playback,
+            queue,
+            audio_info,
+            None,
+            Some("failed".to_string()),
+            None,
+            Some(3),
+        );
+        match status {
+            Ok(HandlerOutput::Data(data)) => assert!(to_string(&data)
+                .expect("Serializer error")
+                .ends_with("\nupdating_db: 3\nerror: failed\n")),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[test]
+    fn it_renders_status_in_mpd_order() {
+        let track = fixtures::build_track(Some("second"), Duration::from_millis(180500));
+        let album = fixtures::build_album(
+            "album_id",
+            vec![
+                fixtures::build_track_simplified(Some("first"), Duration::from_secs(60)),
+                track.clone().simplify(),
+                fixtures::build_track_simplified(Some("third"), Duration::from_secs(60)),
+            ],
+        );
+        // Retrieved in the future for the elapsed time not to move during the test
+        let playback = fixtures::build_playback(
+            Some(PlayingType::Track(track)),
+            Some(Duration::from_millis(42250)),
+            true,
+            Instant::now() + Duration::from_secs(3600),
+        );
+        let audio_info = StatusAudioInfo {
+            bitrate: 320,
+            audio: "44100:16:2".to_string(),
+        };
+        let queue = StatusQueue {
+            context: Arc::new(PlayContext::Album(album)),
+            version: 7,
+        };
+        let status = build_status_result(
+            Arc::new(playback),
+            queue,
+            audio_info,
+            None,
+            None,
+            None,
+            None,
+        );
+        let rendered = match status {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        };
+        assert_eq!(
+            "volume: 20\n\
+             repeat: 0\n\
+             random: 0\n\
+             single: 0\n\
+             consume: 0\n\
+             playlist: 7\n\
+             playlistlength: 3\n\
+             state: play\n\
+             song: 1\n\
+             songid: 2\n\
+             time: 42:181\n\
+             elapsed: 42.25\n\
+             bitrate: 320\n\
+             duration: 180.5\n\
+             audio: 44100:16:2\n\
+             nextsong: 2\n\
+             nextsongid: 3\n",
+            rendered
+        );
+    }
+
+    #[test]
+    fn it_omits_positions_of_songs_outside_the_queue() {
+        let track = fixtures::build_track(Some("other"), Duration::from_secs(60));
+        let album = fixtures::build_album(
+            "album_id",
+            vec![fixtures::build_track_simplified(
+                Some("first"),
+                Duration::from_secs(60),
+            )],
+        );
+        let playback = fixtures::build_playback(
+            Some(PlayingType::Track(track)),
+            Some(Duration::from_secs(10)),
+            true,
+            Instant::now(),
+        );
+        let audio_info = StatusAudioInfo {
+            bitrate: 320,
+            audio: "44100:16:2".to_string(),
+        };
+        let queue = StatusQueue {
+            context: Arc::new(PlayContext::Album(album)),
+            version: 1,
+        };
+        let status = build_status_result(
+            Arc::new(playback),
+            queue,
+            audio_info,
+            None,
+            None,
+            None,
+            None,
+        );
+        let rendered = match status {
+            Ok(HandlerOutput::Data(data)) => to_string(&data).expect("Serializer error"),
+            other => panic!["Unexpected result {:?}", other],
+        };
+        assert!(rendered.contains("playlistlength: 1\n"), "{}", rendered);
+        for field in &["song:", "songid:", "nextsong:", "nextsongid:"] {
+            assert!(!rendered.contains(field), "{}", rendered);
+        }
+    }
+
+    #[test]
+    fn it_merges_status_and_song() {
+        let track = fixtures::build_track(Some("track_id"), Duration::from_secs(60));
+        let playback = Arc::new(fixtures::build_playback(
+            Some(PlayingType::Track(track)),
+            Some(Duration::from_secs(10)),
+            true,
+            Instant::now(),
+        ));
+        let context = Arc::new(PlayContext::Empty);
+        let audio_info = StatusAudioInfo {
+            bitrate: 320,
+            audio: "44100:16:2".to_string(),
+        };
+        let queue = StatusQueue {
+            context: context.clone(),
+            version: 0,
+        };
+        let status =
+            build_status_result(playback.clone(), queue, audio_info, None, None, None, None)
+                .unwrap();
+        let song = build_song_from_playing(playback.get_playing(), context).unwrap();
+
+        let rendered = match merge_outputs(status, song) {
+            HandlerOutput::Data(data) => data
+                .data
+                .iter()
+                .map(|item| to_string(item).expect("Serializer error"))
+                .collect::<String>(),
+            other => panic!["Unexpected result {:?}", other],
+        };
+        assert!(
+            rendered.starts_with("volume: 20\nrepeat: 0\n"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("\nfile: "), "{}", rendered);
+        assert!(rendered.contains("\nTitle: Title\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn it_merges_status_without_song() {
+        let status = HandlerOutput::from(StatusExtensions {
+            spotify_context: None,
+            spotify_saved: None,
+        });
+        match merge_outputs(status, HandlerOutput::Ok) {
+            HandlerOutput::Data(data) => assert_eq!(1, data.data.len()),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+}
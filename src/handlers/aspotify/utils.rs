@@ -1,6 +1,7 @@
-use crate::mpd_protocol::RelativeFloat;
-use aspotify::RepeatState;
-use std::time::Duration;
+use crate::mpd_protocol::{Command, HandlerError, RelativeFloat};
+use aspotify::{CurrentlyPlaying, Device, PlayingType, RepeatState};
+use log::debug;
+use std::time::{Duration, Instant};
 
 pub fn compute_seek(current: Option<Duration>, seek: RelativeFloat) -> Duration {
     match seek {
@@ -18,6 +19,17 @@ pub fn compute_seek(current: Option<Duration>, seek: RelativeFloat) -> Duration
     }
 }
 
+/// Converts a percentage of the current song to a position
+pub fn compute_seek_percent(
+    duration: Option<Duration>,
+    percent: f64,
+) -> Result<Duration, HandlerError> {
+    match duration {
+        None => Err(HandlerError::FromString("no song playing".into())),
+        Some(duration) => Ok(duration.mul_f64(percent / 100.)),
+    }
+}
+
 pub fn compute_repeat(
     current: RepeatState,
     repeat: Option<bool>,
@@ -34,12 +46,144 @@ pub fn compute_repeat(
     }
 }
 
+/// Returns true for commands controlling playback, that require a Premium account
+pub fn requires_premium(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::EnableOutput(_)
+            | Command::Random(_)
+            | Command::Repeat(_)
+            | Command::RepeatSingle(_)
+            | Command::Next
+            | Command::Pause(_)
+            | Command::PlayPos(_)
+            | Command::PlayId(_)
+            | Command::Previous
+            | Command::SeekId(_, _)
+            | Command::SeekPos(_, _)
+            | Command::SeekCur(_)
+            | Command::SeekPercent(_)
+            | Command::Stop
+            | Command::SetVolume(_)
+            | Command::ChangeVolume(_)
+    )
+}
+
+/// Returns true for commands changing the player or library state, that handler
+/// workers run one at a time for them to apply in order
+pub fn is_serialized(command: &Command) -> bool {
+    requires_premium(command)
+        || matches!(
+            command,
+            Command::SaveCurrent | Command::UnsaveCurrent | Command::SpotifyAuth(Some(_))
+        )
+}
+
+/// Seeking to a position starts playback, returns true if
+/// the player was paused and must be paused again afterwards
+pub fn needs_pause_after_seek(playing: Option<&CurrentlyPlaying>) -> bool {
+    playing.is_some_and(|p| !p.is_playing)
+}
+
+/// Returns true if the player is already paused, or already playing
+pub fn is_pause_state(playing: Option<&CurrentlyPlaying>, paused: bool) -> bool {
+    playing.is_some_and(|p| p.is_playing != paused)
+}
+
+/// Returns the known saved state of a track, unless it is older than max_age:
+/// tracks can be saved from other apps, their state must be looked up again
+pub fn known_saved_state(
+    known: Option<&(String, bool, Instant)>,
+    id: &str,
+    max_age: Duration,
+) -> Option<bool> {
+    match known {
+        Some((known_id, saved, checked)) if known_id == id && checked.elapsed() < max_age => {
+            Some(*saved)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the id of the current track, failing for ads, episodes and local tracks
+pub fn current_track_id(playing: Option<&CurrentlyPlaying>) -> Result<&str, HandlerError> {
+    match playing.and_then(|p| p.item.as_ref()) {
+        Some(PlayingType::Track(track)) => track
+            .id
+            .as_deref()
+            .ok_or_else(|| HandlerError::InvalidArgument("cannot save local tracks".into())),
+        Some(_) => Err(HandlerError::InvalidArgument(
+            "current item is not a track".into(),
+        )),
+        None => Err(HandlerError::InvalidArgument("no song playing".into())),
+    }
+}
+
+/// Applies a relative volume change, clamped to 0-100. Fails if no device
+/// reports a volume, as MPD does when no mixer is available
+pub fn compute_volume(current: Option<u32>, delta: i32) -> Result<i32, HandlerError> {
+    match current {
+        None => Err(HandlerError::NoMixer),
+        Some(current) => Ok(100.min(0.max(current as i32 + delta))),
+    }
+}
+
+/// Picks the configured default device, by id or name, to send volume changes to
+/// when no device is active. Returns its current volume and id.
+pub fn default_volume_target(
+    devices: &[Device],
+    default: Option<&str>,
+) -> Result<(Option<u32>, String), HandlerError> {
+    let wanted = default.ok_or(HandlerError::NoActiveDevice)?;
+    let device = devices
+        .iter()
+        .find(|d| d.id.as_deref() == Some(wanted))
+        .or_else(|| devices.iter().find(|d| d.name == wanted));
+    match device {
+        Some(Device {
+            id: Some(id),
+            volume_percent,
+            ..
+        }) => Ok((*volume_percent, id.clone())),
+        _ => {
+            debug!["Default device {} is not available", wanted];
+            Err(HandlerError::NoActiveDevice)
+        }
+    }
+}
+
+/// Checks a position is within the playing context, an empty context has no song to seek into
+pub fn check_position(pos: usize, size: usize) -> Result<(), HandlerError> {
+    match size {
+        0 => Err(HandlerError::NoSuchSong),
+        _ if pos >= size => Err(HandlerError::BadSongIndex),
+        _ => Ok(()),
+    }
+}
+
+/// Returns true for errors worth reporting to all clients in status,
+/// internal errors and commands passed to the next handler are ignored.
+/// Auth errors are only returned to the caller, as they hold the OAuth url.
+pub fn is_reported_error(err: &HandlerError) -> bool {
+    !matches!(
+        err,
+        HandlerError::Unsupported | HandlerError::GetError(_) | HandlerError::AuthNeeded(_)
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::handlers::aspotify::utils::{compute_repeat, compute_seek};
+    use crate::handlers::aspotify::fixtures::{build_device, build_playback, build_track};
+    use crate::handlers::aspotify::utils::{
+        check_position, compute_repeat, compute_seek, compute_seek_percent, compute_volume,
+        current_track_id, default_volume_target, is_pause_state, is_reported_error,
+        known_saved_state, needs_pause_after_seek, requires_premium,
+    };
     use crate::mpd_protocol::RelativeFloat::{Absolute, Relative};
+    use crate::mpd_protocol::{Command, HandlerError};
+    use aspotify::PlayingType;
     use aspotify::RepeatState::{Context, Off, Track};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn it_returns_absolute_time() {
@@ -93,4 +237,191 @@ mod tests {
             assert_eq!(expected, compute_repeat(current, repeat, single));
         }
     }
+
+    #[test]
+    fn it_enables_repeat_then_single() {
+        let state = compute_repeat(Off, Some(true), None);
+        assert_eq!(Context, state);
+        let state = compute_repeat(state, None, Some(true));
+        assert_eq!(Track, state);
+    }
+
+    #[test]
+    fn it_keeps_paused_state_after_seek() {
+        let track = || {
+            Some(PlayingType::Track(build_track(
+                None,
+                Duration::from_secs(60),
+            )))
+        };
+        let progress = Some(Duration::from_secs(10));
+
+        let paused = build_playback(track(), progress, false, Instant::now());
+        assert!(needs_pause_after_seek(paused.get_playing()));
+
+        let playing = build_playback(track(), progress, true, Instant::now());
+        assert!(!needs_pause_after_seek(playing.get_playing()));
+
+        let empty = build_playback(None, None, false, Instant::now());
+        assert!(needs_pause_after_seek(empty.get_playing()));
+        assert!(!needs_pause_after_seek(None));
+    }
+
+    #[test]
+    fn it_skips_pausing_a_paused_player() {
+        let track = || {
+            Some(PlayingType::Track(build_track(
+                None,
+                Duration::from_secs(60),
+            )))
+        };
+        let paused = build_playback(track(), None, false, Instant::now());
+        assert!(is_pause_state(paused.get_playing(), true));
+        assert!(!is_pause_state(paused.get_playing(), false));
+
+        let playing = build_playback(track(), None, true, Instant::now());
+        assert!(is_pause_state(playing.get_playing(), false));
+        assert!(!is_pause_state(playing.get_playing(), true));
+
+        // Without playback, let Spotify report the error
+        assert!(!is_pause_state(None, true));
+    }
+
+    #[test]
+    fn it_requires_premium_for_playback_control() {
+        assert!(requires_premium(&Command::Next));
+        assert!(requires_premium(&Command::Pause(None)));
+        assert!(requires_premium(&Command::SeekCur(Relative(5.))));
+        assert!(requires_premium(&Command::SetVolume(50)));
+        assert!(!requires_premium(&Command::Status));
+        assert!(!requires_premium(&Command::CurrentSong));
+        assert!(!requires_premium(&Command::PlaylistInfo(None)));
+    }
+
+    #[test]
+    fn it_computes_seek_percent() {
+        let duration = Some(Duration::from_secs(200));
+        assert_eq!(
+            Duration::from_secs(85),
+            compute_seek_percent(duration, 42.5).unwrap()
+        );
+        assert_eq!(
+            Duration::default(),
+            compute_seek_percent(duration, 0.).unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(200),
+            compute_seek_percent(duration, 100.).unwrap()
+        );
+        assert!(compute_seek_percent(None, 50.).is_err());
+    }
+
+    #[test]
+    fn it_finds_the_current_track_id() {
+        let track =
+            |id: Option<&str>| Some(PlayingType::Track(build_track(id, Duration::from_secs(60))));
+        let playback = build_playback(track(Some("track_id")), None, true, Instant::now());
+        assert_eq!(
+            "track_id",
+            current_track_id(playback.get_playing()).unwrap()
+        );
+
+        let local = build_playback(track(None), None, true, Instant::now());
+        assert!(current_track_id(local.get_playing()).is_err());
+
+        let ad = build_track(Some("ad_id"), Duration::from_secs(30));
+        let ad = build_playback(Some(PlayingType::Ad(ad)), None, true, Instant::now());
+        assert!(matches!(
+            current_track_id(ad.get_playing()),
+            Err(HandlerError::InvalidArgument(_))
+        ));
+
+        let empty = build_playback(None, None, false, Instant::now());
+        assert!(current_track_id(empty.get_playing()).is_err());
+        assert!(current_track_id(None).is_err());
+    }
+
+    #[test]
+    fn it_clamps_volume_changes() {
+        assert_eq!(30, compute_volume(Some(20), 10).unwrap());
+        assert_eq!(100, compute_volume(Some(95), 10).unwrap());
+        assert_eq!(0, compute_volume(Some(5), -10).unwrap());
+    }
+
+    #[test]
+    fn it_fails_volume_changes_without_device() {
+        assert!(matches!(
+            compute_volume(None, 10),
+            Err(HandlerError::NoMixer)
+        ));
+        assert!(matches!(
+            compute_volume(None, 0),
+            Err(HandlerError::NoMixer)
+        ));
+    }
+
+    #[test]
+    fn it_targets_the_default_device_without_active_one() {
+        let devices = vec![
+            build_device("speaker_id", "Speaker", Some(40)),
+            build_device("phone_id", "Phone", None),
+        ];
+        assert!(matches!(
+            default_volume_target(&devices, None),
+            Err(HandlerError::NoActiveDevice)
+        ));
+        assert_eq!(
+            (Some(40), "speaker_id".to_string()),
+            default_volume_target(&devices, Some("Speaker")).unwrap()
+        );
+        assert_eq!(
+            (None, "phone_id".to_string()),
+            default_volume_target(&devices, Some("phone_id")).unwrap()
+        );
+        assert!(matches!(
+            default_volume_target(&devices, Some("TV")),
+            Err(HandlerError::NoActiveDevice)
+        ));
+    }
+
+    #[test]
+    fn it_reports_user_facing_errors() {
+        assert!(is_reported_error(&HandlerError::PremiumRequired));
+        assert!(is_reported_error(&HandlerError::FromString(
+            "empty playlist".to_string()
+        )));
+        assert!(!is_reported_error(&HandlerError::Unsupported));
+        assert!(!is_reported_error(&HandlerError::AuthNeeded(
+            "https://accounts.spotify.com/authorize".to_string()
+        )));
+    }
+
+    #[test]
+    fn it_checks_positions_in_context() {
+        assert!(check_position(0, 3).is_ok());
+        assert!(check_position(2, 3).is_ok());
+        assert!(matches!(
+            check_position(3, 3),
+            Err(HandlerError::BadSongIndex)
+        ));
+        assert!(matches!(
+            check_position(0, 0),
+            Err(HandlerError::NoSuchSong)
+        ));
+    }
+
+    #[test]
+    fn it_expires_known_saved_states() {
+        let max_age = Duration::from_secs(15);
+        let known = ("track_id".to_string(), true, Instant::now());
+        assert_eq!(
+            Some(true),
+            known_saved_state(Some(&known), "track_id", max_age)
+        );
+        assert_eq!(None, known_saved_state(Some(&known), "other_id", max_age));
+        assert_eq!(None, known_saved_state(None, "track_id", max_age));
+
+        let old = ("track_id".to_string(), true, Instant::now() - max_age);
+        assert_eq!(None, known_saved_state(Some(&old), "track_id", max_age));
+    }
 }
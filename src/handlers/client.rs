@@ -1,21 +1,67 @@
 use crate::mpd_protocol::{Command, HandlerError, HandlerInput, HandlerResult};
+use crate::util::METRICS;
 use aspotify::{Client, ClientCredentials};
-use std::env::VarError;
 use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
 use tokio::sync::{mpsc, oneshot};
 
-pub fn build_aspotify_client() -> Result<Arc<Client>, VarError> {
-    ClientCredentials::from_env().map(Client::new).map(Arc::new)
+/// A Spotify credential was found neither in the settings nor in the environment
+#[derive(Error, Debug)]
+#[error("Missing Spotify {name}, set it with the {setting} or {env} environment variable")]
+pub struct MissingCredential {
+    name: &'static str,
+    setting: &'static str,
+    env: &'static str,
+}
+
+/// Builds a client with the given credentials, or the ones found in the environment
+pub fn build_aspotify_client(
+    credentials: Option<ClientCredentials>,
+) -> Result<Arc<Client>, MissingCredential> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => ClientCredentials {
+            id: env_credential("client id", "MPDIFY_SPOTIFY_CLIENT_ID", "CLIENT_ID")?,
+            secret: env_credential(
+                "client secret",
+                "MPDIFY_SPOTIFY_CLIENT_SECRET",
+                "CLIENT_SECRET",
+            )?,
+        },
+    };
+    Ok(Arc::new(Client::new(credentials)))
+}
+
+fn env_credential(
+    name: &'static str,
+    setting: &'static str,
+    env: &'static str,
+) -> Result<String, MissingCredential> {
+    std::env::var(env).map_err(|_| MissingCredential { name, setting, env })
 }
 
 #[derive(Default, Clone)]
 pub struct HandlerClient {
     handlers: Vec<mpsc::Sender<HandlerInput>>,
+    is_local: bool,
 }
 
 impl HandlerClient {
     pub fn new(handlers: Vec<mpsc::Sender<HandlerInput>>) -> Self {
-        HandlerClient { handlers }
+        HandlerClient {
+            handlers,
+            is_local: false,
+        }
+    }
+
+    /// Returns a client executing commands on behalf of the given peer. Like MPD, sensitive
+    /// commands are only allowed for local clients, handlers check it on each command.
+    pub fn for_peer(&self, is_local: bool) -> Self {
+        HandlerClient {
+            handlers: self.handlers.clone(),
+            is_local,
+        }
     }
 
     pub fn add(&mut self, handler: mpsc::Sender<HandlerInput>) {
@@ -23,16 +69,26 @@ impl HandlerClient {
     }
 
     /// Tries to executes a command by iterating over the registered handlers.
-    /// If a handler returns Unsupported, the next one is tried until no more are available.
+    /// If a handler returns Unsupported, the next one is tried until no more are available,
+    /// NoHandler is then returned.
+    ///
+    /// Commands are not queued when a handler's queue is full, Busy is returned instead:
+    /// clients get an error under load, rather than all connections silently waiting
+    /// on a slow backend. The queue size setting trades one for the other.
     pub async fn exec(&self, command: Command) -> HandlerResult {
+        METRICS.command(&command);
         for handler in self.handlers.iter() {
             let (tx, rx) = oneshot::channel();
-            handler
-                .send(HandlerInput {
-                    command: command.clone(),
-                    resp: tx,
-                })
-                .await?;
+            let input = HandlerInput {
+                command: command.clone(),
+                is_local: self.is_local,
+                resp: tx,
+            };
+            match handler.try_send(input) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return Err(HandlerError::Busy),
+                Err(TrySendError::Closed(input)) => return Err(SendError(input).into()),
+            }
 
             let result = rx.await.unwrap();
             match result {
@@ -43,6 +99,102 @@ impl HandlerClient {
             }
         }
         // All handlers returned Unsupported
-        Err(HandlerError::Unsupported)
+        Err(HandlerError::NoHandler((&command).into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd_protocol::HandlerOutput;
+    use futures::future::join_all;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn it_explains_missing_credentials() {
+        let err = env_credential(
+            "client id",
+            "MPDIFY_SPOTIFY_CLIENT_ID",
+            "MPDIFY_TEST_UNSET_CLIENT_ID",
+        )
+        .unwrap_err();
+        assert_eq!(
+            "Missing Spotify client id, set it with the MPDIFY_SPOTIFY_CLIENT_ID \
+             or MPDIFY_TEST_UNSET_CLIENT_ID environment variable",
+            err.to_string()
+        );
+    }
+
+    /// Answers all commands with the given error, counting calls
+    fn failing_handler(
+        err: fn() -> HandlerError,
+    ) -> (mpsc::Sender<HandlerInput>, Arc<AtomicUsize>) {
+        let (tx, mut rx) = mpsc::channel::<HandlerInput>(1);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        tokio::spawn(async move {
+            while let Some(input) = rx.recv().await {
+                counter.fetch_add(1, Relaxed);
+                input.resp.send(Err(err())).unwrap();
+            }
+        });
+        (tx, calls)
+    }
+
+    #[tokio::test]
+    async fn it_only_tries_next_handlers_for_unsupported_commands() {
+        let (unsupported, _) = failing_handler(|| HandlerError::Unsupported);
+        let (not_implemented, _) =
+            failing_handler(|| HandlerError::NotImplemented("stop".to_string()));
+        let (last, last_calls) = failing_handler(|| HandlerError::Unsupported);
+        let client = HandlerClient::new(vec![unsupported, not_implemented, last]);
+
+        let result = client.exec(Command::Stop).await;
+        assert!(matches!(result, Err(HandlerError::NotImplemented(_))));
+        assert_eq!(0, last_calls.load(Relaxed));
+
+        let result = HandlerClient::default().exec(Command::Stop).await;
+        assert!(matches!(result, Err(HandlerError::NoHandler("stop"))));
+
+        // Errors name the MPD keyword, not the parsed variant
+        let result = HandlerClient::default()
+            .exec(Command::SeekPercent(50.0))
+            .await;
+        assert!(matches!(
+            result,
+            Err(HandlerError::NoHandler("seekpercent"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_fails_fast_when_handlers_are_busy() {
+        let (tx, mut rx) = mpsc::channel::<HandlerInput>(1);
+        let (started_tx, mut started_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(input) = rx.recv().await {
+                let _ = started_tx.send(());
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let _ = input.resp.send(Ok(HandlerOutput::Ok));
+            }
+        });
+        let client = HandlerClient::new(vec![tx]);
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.exec(Command::Stop).await }
+        });
+        started_rx.recv().await;
+
+        // One command is running, one is queued, the others are refused right away
+        let started = Instant::now();
+        let results = join_all((0..8).map(|_| client.exec(Command::Stop))).await;
+        let busy = results
+            .iter()
+            .filter(|r| matches!(r, Err(HandlerError::Busy)))
+            .count();
+        assert_eq!(7, busy);
+        assert!(running.await.unwrap().is_ok());
+        assert!(started.elapsed() < Duration::from_secs(1));
     }
 }
@@ -1,3 +1,4 @@
 pub mod artwork;
 pub mod aspotify;
 pub mod client;
+pub mod workers;
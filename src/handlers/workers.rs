@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// Command queue shared by the workers of a handler, each worker takes
+/// the next command once it is done with its current one
+pub struct SharedReceiver<T> {
+    rx: Arc<Mutex<mpsc::Receiver<T>>>,
+}
+
+impl<T> SharedReceiver<T> {
+    pub fn new(rx: mpsc::Receiver<T>) -> Self {
+        SharedReceiver {
+            rx: Arc::new(Mutex::new(rx)),
+        }
+    }
+
+    /// Waits for the next command, None once all senders are dropped
+    pub async fn recv(&self) -> Option<T> {
+        self.rx.lock().await.recv().await
+    }
+}
+
+impl<T> Clone for SharedReceiver<T> {
+    fn clone(&self) -> Self {
+        SharedReceiver {
+            rx: self.rx.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::client::HandlerClient;
+    use crate::mpd_protocol::{Command, HandlerInput, HandlerOutput};
+    use futures::future::join_all;
+    use std::time::{Duration, Instant};
+
+    const DELAY: Duration = Duration::from_millis(200);
+
+    /// Runs workers answering each command after a delay
+    fn slow_workers(count: usize) -> HandlerClient {
+        let (tx, rx) = mpsc::channel::<HandlerInput>(16);
+        let rx = SharedReceiver::new(rx);
+        for _ in 0..count {
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                while let Some(input) = rx.recv().await {
+                    tokio::time::sleep(DELAY).await;
+                    let _ = input.resp.send(Ok(HandlerOutput::Ok));
+                }
+            });
+        }
+        HandlerClient::new(vec![tx])
+    }
+
+    async fn run_commands(client: HandlerClient, count: usize) -> Duration {
+        let started = Instant::now();
+        let results = join_all((0..count).map(|_| client.exec(Command::Status))).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn it_runs_commands_in_parallel() {
+        // A single worker runs commands one after the other
+        assert!(run_commands(slow_workers(1), 3).await >= DELAY * 3);
+
+        // Workers take commands while the others are busy
+        assert!(run_commands(slow_workers(3), 3).await < DELAY * 2);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod handlers;
 pub mod listeners;
 pub mod mpd_protocol;
+pub mod server;
 pub mod util;
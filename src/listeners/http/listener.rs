@@ -1,51 +1,273 @@
 use crate::handlers::client::HandlerClient;
 use crate::listeners::http::responses::*;
-use crate::mpd_protocol::{Command, HandlerError, HandlerOutput};
-use crate::util::Settings;
+use crate::mpd_protocol::{Command, HandlerOutput, IdleSubsystem, InputError};
+use crate::server::ServerError;
+use crate::util::{ConnectionTracker, IdleBus, Settings, METRICS};
+use futures::future::join_all;
+use hyper::body::HttpBody;
+use hyper::header::AUTHORIZATION;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Server};
+use hyper::{Body, Method, Request, Server};
 use log::debug;
+use percent_encoding::percent_decode_str;
+use serde_json::json;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::Split;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
 
 #[derive(Clone)]
 struct State {
     handler: Arc<HandlerClient>,
+    idle_bus: Arc<IdleBus>,
     auth_path: Arc<str>,
+    connections: Arc<ConnectionTracker>,
+    authenticated: Option<Arc<AtomicBool>>,
+    max_body_bytes: u64,
+    request_timeout: Duration,
+    keep_warm: Duration,
+    credentials: Option<Credentials>,
+    static_dir: Option<Arc<Path>>,
+}
+
+/// Expected basic auth credentials, any username is accepted if None
+#[derive(Clone)]
+struct Credentials {
+    username: Option<Arc<str>>,
+    password: Arc<str>,
+}
+
+impl Credentials {
+    fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.http_password.as_ref().map(|password| Credentials {
+            username: settings.http_username.as_deref().map(Arc::from),
+            password: password.as_str().into(),
+        })
+    }
+
+    /// Checks the value of an Authorization header
+    fn accepts(&self, header: Option<&str>) -> bool {
+        let decoded = header
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match decoded.as_ref().and_then(|d| d.split_once(':')) {
+            Some((username, password)) => {
+                // Both are checked, for the time taken not to tell which one is wrong
+                let password_ok = secure_eq(password, &self.password);
+                let username_ok = self
+                    .username
+                    .as_ref()
+                    .is_none_or(|expected| secure_eq(username, expected));
+                password_ok && username_ok
+            }
+            None => false,
+        }
+    }
+}
+
+/// Compares secrets in constant time, only their length can be told apart
+fn secure_eq(value: &str, expected: &str) -> bool {
+    let differences = value
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    value.len() == expected.len() && differences == 0
+}
+
+impl State {
+    /// Returns the state for a connection, sensitive commands being refused to remote peers
+    fn for_peer(&self, peer: SocketAddr) -> State {
+        State {
+            handler: Arc::new(self.handler.for_peer(peer.ip().is_loopback())),
+            ..self.clone()
+        }
+    }
+
+    /// Executes a command, giving up after the configured timeout
+    async fn exec(&self, command: Command) -> std::result::Result<HandlerOutput, GenericError> {
+        match timeout(self.request_timeout, self.handler.exec(command)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(RequestTimeout.into()),
+        }
+    }
 }
 
 pub struct HttpListener {
-    address: SocketAddr,
+    incoming: Vec<AddrIncoming>,
+    addresses: Vec<SocketAddr>,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::TlsAcceptor>,
     state: State,
 }
 
+/// HTTPS support, the only part of the listener linking to OpenSSL
+#[cfg(feature = "tls")]
+mod tls {
+    use super::*;
+    use futures::future::poll_fn;
+    use hyper::server::accept::Accept;
+    use hyper::server::conn::Http;
+    use log::warn;
+    use native_tls::Identity;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::stack::Stack;
+    use openssl::x509::X509;
+    use std::fs;
+    use std::pin::Pin;
+    pub use tokio_native_tls::TlsAcceptor;
+
+    /// Loads the certificate chain and private key to serve HTTPS with. Both are PEM files:
+    /// the certificate file holds the server certificate followed by any intermediate one,
+    /// the key file holds its private key, in PKCS#8 or traditional RSA / EC format.
+    pub fn load_acceptor(
+        cert_path: &str,
+        key_path: &str,
+    ) -> std::result::Result<TlsAcceptor, GenericError> {
+        let mut chain = X509::stack_from_pem(&fs::read(cert_path)?)?.into_iter();
+        let cert = chain.next().ok_or("no certificate found")?;
+        let key = PKey::private_key_from_pem(&fs::read(key_path)?)?;
+        let mut intermediates = Stack::new()?;
+        for ca in chain {
+            intermediates.push(ca)?;
+        }
+
+        // native-tls only imports identities from PKCS#12 archives
+        let archive = Pkcs12::builder()
+            .name("mpdify")
+            .pkey(&key)
+            .cert(&cert)
+            .ca(intermediates)
+            .build2("")?;
+        let identity = Identity::from_pkcs12(&archive.to_der()?, "")?;
+        Ok(native_tls::TlsAcceptor::new(identity)?.into())
+    }
+
+    /// Accepts connections until the listener closes, each one being served
+    /// in its own task once its TLS handshake completes
+    pub async fn serve(mut incoming: AddrIncoming, acceptor: TlsAcceptor, state: State) {
+        while let Some(accepted) = poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await {
+            let stream = match accepted {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!["Cannot accept HTTPS connection: {}", err];
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let s = state.for_peer(stream.remote_addr());
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => return debug!["TLS handshake failed: {}", err],
+                };
+                let service = service_fn(move |req| handle_request(req, s.clone()));
+                if let Err(err) = Http::new().serve_connection(stream, service).await {
+                    debug!["Error serving HTTPS connection: {}", err];
+                }
+            });
+        }
+    }
+}
+
 impl HttpListener {
-    pub fn new(settings: &Settings, handler: HandlerClient) -> Self {
-        Self {
-            address: settings.http_address(),
+    /// Binds the listening sockets right away, for get_address to return the bound port
+    pub fn new(
+        settings: &Settings,
+        handler: HandlerClient,
+        idle_bus: Arc<IdleBus>,
+        connections: Arc<ConnectionTracker>,
+        authenticated: Arc<AtomicBool>,
+    ) -> std::result::Result<Self, ServerError> {
+        let mut incoming = vec![];
+        let mut addresses = vec![];
+        for address in settings.http_addresses() {
+            match AddrIncoming::bind(&address) {
+                Ok(bound) => {
+                    addresses.push(bound.local_addr());
+                    incoming.push(bound);
+                }
+                Err(err) => {
+                    let err = std::io::Error::other(err);
+                    return Err(ServerError::Bind("HTTP", address.to_string(), err));
+                }
+            }
+        }
+        #[cfg(feature = "tls")]
+        let tls = match settings.http_tls() {
+            Some((cert, key)) => Some(
+                tls::load_acceptor(cert, key)
+                    .map_err(|err| ServerError::Tls(cert.to_string(), err))?,
+            ),
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if let Some((cert, _)) = settings.http_tls() {
+            let err = "built without the tls feature".into();
+            return Err(ServerError::Tls(cert.to_string(), err));
+        }
+        Ok(Self {
+            incoming,
+            addresses,
+            #[cfg(feature = "tls")]
+            tls,
             state: State {
                 handler: Arc::new(handler),
+                idle_bus,
                 auth_path: settings.auth_path().into(),
+                connections,
+                authenticated: match settings.http_healthz_requires_auth {
+                    true => Some(authenticated),
+                    false => None,
+                },
+                max_body_bytes: settings.http_max_body_bytes,
+                request_timeout: settings.http_request_timeout(),
+                keep_warm: settings.http_keep_warm(),
+                credentials: Credentials::from_settings(settings),
+                static_dir: settings.http_static_dir().map(Arc::from),
             },
-        }
+        })
     }
 
+    /// Runs one server per bound address
     pub async fn run(&mut self) {
-        let s = self.state.clone();
-        let new_service = make_service_fn(move |_| {
-            let s = s.clone();
-            async { Ok::<_, GenericError>(service_fn(move |req| handle_request(req, s.clone()))) }
-        });
+        let mut servers = vec![];
+        for incoming in self.incoming.drain(..) {
+            #[cfg(feature = "tls")]
+            if let Some(acceptor) = &self.tls {
+                debug!["Listening on https://{}", incoming.local_addr()];
+                let serve = tls::serve(incoming, acceptor.clone(), self.state.clone());
+                servers.push(tokio::spawn(serve));
+                continue;
+            }
 
-        let server = Server::bind(&self.address).serve(new_service);
-        self.address = server.local_addr();
-        debug!["Listening on http://{}", &self.address];
-        server.await.unwrap();
+            let s = self.state.clone();
+            let new_service = make_service_fn(move |conn: &AddrStream| {
+                let s = s.for_peer(conn.remote_addr());
+                async {
+                    Ok::<_, GenericError>(service_fn(move |req| handle_request(req, s.clone())))
+                }
+            });
+
+            debug!["Listening on http://{}", incoming.local_addr()];
+            let server = Server::builder(incoming).serve(new_service);
+            servers.push(tokio::spawn(async move { server.await.unwrap() }));
+        }
+        join_all(servers).await;
     }
 
+    /// Returns the first bound address
     pub fn get_address(&self) -> String {
-        self.address.to_string()
+        self.addresses
+            .first()
+            .map(|a| a.to_string())
+            .unwrap_or_default()
     }
 }
 
@@ -53,38 +275,262 @@ async fn handle_request(req: Request<Body>, state: State) -> Result {
     if !req.uri().path().starts_with('/') {
         return not_found();
     }
+    let (parts, body) = req.into_parts();
+    let req = match read_body(body, state.max_body_bytes).await? {
+        Some(body) => Request::from_parts(parts, body.into()),
+        None => return payload_too_large(),
+    };
     let mut path_parts = req.uri().path()[1..].split('/');
+    let route = path_parts.next();
+
+    // Health checks and the oauth flow stay reachable without credentials
+    if let Some(credentials) = &state.credentials {
+        let header = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok());
+        if !matches!(route, Some("healthz") | Some("auth")) && !credentials.accepts(header) {
+            return unauthorized();
+        }
+    }
 
-    match match path_parts.next() {
-        Some("command") => handle_command(state, path_parts).await,
+    match match route {
+        Some("command") => handle_command(state, path_parts, req.uri().query()).await,
+        Some("parse") => handle_parse(path_parts),
+        Some("art") => handle_art(&state, path_parts, req.uri().query()).await,
+        Some("idle") => handle_idle(&state, req.uri().query()).await,
+        Some("keepalive") => handle_keepalive(req.method(), &state),
+        Some("outputs") => handle_outputs(req.method(), state, path_parts).await,
         Some("auth") => handle_auth(req, state).await,
-        _ => not_found(),
+        Some("metrics") => ok_metrics(METRICS.render(&state.connections)),
+        Some("healthz") => handle_healthz(state),
+        _ => handle_static(req.method(), &state, req.uri().path()).await,
     } {
         Ok(result) => Ok(result),
         Err(err) => handle_error(err),
     }
 }
 
-async fn handle_command(state: State, input: Split<'_, char>) -> Result {
-    let tokens = input.map(|s| s.to_string()).collect();
+/// Executes a command, HTTP clients don't subscribe to idle updates but are likely to
+/// poll again, so the playback state keeps being refreshed for the next commands.
+async fn handle_command(state: State, input: Split<'_, char>, query: Option<&str>) -> Result {
+    let mut tokens: Vec<String> = input.map(|s| s.to_string()).collect();
+    match tokens.as_slice() {
+        [name] if name == "playlistinfo" => tokens.extend(range_from_query(query)?),
+        // Artwork uris hold slashes too, the offset being the last segment
+        [name, uri @ .., offset] if name == "albumart" && !uri.is_empty() => {
+            let tokens = vec![name.clone(), uri.join("/"), offset.clone()];
+            return exec_art(&state, Command::from_tokens(tokens)?).await;
+        }
+        _ => {}
+    }
     let command = Command::from_tokens(tokens)?;
-    match state.handler.exec(command).await? {
-        HandlerOutput::Data(data) => ok_json(&data),
+    state.idle_bus.keep_warm(state.keep_warm);
+    match state.exec(command).await? {
+        HandlerOutput::Data(data) => ok_json_list(data),
+        HandlerOutput::Lines(lines) => ok_json(&lines),
+        _ => ok_empty(),
+    }
+}
+
+/// Serves artwork for the path following /art/, as the albumart command would.
+/// The offset query parameter allows reading the next chunks.
+async fn handle_art(state: &State, input: Split<'_, char>, query: Option<&str>) -> Result {
+    let path = input.collect::<Vec<&str>>().join("/");
+    let offset = query_param(query, "offset").unwrap_or("0");
+    let tokens = vec!["albumart".to_string(), path, offset.to_string()];
+    exec_art(state, Command::from_tokens(tokens)?).await
+}
+
+/// Executes an albumart command. Chunks past the first one are typed from the start
+/// of the image, read again from the artwork handler that keeps it cached.
+async fn exec_art(state: &State, command: Command) -> Result {
+    let head_command = match &command {
+        Command::AlbumArt(uri, offset) if *offset > 0 => Some(Command::AlbumArt(uri.clone(), 0)),
+        _ => None,
+    };
+    let (size, data) = match state.exec(command).await? {
+        HandlerOutput::Binary(size, data) => (size, data),
+        _ => return not_found(),
+    };
+    let content_type = match head_command {
+        Some(head_command) => match state.exec(head_command).await? {
+            HandlerOutput::Binary(_, head) => art_content_type(&head),
+            _ => art_content_type(&[]),
+        },
+        None => art_content_type(&data),
+    };
+    ok_binary(size, content_type, data)
+}
+
+/// Lists Spotify Connect devices, or transfers playback to one of them.
+/// Output ids are the ones returned by the outputs MPD command.
+async fn handle_outputs(method: &Method, state: State, mut input: Split<'_, char>) -> Result {
+    let command = match (method, input.next(), input.next(), input.next()) {
+        (&Method::GET, None, _, _) | (&Method::GET, Some(""), None, _) => Command::Outputs,
+        (&Method::POST, Some(id), Some("enable"), None) => {
+            Command::from_tokens(vec!["enableoutput".to_string(), id.to_string()])?
+        }
+        _ => return not_found(),
+    };
+    match state.exec(command).await? {
+        HandlerOutput::Data(data) => ok_json_list(data),
         _ => ok_empty(),
     }
 }
 
+/// Long-polls for changes after the `since` cursor, or after the latest change if absent.
+/// Returns the changed subsystems with the cursor to pass to the next poll, so that no
+/// change is lost between polls. Returns no change if none happens before the timeout.
+/// The subscription is owned by this future: hyper drops it when the client disconnects,
+/// so that idle clients gone away do not keep the playback watcher running.
+async fn handle_idle(state: &State, query: Option<&str>) -> Result {
+    // Subscribe before reading the history, for changes made meanwhile to wake us up
+    let mut messages = state.idle_bus.subscribe();
+    let since = match query_param(query, "since") {
+        Some(value) => value
+            .parse()
+            .map_err(|_| InputError::InvalidArgument("since", value.to_string()))?,
+        None => state.idle_bus.last_seq(),
+    };
+    let (mut cursor, mut changed) = state.idle_bus.changes_since(since);
+    if changed.is_empty() {
+        let next_change = async {
+            loop {
+                match messages.recv().await {
+                    Ok(message) if message.seq <= since => continue,
+                    Err(RecvError::Closed) => return false,
+                    _ => return true,
+                }
+            }
+        };
+        if let Ok(true) = timeout(state.request_timeout, next_change).await {
+            let changes = state.idle_bus.changes_since(since);
+            cursor = changes.0;
+            changed = changes.1;
+        }
+    }
+    let changed: Vec<IdleSubsystem> = changed.iter().collect();
+    ok_json(&json!({ "cursor": cursor, "changed": changed }))
+}
+
+/// Keeps the playback state refreshed for a grace period, for clients polling
+/// the state without holding an idle connection
+fn handle_keepalive(method: &Method, state: &State) -> Result {
+    if method != Method::POST {
+        return not_found();
+    }
+    state.idle_bus.keep_warm(state.keep_warm);
+    ok_empty()
+}
+
+/// Builds a range argument from the start and end query parameters, for clients to
+/// page through the queue without the MPD range syntax. Missing bounds are open.
+fn range_from_query(query: Option<&str>) -> std::result::Result<Option<String>, InputError> {
+    let bound = |name: &'static str| match query_param(query, name) {
+        Some(value) => value
+            .parse::<usize>()
+            .map(Some)
+            .map_err(|_| InputError::InvalidArgument(name, value.to_string())),
+        None => Ok(None),
+    };
+    match (bound("start")?, bound("end")?) {
+        (None, None) => Ok(None),
+        (start, end) => Ok(Some(format![
+            "{}:{}",
+            start.unwrap_or_default(),
+            end.unwrap_or(usize::MAX)
+        ])),
+    }
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        match key == name {
+            true => Some(value),
+            false => None,
+        }
+    })
+}
+
+/// Serves files from the static directory, if set. Paths without an extension that don't
+/// match a file get index.html instead, for single page apps to handle their own routes.
+async fn handle_static(method: &Method, state: &State, path: &str) -> Result {
+    let root = match (method, &state.static_dir) {
+        (&Method::GET, Some(root)) => root,
+        _ => return not_found(),
+    };
+    let file = match resolve_static(root, path) {
+        Some(file) => file,
+        None => return not_found(),
+    };
+    let file = match file.is_dir() {
+        true => file.join("index.html"),
+        false => file,
+    };
+    if let Ok(data) = tokio::fs::read(&file).await {
+        return ok_file(&file, data);
+    }
+    match file.extension() {
+        None => {
+            let index = root.join("index.html");
+            match tokio::fs::read(&index).await {
+                Ok(data) => ok_file(&index, data),
+                Err(_) => not_found(),
+            }
+        }
+        Some(_) => not_found(),
+    }
+}
+
+/// Maps a request path to a location inside root, None if it would escape it
+fn resolve_static(root: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut resolved = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ if segment.contains('\\') || segment.contains('\0') => return None,
+            _ => resolved.push(segment),
+        }
+    }
+
+    // Symbolic links must not lead out of the directory either
+    if let Ok(canonical) = resolved.canonicalize() {
+        if !canonical.starts_with(root.canonicalize().ok()?) {
+            return None;
+        }
+    }
+    Some(resolved)
+}
+
+/// Shows how a command is parsed, without executing it
+fn handle_parse(input: Split<'_, char>) -> Result {
+    let tokens = input.map(|s| s.to_string()).collect();
+    let command = Command::from_tokens(tokens)?;
+    ok_json(&json!({ "command": format!["{:?}", command] }))
+}
+
+/// Reports readiness from the cached auth state, without calling the Spotify API
+fn handle_healthz(state: State) -> Result {
+    match state.authenticated {
+        Some(flag) if !flag.load(Ordering::Acquire) => unavailable("Waiting for Spotify auth"),
+        _ => ok_text("OK"),
+    }
+}
+
 async fn handle_auth(req: Request<Body>, state: State) -> Result {
     match req.uri().query() {
         None => {
-            let response = state.handler.exec(Command::SpotifyAuth(None)).await;
             // Redirect user if we need to authenticate
-            if let Err(HandlerError::AuthNeeded(destination)) = &response {
-                return auth_redirect(destination);
-            };
-            // Bubble up unexpected errors
-            response?;
-            // Otherwise, return an empty 204
+            if let HandlerOutput::Data(data) = state.exec(Command::SpotifyAuth(None)).await? {
+                let status = serde_json::to_value(&data)?;
+                if let Some(destination) = status.get("auth_url").and_then(|u| u.as_str()) {
+                    return auth_redirect(destination);
+                }
+            }
             auth_ok()
         }
 
@@ -96,8 +542,449 @@ async fn handle_auth(req: Request<Body>, state: State) -> Result {
                 req.uri().query().unwrap_or_default()
             ];
             debug!["{}", url];
-            state.handler.exec(Command::SpotifyAuth(Some(url))).await?;
+            state.exec(Command::SpotifyAuth(Some(url))).await?;
             auth_ok()
         }
     }
 }
+
+/// Reads the body, None if larger than max_body_bytes as we never need large bodies.
+/// Chunked bodies don't announce their size, so reading stops once past the limit.
+async fn read_body(
+    mut body: Body,
+    max_body_bytes: u64,
+) -> std::result::Result<Option<Vec<u8>>, hyper::Error> {
+    if body.size_hint().lower() > max_body_bytes {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (data.len() + chunk.len()) as u64 > max_body_bytes {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listeners::mpd::BasicCommandHandler;
+    use crate::mpd_protocol::{
+        HandlerError, HandlerInput, OutputData, OutputsResponse, Path, PositionRange,
+    };
+    use hyper::header::CONTENT_TYPE;
+    use tokio::sync::mpsc;
+
+    /// Builds a state answering outputs commands, and forwarding received commands
+    fn outputs_state() -> (State, mpsc::UnboundedReceiver<Command>) {
+        let (tx, mut rx) = mpsc::channel::<HandlerInput>(1);
+        let (seen_tx, seen_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(input) = rx.recv().await {
+                let resp = match input.command {
+                    Command::Outputs => Ok(HandlerOutput::from(OutputsResponse {
+                        outputid: 0,
+                        outputname: "Device".to_string(),
+                        outputenabled: true,
+                        plugin: "spotify".to_string(),
+                    })),
+                    Command::EnableOutput(_) => Ok(HandlerOutput::Ok),
+                    _ => Err(HandlerError::Unsupported),
+                };
+                seen_tx.send(input.command).unwrap();
+                input.resp.send(resp).unwrap();
+            }
+        });
+        (test_state(tx), seen_rx)
+    }
+
+    /// Builds a state answering playlistinfo with the given number of songs
+    fn playlist_state(size: usize) -> State {
+        let (tx, mut rx) = mpsc::channel::<HandlerInput>(1);
+        tokio::spawn(async move {
+            while let Some(input) = rx.recv().await {
+                let range = match input.command {
+                    Command::PlaylistInfo(Some(range)) => range,
+                    _ => PositionRange {
+                        start: 0,
+                        end: size,
+                    },
+                };
+                let mut out = OutputData::empty();
+                for pos in (0..size).filter(|pos| range.contains(*pos)) {
+                    out.push(json!({ "Pos": pos, "Title": format!["Song {}", pos] }));
+                }
+                input.resp.send(Ok(HandlerOutput::Data(out))).unwrap();
+            }
+        });
+        test_state(tx)
+    }
+
+    const ART_URI: &str = "internal/album/4IOXEu8EgItKI8J9JDaEr4";
+
+    /// Builds a state serving 4 bytes chunks of a fake png for albumart commands on ART_URI
+    fn art_state() -> State {
+        let (tx, mut rx) = mpsc::channel::<HandlerInput>(1);
+        tokio::spawn(async move {
+            let art = b"\x89PNG0123456789";
+            let art_uri: Path = ART_URI.parse().unwrap();
+            while let Some(input) = rx.recv().await {
+                let resp = match input.command {
+                    Command::AlbumArt(uri, _) if uri != art_uri => {
+                        Err(HandlerError::InvalidArgument(format!["{:?}", uri]))
+                    }
+                    Command::AlbumArt(_, offset) => {
+                        let start = art.len().min(offset as usize);
+                        let end = art.len().min(start + 4);
+                        Ok(HandlerOutput::Binary(
+                            art.len() as u64,
+                            art[start..end].to_vec(),
+                        ))
+                    }
+                    _ => Err(HandlerError::Unsupported),
+                };
+                input.resp.send(resp).unwrap();
+            }
+        });
+        test_state(tx)
+    }
+
+    fn test_state(tx: mpsc::Sender<HandlerInput>) -> State {
+        State {
+            handler: Arc::new(HandlerClient::new(vec![tx])),
+            idle_bus: IdleBus::new(),
+            auth_path: "".into(),
+            connections: ConnectionTracker::new(None),
+            authenticated: None,
+            max_body_bytes: 0,
+            request_timeout: Duration::from_secs(1),
+            keep_warm: Duration::from_secs(60),
+            credentials: None,
+            static_dir: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_rejects_oversized_bodies() {
+        let read = |size: usize| read_body(Body::from(vec![0; size]), 16);
+        assert_eq!(Some(vec![]), read(0).await.unwrap());
+        assert_eq!(Some(vec![0; 16]), read(16).await.unwrap());
+        assert_eq!(None, read(17).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_oversized_chunked_bodies() {
+        let (mut sender, body) = Body::channel();
+        assert_eq!(0, body.size_hint().lower());
+        let reading = tokio::spawn(read_body(body, 16));
+        sender.send_data(vec![0; 10].into()).await.unwrap();
+        sender.send_data(vec![0; 10].into()).await.unwrap();
+
+        // Reading stops at the chunk going past the limit, without waiting for the end
+        assert_eq!(None, reading.await.unwrap().unwrap());
+    }
+
+    #[test]
+    fn it_checks_basic_auth_credentials() {
+        let encode = |value: &str| format!["Basic {}", base64::encode(value)];
+        let credentials = Credentials {
+            username: Some("user".into()),
+            password: "secret".into(),
+        };
+        assert!(credentials.accepts(Some(&encode("user:secret"))));
+        assert!(!credentials.accepts(Some(&encode("user:wrong"))));
+        assert!(!credentials.accepts(Some(&encode("other:secret"))));
+        assert!(!credentials.accepts(Some("Bearer token")));
+        assert!(!credentials.accepts(None));
+
+        let any_user = Credentials {
+            username: None,
+            password: "secret".into(),
+        };
+        assert!(any_user.accepts(Some(&encode("other:secret"))));
+        assert!(!any_user.accepts(Some(&encode("other:secre"))));
+        assert!(!any_user.accepts(Some(&encode("other:secrets"))));
+    }
+
+    #[tokio::test]
+    async fn it_parses_commands_without_executing_them() {
+        let response = handle_parse("seekpos/2/10.5".split('/')).unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!({ "command": "SeekPos(2, 10.5)" }), parsed);
+
+        let err = handle_parse("seekpos/two".split('/')).unwrap_err();
+        assert!(err.is::<InputError>());
+    }
+
+    #[tokio::test]
+    async fn it_manages_outputs() {
+        let (state, mut seen) = outputs_state();
+        let response = handle_outputs(&Method::GET, state.clone(), "".split('/'))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!("Device"), listed[0]["outputname"]);
+        assert!(matches!(seen.recv().await, Some(Command::Outputs)));
+
+        let response = handle_outputs(&Method::POST, state.clone(), "1/enable".split('/'))
+            .await
+            .unwrap();
+        assert_eq!(hyper::StatusCode::NO_CONTENT, response.status());
+        assert!(matches!(seen.recv().await, Some(Command::EnableOutput(1))));
+
+        let response = handle_outputs(&Method::GET, state.clone(), "1/enable".split('/'))
+            .await
+            .unwrap();
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+        let err = handle_outputs(&Method::POST, state, "one/enable".split('/'))
+            .await
+            .unwrap_err();
+        assert!(err.is::<InputError>());
+    }
+
+    #[tokio::test]
+    async fn it_streams_large_lists() {
+        for size in &[0, 1, 2, 500] {
+            let response = handle_command(playlist_state(*size), "playlistinfo".split('/'), None)
+                .await
+                .unwrap();
+            assert_eq!(hyper::StatusCode::OK, response.status());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let items = listed.as_array().expect("Expected a list");
+            assert_eq!(*size, items.len());
+            for (pos, item) in items.iter().enumerate() {
+                assert_eq!(json!(pos), item["Pos"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_pages_through_the_queue() {
+        let cases = vec![
+            ("start=0&end=10", 0..10),
+            ("start=490", 490..500),
+            ("end=2", 0..2),
+            ("other=1", 0..500),
+        ];
+        for (query, expected) in cases {
+            let input = "playlistinfo".split('/');
+            let response = handle_command(playlist_state(500), input, Some(query))
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let positions: Vec<usize> = listed
+                .as_array()
+                .expect("Expected a list")
+                .iter()
+                .map(|item| item["Pos"].as_u64().unwrap() as usize)
+                .collect();
+            assert_eq!(expected.collect::<Vec<usize>>(), positions, "{}", query);
+        }
+
+        for query in &["start=a", "start=10&end=5"] {
+            let input = "playlistinfo".split('/');
+            let err = handle_command(playlist_state(500), input, Some(query))
+                .await
+                .unwrap_err();
+            assert!(err.is::<InputError>(), "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_does_not_unpack_single_items() {
+        let response = handle_command(playlist_state(1), "playlistinfo".split('/'), None)
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!([{ "Pos": 0, "Title": "Song 0" }]), listed);
+    }
+
+    #[tokio::test]
+    async fn it_serves_artwork() {
+        let state = art_state();
+        let path = ART_URI;
+        let response = handle_art(&state, path.split('/'), None).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+        assert_eq!("14", response.headers()["X-Art-Size"]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(b"\x89PNG", body.as_ref());
+
+        let response = handle_art(&state, path.split('/'), Some("offset=12"))
+            .await
+            .unwrap();
+        assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(b"89", body.as_ref());
+
+        let err = handle_art(&state, path.split('/'), Some("offset=-1"))
+            .await
+            .unwrap_err();
+        assert!(err.is::<InputError>());
+    }
+
+    #[tokio::test]
+    async fn it_serves_artwork_through_the_albumart_command() {
+        let state = art_state();
+        for (offset, expected) in [("0", b"\x89PNG"), ("4", b"0123")].iter() {
+            let input = format!["albumart/{}/{}", ART_URI, offset];
+            let response = handle_command(state.clone(), input.split('/'), None)
+                .await
+                .unwrap();
+            assert_eq!(hyper::StatusCode::OK, response.status());
+            assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+            assert_eq!("14", response.headers()["X-Art-Size"]);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(expected, &body.as_ref());
+        }
+    }
+
+    async fn poll_idle(state: &State, query: Option<&str>) -> serde_json::Value {
+        let response = handle_idle(state, query).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_keeps_idle_changes_between_polls() {
+        let (tx, _) = mpsc::channel::<HandlerInput>(1);
+        let mut state = test_state(tx);
+        state.request_timeout = Duration::from_millis(50);
+
+        // No change before the timeout
+        let polled = poll_idle(&state, None).await;
+        assert_eq!(json!({ "cursor": 0, "changed": [] }), polled);
+
+        // Change happening between two polls is returned right away
+        state.idle_bus.notify(IdleSubsystem::Player);
+        let polled = poll_idle(&state, Some("since=0")).await;
+        assert_eq!(json!({ "cursor": 1, "changed": ["player"] }), polled);
+
+        // Change happening while polling wakes the client up
+        let bus = state.idle_bus.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            bus.notify(IdleSubsystem::Mixer);
+        });
+        state.request_timeout = Duration::from_secs(1);
+        let polled = poll_idle(&state, Some("since=1")).await;
+        assert_eq!(json!({ "cursor": 2, "changed": ["mixer"] }), polled);
+
+        let err = handle_idle(&state, Some("since=last")).await.unwrap_err();
+        assert!(err.is::<InputError>());
+    }
+
+    #[tokio::test]
+    async fn it_unsubscribes_when_idle_polls_are_dropped() {
+        let (tx, _) = mpsc::channel::<HandlerInput>(1);
+        let mut state = test_state(tx);
+        state.request_timeout = Duration::from_secs(60);
+        let _watcher = state.idle_bus.subscribe();
+
+        let poll_state = state.clone();
+        let poll = tokio::spawn(async move { handle_idle(&poll_state, None).await });
+        while state.idle_bus.subscriber_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        poll.abort();
+        let _ = poll.await;
+        assert_eq!(1, state.idle_bus.subscriber_count());
+    }
+
+    #[test]
+    fn it_keeps_the_playback_warm_on_keepalive() {
+        let (tx, _) = mpsc::channel::<HandlerInput>(1);
+        let mut state = test_state(tx);
+        state.keep_warm = Duration::from_millis(50);
+
+        let response = handle_keepalive(&Method::GET, &state).unwrap();
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+        assert!(!state.idle_bus.has_subscribers());
+
+        // The playback watcher keeps polling during the grace period only
+        handle_keepalive(&Method::POST, &state).unwrap();
+        assert!(state.idle_bus.has_subscribers());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!state.idle_bus.has_subscribers());
+    }
+
+    async fn get_static(state: &State, path: &str) -> (hyper::StatusCode, String, Vec<u8>) {
+        let response = handle_static(&Method::GET, state, path).await.unwrap();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|h| h.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, content_type, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn it_serves_static_files() {
+        let root = std::env::temp_dir().join(format!["mpdify-static-{}", std::process::id()]);
+        std::fs::create_dir_all(root.join("ui/assets")).unwrap();
+        std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+        std::fs::write(root.join("ui/index.html"), b"<html>").unwrap();
+        std::fs::write(root.join("ui/assets/app.js"), b"app()").unwrap();
+
+        let (tx, _) = mpsc::channel::<HandlerInput>(1);
+        let mut state = test_state(tx);
+        assert_eq!(404, get_static(&state, "/").await.0);
+        state.static_dir = Some(Arc::from(root.join("ui").as_path()));
+
+        let (status, content_type, body) = get_static(&state, "/assets/app.js").await;
+        assert_eq!(200, status);
+        assert_eq!("text/javascript; charset=utf-8", content_type);
+        assert_eq!(b"app()".to_vec(), body);
+        assert_eq!(b"<html>".to_vec(), get_static(&state, "/").await.2);
+
+        // Routes of the web UI get the index, missing assets don't
+        assert_eq!(b"<html>".to_vec(), get_static(&state, "/albums/1").await.2);
+        assert_eq!(404, get_static(&state, "/assets/missing.js").await.0);
+
+        // No way out of the directory
+        for path in &[
+            "/../secret.txt",
+            "/assets/%2E%2E/../secret.txt",
+            "/..%2Fsecret.txt",
+        ] {
+            assert_eq!(404, get_static(&state, path).await.0, "{}", path);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_refuses_config_to_remote_peers() {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(BasicCommandHandler::run(rx, "/cache".to_string(), 0));
+        let state = test_state(tx);
+        let get_config = |peer: &str| {
+            let state = state.for_peer(peer.parse().unwrap());
+            let request = Request::get("/command/config").body(Body::empty()).unwrap();
+            async move { handle_request(request, state).await.unwrap().status() }
+        };
+
+        assert_eq!(
+            hyper::StatusCode::FORBIDDEN,
+            get_config("192.0.2.1:6600").await
+        );
+        assert_eq!(hyper::StatusCode::OK, get_config("127.0.0.1:6600").await);
+    }
+
+    #[test]
+    fn it_maps_timeouts_to_504() {
+        let response = handle_error(RequestTimeout.into()).unwrap();
+        assert_eq!(hyper::StatusCode::GATEWAY_TIMEOUT, response.status());
+    }
+}
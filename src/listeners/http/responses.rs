@@ -1,13 +1,37 @@
-use crate::mpd_protocol::InputError;
-use hyper::header::{CONTENT_TYPE, LOCATION};
+use crate::mpd_protocol::{HandlerError, InputError, OutputData};
+use hyper::header::{CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
 use hyper::{Body, Response, StatusCode};
 use log::{debug, warn};
 use serde::Serialize;
+use std::path::Path;
+use thiserror::Error;
 
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 pub type Result = std::result::Result<Response<Body>, GenericError>;
 
+/// Returned when a handler does not answer in time
+#[derive(Error, Debug)]
+#[error("request timed out")]
+pub struct RequestTimeout;
+
 pub fn handle_error(err: GenericError) -> Result {
+    if err.is::<RequestTimeout>() {
+        warn!["Handler timed out"];
+        return Ok(Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body("Request timed out".into())
+            .unwrap());
+    }
+    if let Some(status) = err
+        .downcast_ref::<HandlerError>()
+        .and_then(client_error_status)
+    {
+        debug!["Handler refused the command: {}", err];
+        return Ok(Response::builder()
+            .status(status)
+            .body(err.to_string().into())
+            .unwrap());
+    }
     if let Some(err) = err.downcast_ref::<InputError>() {
         debug!["Input error: {:?}", err];
         return Ok(Response::builder()
@@ -22,6 +46,20 @@ pub fn handle_error(err: GenericError) -> Result {
         .unwrap())
 }
 
+/// Status for errors caused by the request itself, others are upstream failures
+fn client_error_status(err: &HandlerError) -> Option<StatusCode> {
+    match err {
+        HandlerError::Busy => Some(StatusCode::SERVICE_UNAVAILABLE),
+        HandlerError::PermissionDenied(_) => Some(StatusCode::FORBIDDEN),
+        HandlerError::NotImplemented(_) => Some(StatusCode::NOT_IMPLEMENTED),
+        HandlerError::NoHandler(_) | HandlerError::NoSuchSong => Some(StatusCode::NOT_FOUND),
+        HandlerError::InvalidArgument(_) | HandlerError::BadSongIndex => {
+            Some(StatusCode::BAD_REQUEST)
+        }
+        _ => None,
+    }
+}
+
 pub fn not_found() -> Result {
     Ok(Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -29,6 +67,21 @@ pub fn not_found() -> Result {
         .unwrap())
 }
 
+pub fn unauthorized() -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Basic realm=\"mpdify\"")
+        .body("Unauthorized".into())
+        .unwrap())
+}
+
+pub fn payload_too_large() -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body("Payload Too Large".into())
+        .unwrap())
+}
+
 pub fn ok_empty() -> Result {
     Ok(Response::builder()
         .status(StatusCode::NO_CONTENT)
@@ -47,6 +100,112 @@ where
         .unwrap())
 }
 
+/// Lists with at least this many items are streamed by ok_json_list
+const STREAMING_MIN_ITEMS: usize = 64;
+
+/// Sends handler data as a json list, even for single items that the MPD protocol
+/// unpacks. Large lists are serialized one item at a time while the body is sent,
+/// instead of being rendered in memory first.
+pub fn ok_json_list(data: OutputData) -> Result {
+    if data.data.len() < STREAMING_MIN_ITEMS {
+        return ok_json(&data.data);
+    }
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut separator = "[\n";
+        for item in data.data {
+            let mut chunk = separator.as_bytes().to_vec();
+            if let Err(err) = serde_json::to_writer_pretty(&mut chunk, &item) {
+                warn!["Cannot serialize item: {:?}", err];
+                sender.abort();
+                return;
+            }
+            if sender.send_data(chunk.into()).await.is_err() {
+                debug!["Client went away, stopping serialization"];
+                return;
+            }
+            separator = ",\n";
+        }
+        let _ = sender.send_data("\n]".into()).await;
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .unwrap())
+}
+
+/// Types artwork from the start of the image, as chunks past the first one can't be
+pub fn art_content_type(head: &[u8]) -> &'static str {
+    match head {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Sends a chunk of artwork, with the size of the whole image in a header
+pub fn ok_binary(size: u64, content_type: &str, data: Vec<u8>) -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .header("X-Art-Size", size)
+        .body(data.into())
+        .unwrap())
+}
+
+/// Sends a static file, typed from its extension
+pub fn ok_file(path: &Path, data: Vec<u8>) -> Result {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let content_type = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(data.into())
+        .unwrap())
+}
+
+pub fn ok_text(body: &'static str) -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(body.into())
+        .unwrap())
+}
+
+pub fn unavailable(body: &'static str) -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(body.into())
+        .unwrap())
+}
+
+pub fn ok_metrics(body: String) -> Result {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body.into())
+        .unwrap())
+}
+
 pub fn auth_ok() -> Result {
     Ok(Response::builder()
         .status(StatusCode::OK)
@@ -1,6 +1,6 @@
 use crate::handlers::client::HandlerClient;
 use crate::listeners::mpd::idle::{watch_idle, IdleClient};
-use crate::listeners::mpd::input::read_command;
+use crate::listeners::mpd::input::{complete_command, read_command, read_one_command};
 use crate::listeners::mpd::types::ListenerError;
 use crate::mpd_protocol::Command::CommandListStart;
 use crate::mpd_protocol::*;
@@ -28,17 +28,28 @@ pub struct Connection {
     read_lines: LinesStream<BufReader<OwnedReadHalf>>,
     write: OwnedWriteHalf,
     idle_client: IdleClient,
+    // Song tags enabled with the tagtypes command
+    tags: EnumSet<TagType>,
+    // Line read while a command was running, to execute next
+    pending: Option<Result<Command, ListenerError>>,
 }
 
 impl Connection {
     pub fn new(socket: TcpStream, handler: HandlerClient, idle_messages: IdleMessages) -> Self {
+        // Like MPD, only allow sensitive commands on local connections
+        let is_local = socket
+            .peer_addr()
+            .map(|a| a.ip().is_loopback())
+            .unwrap_or(false);
         let (read, write) = socket.into_split();
         let read_lines = LinesStream::new(BufReader::new(read).lines());
         Connection {
-            handler,
+            handler: handler.for_peer(is_local),
             read_lines,
             write,
             idle_client: watch_idle(idle_messages),
+            tags: EnumSet::all(),
+            pending: None,
         }
     }
 
@@ -50,9 +61,15 @@ impl Connection {
         }
 
         loop {
-            let ok = match read_command(&mut self.read_lines).await {
+            let next = match self.pending.take() {
+                Some(Ok(command)) => complete_command(command, &mut self.read_lines).await,
+                Some(Err(err)) => Err(err),
+                None => read_command(&mut self.read_lines).await,
+            };
+            let ok = match next {
                 Err(ListenerError::ConnectionClosed) => break,
-                Err(err) => self.output_error(err).await,
+                Err(err) => self.output_error(err, 0).await,
+                Ok(CommandListStart(list)) => self.exec_command_list(list).await,
                 Ok(command) => {
                     let result = self.exec_command(command).await;
                     self.output_result(result, OkOutput::Ok).await
@@ -71,35 +88,91 @@ impl Connection {
         }
     }
 
-    /// Wrapper around exec_one_command to handle command lists
+    /// Runs the commands of a list in order. Like MPD, the first failing command
+    /// aborts the list, and is reported with its index in the ACK.
+    async fn exec_command_list(&mut self, list: CommandList) -> Result<(), ListenerError> {
+        for (index, nested) in list.get_commands().into_iter().enumerate() {
+            let result = match nested {
+                Command::TagTypes(action) => self.exec_tag_types(action),
+                _ => self.handler.exec(nested).await,
+            };
+            match result {
+                Ok(output) => {
+                    let ok = if list.is_verbose() {
+                        self.output_result(Ok(output), OkOutput::ListOk).await
+                    } else {
+                        self.output_result(Ok(output), OkOutput::None).await
+                    };
+                    if let Err(err) = ok {
+                        warn!("Cannot print results: {:?}", err);
+                    }
+                }
+                Err(err) => {
+                    return self
+                        .output_error(ListenerError::HandlerError(err), index)
+                        .await
+                }
+            }
+        }
+        self.output_result(Ok(HandlerOutput::Ok), OkOutput::Ok)
+            .await
+    }
+
+    /// Wrapper around exec_one_command for commands handled by the connection
     async fn exec_command(&mut self, command: Command) -> HandlerResult {
         match command {
             // Idle is not supported in a command list
             Command::Idle(subsystems) => self.exec_idle(subsystems).await,
-            // Iterate over command lists
-            CommandListStart(list) => {
-                for nested in list.get_commands() {
-                    match self.handler.exec(nested).await {
-                        Ok(output) => {
-                            let ok = if list.is_verbose() {
-                                self.output_result(Ok(output), OkOutput::ListOk).await
-                            } else {
-                                self.output_result(Ok(output), OkOutput::None).await
-                            };
-                            if let Err(err) = ok {
-                                warn!("Cannot print results: {:?}", err);
-                            }
-                        }
-                        Err(err) => return Err(err),
-                    }
+
+            // Tags are filtered by the connection
+            Command::TagTypes(action) => self.exec_tag_types(action),
+
+            // Pass single commands
+            _ => self.exec_interruptible(command).await,
+        }
+    }
+
+    /// Runs a single command while reading the next line, to close the connection
+    /// without waiting for a slow command if the client sends close or disconnects.
+    /// Other lines are kept to be executed once the command completes.
+    /// This includes ping: clients match responses to commands by order, an OK written
+    /// before the running command's output would end its response early.
+    async fn exec_interruptible(&mut self, command: Command) -> HandlerResult {
+        let exec = self.handler.exec(command);
+        tokio::pin!(exec);
+        tokio::select! {
+            result = &mut exec => result,
+            next = read_one_command(&mut self.read_lines) => match next {
+                Ok(Command::Close) | Err(ListenerError::ConnectionClosed) => {
+                    debug!["Aborting running command on close"];
+                    Ok(HandlerOutput::Close)
+                }
+                next => {
+                    self.pending = Some(next);
+                    exec.await
                 }
-                Ok(HandlerOutput::Ok)
             }
-            // Pass single commands
-            _ => self.handler.exec(command).await,
         }
     }
 
+    fn exec_tag_types(&mut self, action: TagTypesAction) -> HandlerResult {
+        match action {
+            TagTypesAction::List => {
+                return Ok(HandlerOutput::Lines(
+                    self.tags
+                        .iter()
+                        .map(|tag| format!["tagtype: {}", <&str>::from(tag)])
+                        .collect(),
+                ))
+            }
+            TagTypesAction::Enable(tags) => self.tags.insert_all(tags),
+            TagTypesAction::Disable(tags) => self.tags.remove_all(tags),
+            TagTypesAction::Clear => self.tags.clear(),
+            TagTypesAction::All => self.tags = EnumSet::all(),
+        }
+        Ok(HandlerOutput::Ok)
+    }
+
     async fn exec_idle(&mut self, subsystems: EnumSet<IdleSubsystem>) -> HandlerResult {
         self.idle_client.start(subsystems).await;
         tokio::select! {
@@ -134,7 +207,7 @@ impl Connection {
         // Unpack handler output by handling error case early
         let output = match result {
             Ok(output) => output,
-            Err(err) => return self.output_error(ListenerError::HandlerError(err)).await,
+            Err(err) => return self.output_error(ListenerError::HandlerError(err), 0).await,
         };
 
         match output {
@@ -144,9 +217,11 @@ impl Connection {
             }
             HandlerOutput::Ok => {}
             HandlerOutput::Data(data) => {
+                let disabled: Vec<&'static str> =
+                    self.tags.complement().iter().map(<&str>::from).collect();
                 let mut items = stream::iter(data.data);
                 while let Some(item) = items.next().await {
-                    let bytes = to_string(&item)?;
+                    let bytes = to_string_without(&item, disabled.clone())?;
                     self.write.write(bytes.as_bytes()).await?;
                 }
             }
@@ -185,12 +260,15 @@ impl Connection {
         Ok(())
     }
 
-    /// Tries to executes a command by iterating over the registered handlers.
-    /// If a handler returns Unsupported, the next one is tried until no more are available.
-    async fn output_error(&mut self, err: ListenerError) -> Result<(), ListenerError> {
+    /// Reports an error, index is the position of the failing command in its list
+    async fn output_error(
+        &mut self,
+        err: ListenerError,
+        index: usize,
+    ) -> Result<(), ListenerError> {
         info!("Cannot handle command: {:?}", err);
         self.write
-            .write(format!["ACK {:?}\n", err].as_bytes())
+            .write(format!["ACK [{}@{}] {{}} {}\n", err.ack_code() as u8, index, err].as_bytes())
             .await?;
         Ok(())
     }
@@ -1,6 +1,8 @@
 use crate::mpd_protocol::IdleSubsystem;
-use crate::util::IdleMessages;
+use crate::util::{IdleMessage, IdleMessages};
 use enumset::EnumSet;
+use log::debug;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::mpsc;
 use tokio::time::{timeout, Duration};
 
@@ -39,22 +41,23 @@ impl WatcherState {
         loop {
             tokio::select! {
                 message = messages.recv() => {
-                    if let Ok(message) = message {
-                        self.changed.insert(message.what);
+                    if !self.record(message) {
+                        break;
+                    }
 
-                        // Wait 50ms for other messages to aggregate
-                        while let Ok(Ok(message)) = timeout(Duration::from_millis(50), messages.recv()).await {
-                            self.changed.insert(message.what);
+                    // Wait 50ms for other messages to aggregate
+                    while let Ok(message) = timeout(Duration::from_millis(50), messages.recv()).await {
+                        if !self.record(message) {
+                            break;
                         }
-
-                        self.check().await;
-                    } else {
-                        break;
                     }
+
+                    self.check().await;
                 }
                 enable = enable_rx.recv() => {
                     if let Some(enable) = enable {
                         self.waiting = enable;
+                        self.drain(&mut messages);
                         self.check().await;
                     } else {
                         break;
@@ -67,10 +70,42 @@ impl WatcherState {
         }
     }
 
+    /// Records a message from the bus, returns false if the bus is closed
+    fn record(&mut self, message: Result<IdleMessage, RecvError>) -> bool {
+        match message {
+            Ok(message) => {
+                self.changed.insert(message.what);
+                true
+            }
+            Err(RecvError::Lagged(count)) => {
+                // Missed messages could be about any subsystem
+                debug!["Idle watcher lagged by {} messages", count];
+                self.changed = EnumSet::all();
+                true
+            }
+            Err(RecvError::Closed) => false,
+        }
+    }
+
+    /// Records messages already sent on the bus, so that changes
+    /// made before an idle command are reported without delay
+    fn drain(&mut self, messages: &mut IdleMessages) {
+        loop {
+            let message = match messages.try_recv() {
+                Ok(message) => Ok(message),
+                Err(TryRecvError::Lagged(count)) => Err(RecvError::Lagged(count)),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            };
+            self.record(message);
+        }
+    }
+
     async fn check(&mut self) {
         if !self.changed.is_disjoint(self.waiting) {
             let matching = self.changed.intersection(self.waiting);
-            self.changed.remove_all(self.waiting); // FIXME: do we want to clear instead?
+            // Only clear the reported subsystems, changes to other ones
+            // must be returned by a later idle command
+            self.changed.remove_all(matching);
             self.waiting = EnumSet::empty();
 
             self.send_err = self.watch_tx.send(matching).await.is_err();
@@ -215,4 +250,62 @@ mod tests {
         watcher.start(EnumSet::only(Mixer)).await;
         assert_receive(&mut watcher, EnumSet::only(Mixer)).await;
     }
+
+    #[tokio::test]
+    async fn test_it_keeps_other_subsystems_across_idles() {
+        let (bus, mut watcher) = setup();
+        bus.notify(Mixer);
+
+        // Several rounds of idle on another subsystem don't consume the change
+        for _ in 0..3 {
+            watcher.start(EnumSet::only(Player)).await;
+            bus.notify(Player);
+            assert_receive(&mut watcher, EnumSet::only(Player)).await;
+        }
+
+        // Mixer change is delivered along with a pending one, then cleared
+        bus.notify(PlayQueue);
+        assert_nothing(&mut watcher).await;
+        watcher.start(Mixer | PlayQueue | Player).await;
+        assert_receive(&mut watcher, Mixer | PlayQueue).await;
+        watcher.start(Mixer | PlayQueue).await;
+        assert_nothing(&mut watcher).await;
+    }
+
+    #[tokio::test]
+    async fn test_it_survives_lagging() {
+        let _ = pretty_env_logger::try_init();
+        let bus = IdleBus::new();
+        let messages = bus.subscribe();
+
+        // Overflow the channel before the watcher reads from it
+        for _ in 0..32 {
+            bus.notify(Mixer);
+        }
+        let mut watcher = watch_idle(messages);
+
+        // Lost messages wake up any subsystem
+        watcher.start(EnumSet::only(Player)).await;
+        assert_receive(&mut watcher, EnumSet::only(Player)).await;
+
+        // Watcher is still running
+        watcher.start(EnumSet::only(Player)).await;
+        assert_nothing(&mut watcher).await;
+        bus.notify(Player);
+        assert_receive(&mut watcher, EnumSet::only(Player)).await;
+    }
+
+    #[tokio::test]
+    async fn test_it_does_not_lag_with_larger_capacity() {
+        let _ = pretty_env_logger::try_init();
+        let bus = IdleBus::with_capacity(64);
+        let messages = bus.subscribe();
+        for _ in 0..32 {
+            bus.notify(Mixer);
+        }
+        let mut watcher = watch_idle(messages);
+
+        watcher.start(Mixer | Player).await;
+        assert_receive(&mut watcher, EnumSet::only(Mixer)).await;
+    }
 }
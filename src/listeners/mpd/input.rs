@@ -11,9 +11,18 @@ where
     T: Stream<Item = std::io::Result<String>> + Unpin,
 {
     let command = read_one_command(lines).await?;
+    complete_command(command, lines).await
+}
 
+/// Reads the rest of a command list if the first line started one
+pub async fn complete_command<T>(command: Command, lines: &mut T) -> Result<Command, ListenerError>
+where
+    T: Stream<Item = std::io::Result<String>> + Unpin,
+{
     match command {
-        Command::CommandListEnd => Err(ListenerError::InputError(InputError::MissingCommand)),
+        Command::CommandListEnd => Err(ListenerError::InputError(
+            InputError::UnexpectedCommandListEnd,
+        )),
         Command::CommandListStart(mut list) => loop {
             let nested = read_one_command(lines).await?;
             match nested {
@@ -28,7 +37,8 @@ where
     }
 }
 
-async fn read_one_command<T>(lines: &mut T) -> Result<Command, ListenerError>
+/// Reads a single line, safe to cancel as no input is lost until a line is complete
+pub async fn read_one_command<T>(lines: &mut T) -> Result<Command, ListenerError>
 where
     T: Stream<Item = std::io::Result<String>> + Unpin,
 {
@@ -162,7 +172,9 @@ mod tests {
     #[tokio::test]
     async fn it_rejects_command_list_end_before_begin() {
         let mut input = Lines::from_str(vec!["command_list_end"]);
-        input.assert_input_error(InputError::MissingCommand).await;
+        input
+            .assert_input_error(InputError::UnexpectedCommandListEnd)
+            .await;
         input.assert_closed().await;
     }
 }
@@ -1,16 +1,22 @@
 use crate::handlers::client::HandlerClient;
 use crate::listeners::mpd::connection::Connection;
 use crate::mpd_protocol::*;
-use crate::util::{IdleBus, Settings};
+use crate::server::ServerError;
+use crate::util::{ConnectionTracker, IdleBus, Settings, METRICS};
+use futures::future::join_all;
 use log::{debug, warn};
+use socket2::{SockRef, TcpKeepalive};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 
 pub struct MpdListener {
-    tcp_listener: TcpListener,
+    tcp_listeners: Vec<TcpListener>,
     handler: HandlerClient,
     idle_bus: Arc<IdleBus>,
+    connections: Arc<ConnectionTracker>,
+    keepalive: Option<Duration>,
 }
 
 /// Listens to incoming connections and spawns one Connection task by client
@@ -19,50 +25,140 @@ impl MpdListener {
         settings: &Settings,
         mut handler: HandlerClient,
         idle_bus: Arc<IdleBus>,
-    ) -> Self {
+    ) -> Result<Self, ServerError> {
         // Run basic fallback handler
-        let (tx, rx) = mpsc::channel(8);
+        let (tx, rx) = mpsc::channel(settings.handler_queue_size);
         handler.add(tx);
+        let cache_path = settings.cache_root_path().display().to_string();
+        let artwork_cache_limit = settings.artwork_cache_size();
         tokio::spawn(async move {
-            BasicCommandHandler::run(rx).await;
+            BasicCommandHandler::run(rx, cache_path, artwork_cache_limit).await;
         });
 
-        MpdListener {
-            tcp_listener: TcpListener::bind(settings.mpd_address()).await.unwrap(),
+        let mut tcp_listeners = vec![];
+        for address in settings.mpd_addresses() {
+            match TcpListener::bind(address).await {
+                Ok(listener) => tcp_listeners.push(listener),
+                Err(err) => return Err(ServerError::Bind("MPD", address.to_string(), err)),
+            }
+        }
+
+        Ok(MpdListener {
+            tcp_listeners,
             handler,
             idle_bus,
-        }
+            connections: ConnectionTracker::new(settings.mpd_max_connections),
+            keepalive: settings.idle_keepalive(),
+        })
+    }
+
+    /// Returns the tracker for active connections, shared with the http metrics
+    pub fn connections(&self) -> Arc<ConnectionTracker> {
+        self.connections.clone()
     }
 
+    pub fn connection_count(&self) -> usize {
+        self.connections.count()
+    }
+
+    /// Returns the first bound address
     pub fn get_address(&self) -> std::io::Result<String> {
-        Ok(self.tcp_listener.local_addr()?.to_string())
+        match self.get_addresses()?.into_iter().next() {
+            Some(address) => Ok(address),
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
     }
 
+    pub fn get_addresses(&self) -> std::io::Result<Vec<String>> {
+        self.tcp_listeners
+            .iter()
+            .map(|l| Ok(l.local_addr()?.to_string()))
+            .collect()
+    }
+
+    /// Runs one accept loop per bound address
     pub async fn run(&mut self) {
-        debug!["Listening on {}", self.get_address().unwrap_or_default()];
-        loop {
-            let (socket, _) = self.tcp_listener.accept().await.unwrap();
-            let copied_handlers = self.handler.to_owned();
-            let idle_messages = self.idle_bus.subscribe();
-            tokio::spawn(async move {
-                Connection::new(socket, copied_handlers, idle_messages)
-                    .run()
-                    .await;
-            });
+        let mut loops = vec![];
+        for tcp_listener in self.tcp_listeners.drain(..) {
+            let handler = self.handler.to_owned();
+            let idle_bus = self.idle_bus.clone();
+            let connections = self.connections.clone();
+            let keepalive = self.keepalive;
+            loops.push(tokio::spawn(async move {
+                accept_loop(tcp_listener, handler, idle_bus, connections, keepalive).await
+            }));
+        }
+        join_all(loops).await;
+    }
+}
+
+async fn accept_loop(
+    tcp_listener: TcpListener,
+    handler: HandlerClient,
+    idle_bus: Arc<IdleBus>,
+    connections: Arc<ConnectionTracker>,
+    keepalive: Option<Duration>,
+) {
+    debug![
+        "Listening on {}",
+        tcp_listener
+            .local_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default()
+    ];
+    loop {
+        let (socket, peer) = tcp_listener.accept().await.unwrap();
+        let guard = match connections.try_acquire() {
+            Some(guard) => guard,
+            None => {
+                warn!["Too many connections, rejecting {}", peer];
+                continue;
+            }
+        };
+        if let Some(time) = keepalive {
+            // Application-level keepalives would break the protocol, use TCP ones
+            let params = TcpKeepalive::new().with_time(time);
+            if let Err(err) = SockRef::from(&socket).set_tcp_keepalive(&params) {
+                warn!["Cannot enable keepalive for {}: {}", peer, err];
+            }
         }
+        let copied_handlers = handler.to_owned();
+        let idle_messages = idle_bus.subscribe();
+        tokio::spawn(async move {
+            Connection::new(socket, copied_handlers, idle_messages)
+                .run()
+                .await;
+            drop(guard);
+        });
     }
 }
 
-/// Handles the ping and close commands
+/// Handles the ping and close commands, noidle when not idling, accepts
+/// queue priorities as no-ops and rejects the tag edition commands.
+/// Config is only answered to local clients, as it exposes filesystem paths.
 pub struct BasicCommandHandler {}
 
 impl BasicCommandHandler {
-    async fn run(mut commands: mpsc::Receiver<HandlerInput>) {
+    pub(crate) async fn run(
+        mut commands: mpsc::Receiver<HandlerInput>,
+        cache_path: String,
+        artwork_cache_limit: u64,
+    ) {
         debug!["BasicCommandHandler entered loop"];
         while let Some(input) = commands.recv().await {
             let resp = match input.command {
-                Command::Ping => Ok(HandlerOutput::Ok),
+                Command::Ping | Command::NoIdle => Ok(HandlerOutput::Ok),
+                Command::Prio(_, _) | Command::PrioId(_, _) => Ok(HandlerOutput::Ok),
+                Command::AddTagId => Err(HandlerError::PermissionDenied("addtagid")),
+                Command::ClearTagId => Err(HandlerError::PermissionDenied("cleartagid")),
                 Command::Close => Ok(HandlerOutput::Close),
+                Command::Config if !input.is_local => Err(HandlerError::PermissionDenied("config")),
+                Command::Config => Ok(HandlerOutput::from(ConfigResponse {
+                    music_directory: INTERNAL_PREFIX.to_string(),
+                    cache_path: cache_path.clone(),
+                    artwork_cache_bytes: METRICS.artwork_disk_bytes(),
+                    artwork_cache_limit,
+                })),
                 Command::Commands => Ok(HandlerOutput::Lines(
                     Command::known_commands()
                         .iter()
@@ -81,3 +177,23 @@ impl BasicCommandHandler {
         debug!["BasicCommandHandler exited loop"];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_only_answers_config_to_local_clients() {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(BasicCommandHandler::run(rx, "/cache".to_string(), 0));
+        let handler = HandlerClient::new(vec![tx]);
+
+        let result = handler.for_peer(false).exec(Command::Config).await;
+        assert!(matches!(
+            result,
+            Err(HandlerError::PermissionDenied("config"))
+        ));
+        let result = handler.for_peer(true).exec(Command::Config).await;
+        assert!(matches!(result, Ok(HandlerOutput::Data(_))));
+    }
+}
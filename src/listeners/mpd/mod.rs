@@ -4,4 +4,6 @@ mod input;
 mod listener;
 mod types;
 
+#[cfg(test)]
+pub(crate) use listener::BasicCommandHandler;
 pub use listener::MpdListener;
@@ -1,4 +1,4 @@
-use crate::mpd_protocol::{HandlerError, InputError, SerializerError};
+use crate::mpd_protocol::{AckCode, HandlerError, InputError, SerializerError};
 use std::fmt::Debug;
 use thiserror::Error;
 
@@ -17,3 +17,13 @@ pub enum ListenerError {
     #[error(transparent)]
     HandlerError(#[from] HandlerError),
 }
+
+impl ListenerError {
+    pub fn ack_code(&self) -> AckCode {
+        match self {
+            ListenerError::InputError(err) => err.ack_code(),
+            ListenerError::HandlerError(err) => err.ack_code(),
+            _ => AckCode::System,
+        }
+    }
+}
@@ -1,36 +1,64 @@
-use log::debug;
-use mpdify::handlers::artwork::ArtworkHandler;
-use mpdify::handlers::aspotify::SpotifyHandler;
-use mpdify::handlers::client::{build_aspotify_client, HandlerClient};
-use mpdify::listeners::http::listener::HttpListener;
-use mpdify::listeners::mpd::MpdListener;
-use mpdify::util::{IdleBus, Settings};
+use mpdify::server::{MpdifyServer, ServerError};
+use mpdify::util::{init_logger, Settings};
 
 #[tokio::main]
 pub async fn main() -> () {
-    pretty_env_logger::init();
-    let settings = Settings::new().expect("Cannot read settings");
-    debug!["Current settings: {:?}", settings];
-
-    let client = build_aspotify_client().unwrap();
-    let mut handlers = HandlerClient::default();
-    let idle_bus = IdleBus::new();
-
-    let (mut spotify, spotify_tx) =
-        SpotifyHandler::new(&settings, client.clone(), idle_bus.clone()).await;
-    handlers.add(spotify_tx);
+    let check_only = std::env::args().skip(1).any(|arg| arg == "--check");
+    // Settings are read first, as they configure logging
+    let builder = match Settings::new() {
+        Ok(settings) => {
+            init_logger(&settings);
+            MpdifyServer::builder().with_settings(settings)
+        }
+        Err(err) if check_only => {
+            std::process::exit(report_failure(ServerError::ReadSettings(err)))
+        }
+        Err(err) => exit_with(ServerError::ReadSettings(err)),
+    };
+    if check_only {
+        std::process::exit(match builder.build() {
+            Ok(server) => check(server).await,
+            Err(err) => report_failure(err),
+        });
+    }
+    match builder.build() {
+        Ok(server) => {
+            if let Err(err) = server.run().await {
+                exit_with(err)
+            }
+        }
+        Err(err) => exit_with(err),
+    }
+}
 
-    let (mut artwork, artwork_tx) = ArtworkHandler::new(&settings, client.clone()).await;
-    handlers.add(artwork_tx);
+/// Reports the configuration and auth status, returns the exit code
+async fn check(server: MpdifyServer) -> i32 {
+    println!["settings: OK"];
+    println!["spotify credentials: OK"];
+    match server.check_auth().await {
+        Ok(()) => {
+            println!["spotify auth: OK"];
+            0
+        }
+        Err(err) => {
+            println!["spotify auth: FAILED, {}", err];
+            1
+        }
+    }
+}
 
-    let mut mpd = MpdListener::new(&settings, handlers.clone(), idle_bus.clone()).await;
-    let mut http = HttpListener::new(&settings, handlers);
+fn report_failure(err: ServerError) -> i32 {
+    match err {
+        ServerError::Credentials(_) => {
+            println!["settings: OK"];
+            println!["spotify credentials: FAILED, {}", err];
+        }
+        _ => println!["settings: FAILED, {}", err],
+    }
+    1
+}
 
-    let tasks = vec![
-        tokio::spawn(async move { spotify.run().await }),
-        tokio::spawn(async move { artwork.run().await }),
-        tokio::spawn(async move { mpd.run().await }),
-        tokio::spawn(async move { http.run().await }),
-    ];
-    futures::future::join_all(tasks).await;
+fn exit_with(err: ServerError) -> ! {
+    eprintln!["{}", err];
+    std::process::exit(1);
 }
@@ -0,0 +1,33 @@
+/// Error codes sent in ACK responses
+/// See https://github.com/MusicPlayerDaemon/MPD/blob/master/src/protocol/Ack.hxx
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckCode {
+    Arg = 2,
+    Permission = 4,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpd_protocol::{AckCode, HandlerError, InputError};
+
+    #[test]
+    fn it_maps_errors_to_codes() {
+        assert_eq!(AckCode::Unknown, InputError::MissingCommand.ack_code());
+        assert_eq!(
+            AckCode::Unknown,
+            InputError::UnexpectedCommandListEnd.ack_code()
+        );
+        assert_eq!(AckCode::Arg, InputError::MissingArgument("uri").ack_code());
+        assert_eq!(AckCode::System, HandlerError::NoMixer.ack_code());
+        assert_eq!(
+            AckCode::Permission,
+            HandlerError::PermissionDenied("config").ack_code()
+        );
+        assert_eq!(AckCode::NoExist, HandlerError::NoSuchSong.ack_code());
+        assert_eq!(AckCode::Arg, HandlerError::BadSongIndex.ack_code());
+        assert_eq!(52, HandlerError::NoMixer.ack_code() as u8);
+    }
+}
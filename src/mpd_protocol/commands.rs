@@ -1,19 +1,25 @@
 use crate::mpd_protocol::commands::Command::{
-    ChangeVolume, EnableOutput, Pause, PlayId, PlayPos, PlaylistId, PlaylistInfo, Random, Repeat,
-    RepeatSingle, SeekCur, SeekId, SeekPos, SetVolume, SpotifyAuth,
+    ChangeVolume, EnableOutput, ListAll, ListAllInfo, Pause, PlChanges, PlChangesPosId, PlayId,
+    PlayPos, PlaylistId, PlaylistInfo, Prio, PrioId, Random, Repeat, RepeatSingle, Rescan, SeekCur,
+    SeekId, SeekPercent, SeekPos, SetVolume, SpotifyAuth, Update,
 };
 use crate::mpd_protocol::input::InputError::{
     InvalidArgument, MissingArgument, MissingCommand, UnknownCommand,
 };
 use crate::mpd_protocol::input::{InputError, RelativeFloat};
 use crate::mpd_protocol::Command::AlbumArt;
-use crate::mpd_protocol::{CommandList, IdleSubsystem, Path, PositionRange};
+use crate::mpd_protocol::{
+    CommandList, IdleSubsystem, Path, PositionRange, TagType, TagTypesAction,
+};
 use enumset::EnumSet;
 use log::debug;
 use std::str::FromStr;
+use strum::IntoStaticStr;
 
 // From https://www.musicpd.org/doc/html/protocol.html
-#[derive(Debug, PartialEq, Clone)]
+// Names are the keywords commands are parsed from, for errors and metrics to show them
+#[derive(Debug, PartialEq, Clone, IntoStaticStr)]
+#[strum(serialize_all = "lowercase")]
 pub enum Command {
     // Status commands
     CurrentSong,
@@ -21,7 +27,10 @@ pub enum Command {
     NoIdle,
     Status,
     Stats,
+    ClearError,
     Commands,
+    Config,
+    NowPlaying, // Extension, status and currentsong in one response
 
     // Outputs
     Outputs,
@@ -30,41 +39,69 @@ pub enum Command {
     // Playlist info
     PlaylistInfo(Option<PositionRange>), // End is exclusive
     PlaylistId(Option<usize>),
+    PlChanges(u32),          // Queue version known by the client
+    PlChangesPosId(u32),     // Queue version known by the client
+    AddTagId,                // Not supported, we can't edit Spotify tags
+    ClearTagId,              // Not supported, we can't edit Spotify tags
+    Prio(u8, PositionRange), // No-op, Spotify queues have no priorities
+    PrioId(u8, usize),       // No-op, Spotify queues have no priorities
 
     // Playback options
     Random(bool),
     Repeat(bool),
+    #[strum(serialize = "single")]
     RepeatSingle(bool),
 
     // Playback control
     Next,
-    Pause(Option<bool>),    // None means toggle
+    Pause(Option<bool>), // None means toggle
+    #[strum(serialize = "play")]
     PlayPos(Option<usize>), // None means unpause, position >=0
-    PlayId(Option<usize>),  // None means unpause, id > 0
+    PlayId(Option<usize>), // None means unpause, id > 0
     Previous,
     SeekId(usize, f64),
     SeekPos(usize, f64),
     SeekCur(RelativeFloat), // Seconds
+    SeekPercent(f64),       // Extension, percentage of the current song
     Stop,
 
+    // Music database
+    ListAll(Path),
+    ListAllInfo(Path),
+    Update(Option<Path>),
+    Rescan(Option<Path>),
+
     // Volume
+    #[strum(serialize = "getvol")]
     GetVolume,
-    SetVolume(u32),    // Absolute value
+    #[strum(serialize = "setvol")]
+    SetVolume(u32), // Absolute value
+    #[strum(serialize = "volume")]
     ChangeVolume(i32), // Relative change
 
     // Connection settings
     Ping,
     Close,
+    TagTypes(TagTypesAction),
 
     // Command list
+    #[strum(serialize = "command_list_begin")]
     CommandListStart(CommandList),
+    #[strum(serialize = "command_list_end")]
     CommandListEnd,
 
     // Artwork
     AlbumArt(Path, u64),
 
     // Custom extension to support oauth2 authentication
+    #[strum(serialize = "auth")]
     SpotifyAuth(Option<String>),
+
+    // Custom extensions to manage saved tracks
+    #[strum(serialize = "save_track")]
+    SaveCurrent,
+    #[strum(serialize = "unsave_track")]
+    UnsaveCurrent,
 }
 
 impl FromStr for Command {
@@ -80,11 +117,19 @@ impl Command {
         vec![
             "currentsong",
             "status",
+            "stats",
+            "clearerror",
             "commands",
+            "config",
+            "nowplaying",
             "idle",
             "noidle",
             "playlistinfo",
+            "prio",
+            "prioid",
             "playlistid",
+            "plchanges",
+            "plchangesposid",
             "random",
             "repeat",
             "single",
@@ -94,18 +139,26 @@ impl Command {
             "seekcur",
             "seekid",
             "seekpos",
+            "seekpercent",
             "stop",
             "play",
             "playid",
+            "listall",
+            "listallinfo",
+            "update",
+            "rescan",
             "getvol",
             "setvol",
             "volume",
             "ping",
             "close",
+            "tagtypes",
             "command_list_begin",
             "command_list_ok_begin",
             "command_list_end",
             "auth",
+            "save_track",
+            "unsave_track",
             "outputs",
             "toggleoutput",
             "enableoutput",
@@ -122,6 +175,8 @@ impl Command {
             "status" => Ok(Command::Status),
             "stats" => Ok(Command::Stats),
             "commands" => Ok(Command::Commands),
+            "config" => Ok(Command::Config),
+            "nowplaying" => Ok(Command::NowPlaying),
 
             // Outputs
             "outputs" => Ok(Command::Outputs),
@@ -148,6 +203,12 @@ impl Command {
             // Playlist info
             "playlistinfo" => args.opt("range").map(PlaylistInfo),
             "playlistid" => args.opt("songid").and_then(check_song_id).map(PlaylistId),
+            "plchanges" => args.req("version").map(PlChanges),
+            "plchangesposid" => args.req("version").map(PlChangesPosId),
+            "addtagid" => Ok(Command::AddTagId),
+            "cleartagid" => Ok(Command::ClearTagId),
+            "prio" => Ok(Prio(args.req("priority")?, args.req("range")?)),
+            "prioid" => Ok(PrioId(args.req("priority")?, args.req("songid")?)),
 
             // Playback options
             "random" => args.req("state").map(int_to_bool).map(Random),
@@ -161,10 +222,17 @@ impl Command {
             "seekcur" => args.req("time").map(SeekCur),
             "seekid" => Ok(SeekId(args.req("songid")?, args.req("time")?)),
             "seekpos" => Ok(SeekPos(args.req("songpos")?, args.req("time")?)),
+            "seekpercent" => args.req("percent").and_then(check_percent).map(SeekPercent),
             "stop" => Ok(Command::Stop),
             "play" => args.opt("pos").map(PlayPos),
             "playid" => args.opt("songid").and_then(check_song_id).map(PlayId),
 
+            // Music database
+            "listall" => Ok(ListAll(args.opt("uri")?.unwrap_or(Path::Empty))),
+            "listallinfo" => Ok(ListAllInfo(args.opt("uri")?.unwrap_or(Path::Empty))),
+            "update" => args.opt("uri").map(Update),
+            "rescan" => args.opt("uri").map(Rescan),
+
             // Volume
             "getvol" => Ok(Command::GetVolume),
             "setvol" => args.req("vol").map(SetVolume),
@@ -172,6 +240,8 @@ impl Command {
 
             // Connection settings
             "ping" => Ok(Command::Ping),
+            "clearerror" => Ok(Command::ClearError),
+            "tagtypes" => parse_tag_types_action(&mut args).map(Command::TagTypes),
             "close" => Ok(Command::Close),
 
             // Command list
@@ -182,13 +252,17 @@ impl Command {
             // Custom extension to support oauth2 authentication
             "auth" => args.opt("url").map(SpotifyAuth),
 
+            // Custom extensions to manage saved tracks
+            "save_track" => Ok(Command::SaveCurrent),
+            "unsave_track" => Ok(Command::UnsaveCurrent),
+
             // Artwork
             "albumart" | "readpicture" => Ok(AlbumArt(args.req("uri")?, args.req("offset")?)),
 
             // Unsupported commands we just map to a ping
-            "clearerror" | "channels" | "subscribe" | "unsubscribe" | "readmessages"
-            | "sendmessage" | "consume" | "crossfade" | "mixrampdb" | "mixrampdelay"
-            | "replay_gain_mode" | "replay_gain_status" | "disableoutput" => Ok(Command::Ping),
+            "channels" | "subscribe" | "unsubscribe" | "readmessages" | "sendmessage"
+            | "consume" | "crossfade" | "mixrampdb" | "mixrampdelay" | "replay_gain_mode"
+            | "replay_gain_status" | "disableoutput" => Ok(Command::Ping),
 
             // Unknown command
             _ => Err(UnknownCommand(command)),
@@ -248,6 +322,38 @@ fn check_song_id(id: Option<usize>) -> Result<Option<usize>, InputError> {
     }
 }
 
+fn check_percent(percent: f64) -> Result<f64, InputError> {
+    match (0.0..=100.0).contains(&percent) {
+        true => Ok(percent),
+        false => Err(InvalidArgument("percent", percent.to_string())),
+    }
+}
+
+fn parse_tag_types_action(args: &mut Arguments) -> Result<TagTypesAction, InputError> {
+    match args.pop().as_deref() {
+        None => Ok(TagTypesAction::List),
+        Some("enable") => parse_tag_types(args).map(TagTypesAction::Enable),
+        Some("disable") => parse_tag_types(args).map(TagTypesAction::Disable),
+        Some("clear") => Ok(TagTypesAction::Clear),
+        Some("all") => Ok(TagTypesAction::All),
+        Some(other) => Err(InvalidArgument("action", other.to_string())),
+    }
+}
+
+fn parse_tag_types(args: &mut Arguments) -> Result<EnumSet<TagType>, InputError> {
+    let mut tags = EnumSet::empty();
+    while let Some(name) = args.pop() {
+        match TagType::from_str(&name) {
+            Ok(tag) => tags.insert(tag),
+            Err(_) => return Err(InvalidArgument("tagtype", name)),
+        };
+    }
+    match tags.is_empty() {
+        true => Err(MissingArgument("tagtype")),
+        false => Ok(tags),
+    }
+}
+
 fn tokenize_command(input: &str) -> Vec<String> {
     let mut tokens = vec![];
     let mut is_escaped = false;
@@ -287,6 +393,7 @@ mod tests {
     use super::*;
     use crate::mpd_protocol::commands::Command::Ping;
     use crate::mpd_protocol::input::RelativeFloat::{Absolute, Relative};
+    use crate::mpd_protocol::to_string;
     use crate::mpd_protocol::Command::Idle;
 
     #[test]
@@ -307,6 +414,64 @@ mod tests {
         assert_eq!(Command::from_str("ping").unwrap(), Ping);
     }
 
+    #[test]
+    fn test_tagtypes() {
+        assert_eq!(
+            Command::from_str("tagtypes").unwrap(),
+            Command::TagTypes(TagTypesAction::List)
+        );
+        assert_eq!(
+            Command::from_str("tagtypes disable track Disc").unwrap(),
+            Command::TagTypes(TagTypesAction::Disable(TagType::Track | TagType::Disc))
+        );
+        assert_eq!(
+            Command::from_str("tagtypes disable x-spotify-id isrc").unwrap(),
+            Command::TagTypes(TagTypesAction::Disable(TagType::SpotifyId | TagType::Isrc))
+        );
+        assert_eq!(
+            Command::from_str("tagtypes clear").unwrap(),
+            Command::TagTypes(TagTypesAction::Clear)
+        );
+        assert_eq!(
+            Command::from_str("tagtypes enable").err().unwrap(),
+            MissingArgument("tagtype")
+        );
+        assert_eq!(
+            Command::from_str("tagtypes enable Genre").err().unwrap(),
+            InvalidArgument("tagtype", "Genre".to_string())
+        );
+    }
+
+    #[test]
+    fn test_saved_tracks() {
+        assert_eq!(
+            Command::from_str("save_track").unwrap(),
+            Command::SaveCurrent
+        );
+        assert_eq!(
+            Command::from_str("unsave_track").unwrap(),
+            Command::UnsaveCurrent
+        );
+    }
+
+    #[test]
+    fn test_plchanges() {
+        assert_eq!(Command::from_str("plchanges 3").unwrap(), PlChanges(3));
+        assert_eq!(
+            Command::from_str("plchangesposid 0").unwrap(),
+            PlChangesPosId(0)
+        );
+        assert!(Command::from_str("plchanges").is_err());
+    }
+
+    #[test]
+    fn test_clearerror() {
+        assert_eq!(
+            Command::from_str("clearerror").unwrap(),
+            Command::ClearError
+        );
+    }
+
     #[test]
     fn test_pause() {
         assert_eq!(Command::from_str("pause 1").unwrap(), Pause(Some(true)));
@@ -338,6 +503,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_albumart() {
+        let uri = "internal/album/4IOXEu8EgItKI8J9JDaEr4";
+        assert_eq!(
+            Command::from_str(&format!("albumart {} 1024", uri)).unwrap(),
+            AlbumArt(Path::from_str(uri).unwrap(), 1024)
+        );
+        assert_eq!(
+            Command::from_str(&format!("albumart {} -1", uri))
+                .err()
+                .unwrap(),
+            InvalidArgument("offset", "-1".to_string())
+        );
+        assert_eq!(
+            Command::from_str(&format!("albumart {} 99999999999999999999", uri))
+                .err()
+                .unwrap(),
+            InvalidArgument("offset", "99999999999999999999".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prio() {
+        assert_eq!(
+            Command::from_str("prio 255 2:5").unwrap(),
+            Prio(255, PositionRange { start: 2, end: 5 })
+        );
+        assert_eq!(
+            Command::from_str("prio 0 3").unwrap(),
+            Prio(0, PositionRange::one(3))
+        );
+        assert_eq!(Command::from_str("prioid 10 4").unwrap(), PrioId(10, 4));
+
+        assert_eq!(
+            Command::from_str("prio 256 1").err().unwrap(),
+            InvalidArgument("priority", "256".to_string())
+        );
+        assert_eq!(
+            Command::from_str("prio 1 5:2").err().unwrap(),
+            InvalidArgument("range", "5:2".to_string())
+        );
+        assert_eq!(
+            Command::from_str("prioid 1").err().unwrap(),
+            MissingArgument("songid")
+        );
+    }
+
+    #[test]
+    fn test_listall() {
+        assert_eq!(Command::from_str("listall").unwrap(), ListAll(Path::Empty));
+        assert_eq!(
+            Command::from_str("listall /").unwrap(),
+            ListAll(Path::Empty)
+        );
+        assert_eq!(
+            Command::from_str("listallinfo internal/album/4IOXEu8EgItKI8J9JDaEr4").unwrap(),
+            ListAllInfo(Path::from_str("internal/album/4IOXEu8EgItKI8J9JDaEr4").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        assert_eq!(Command::from_str("update").unwrap(), Update(None));
+        assert_eq!(
+            Command::from_str("rescan internal/album/4IOXEu8EgItKI8J9JDaEr4").unwrap(),
+            Rescan(Some(
+                Path::from_str("internal/album/4IOXEu8EgItKI8J9JDaEr4").unwrap()
+            ))
+        );
+        assert_eq!(
+            Command::from_str("update unknown/path").err().unwrap(),
+            InvalidArgument("uri", "unknown/path".to_string())
+        );
+    }
+
     #[test]
     fn test_idle() {
         assert_eq!(Command::from_str("idle").unwrap(), Idle(EnumSet::all()));
@@ -345,6 +585,55 @@ mod tests {
             Command::from_str("idle playlist unknown mixer").unwrap(),
             Idle(IdleSubsystem::PlayQueue | IdleSubsystem::Mixer)
         );
+        assert_eq!(
+            Command::from_str("idle database").unwrap(),
+            Idle(EnumSet::only(IdleSubsystem::Database))
+        );
+    }
+
+    #[test]
+    fn test_idle_subsystem_names() {
+        // Exhaustive on purpose, new subsystems must be given their protocol name here
+        let name = |subsystem| match subsystem {
+            IdleSubsystem::Database => "database",
+            IdleSubsystem::PlayQueue => "playlist",
+            IdleSubsystem::Playlists => "stored_playlist",
+            IdleSubsystem::Player => "player",
+            IdleSubsystem::Mixer => "mixer",
+            IdleSubsystem::Options => "options",
+            IdleSubsystem::Outputs => "output",
+        };
+        for subsystem in EnumSet::<IdleSubsystem>::all() {
+            assert_eq!(name(subsystem), to_string(&subsystem).unwrap());
+            assert_eq!(
+                Command::from_str(&format!["idle {}", name(subsystem)]).unwrap(),
+                Idle(EnumSet::only(subsystem))
+            );
+        }
+    }
+
+    #[test]
+    fn test_seek_percent() {
+        assert_eq!(
+            Command::from_str("seekpercent 42.5").unwrap(),
+            SeekPercent(42.5)
+        );
+        assert_eq!(
+            Command::from_str("seekpercent 100").unwrap(),
+            SeekPercent(100.0)
+        );
+        assert_eq!(
+            Command::from_str("seekpercent").err().unwrap(),
+            MissingArgument("percent")
+        );
+        assert_eq!(
+            Command::from_str("seekpercent 100.1").err().unwrap(),
+            InvalidArgument("percent", "100.1".to_string())
+        );
+        assert_eq!(
+            Command::from_str("seekpercent -5").err().unwrap(),
+            InvalidArgument("percent", "-5".to_string())
+        );
     }
 
     #[test]
@@ -389,4 +678,23 @@ mod tests {
         assert_eq!(tokenize_command("pause 1"), vec!["pause", "1"]);
         assert_eq!(tokenize_command("pause \"1\""), vec!["pause", "1"]);
     }
+
+    #[test]
+    fn it_names_commands_after_their_keyword() {
+        for line in [
+            "albumart internal 0",
+            "seekpercent 50",
+            "playlistinfo",
+            "volume 5",
+            "single 1",
+            "play",
+            "save_track",
+            "auth",
+            "command_list_end",
+        ] {
+            let command = Command::from_str(line).unwrap();
+            let name: &'static str = (&command).into();
+            assert_eq!(line.split(' ').next().unwrap(), name);
+        }
+    }
 }
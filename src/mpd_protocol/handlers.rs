@@ -1,5 +1,5 @@
 use crate::mpd_protocol::commands::Command;
-use crate::mpd_protocol::{IdleSubsystem, OutputData};
+use crate::mpd_protocol::{AckCode, IdleSubsystem, OutputData};
 use enumset::EnumSet;
 use thiserror::Error;
 use tokio::sync::oneshot::Sender;
@@ -7,12 +7,36 @@ use tokio::sync::oneshot::Sender;
 /// Errors caused by command handling
 #[derive(Error, Debug)]
 pub enum HandlerError {
+    /// Not handled by this handler, the next one is tried
     #[error("unsupported operation")]
     Unsupported,
+    /// Recognized but not possible with Spotify, returned without trying other handlers
+    #[error("{0} is not supported by Spotify")]
+    NotImplemented(String),
+    /// No handler supports this command
+    #[error("unsupported command: {0}")]
+    NoHandler(&'static str),
     #[error(transparent)]
     GetError(#[from] tokio::sync::mpsc::error::SendError<HandlerInput>),
+    /// The handler queue is full
+    #[error("server busy, try again later")]
+    Busy,
+    #[error("you don't have permission for \"{0}\"")]
+    PermissionDenied(&'static str),
     #[error("Authenticate at: {0}")]
     AuthNeeded(String),
+    #[error("requires Spotify Premium")]
+    PremiumRequired,
+    #[error("problems setting volume")]
+    NoMixer,
+    #[error("no active device")]
+    NoActiveDevice,
+    #[error("No such song")]
+    NoSuchSong,
+    #[error("Bad song index")]
+    BadSongIndex,
+    #[error("{0}")]
+    InvalidArgument(String),
     #[error(transparent)]
     RedirectedError(#[from] aspotify::RedirectedError),
     #[error("Spotify error: {0}")]
@@ -25,6 +49,23 @@ pub enum HandlerError {
     FromString(String),
 }
 
+impl HandlerError {
+    pub fn ack_code(&self) -> AckCode {
+        match self {
+            HandlerError::Unsupported
+            | HandlerError::NotImplemented(_)
+            | HandlerError::NoHandler(_)
+            | HandlerError::FromString(_) => AckCode::Unknown,
+            HandlerError::InvalidArgument(_) | HandlerError::BadSongIndex => AckCode::Arg,
+            HandlerError::NoSuchSong => AckCode::NoExist,
+            HandlerError::PermissionDenied(_)
+            | HandlerError::AuthNeeded(_)
+            | HandlerError::PremiumRequired => AckCode::Permission,
+            _ => AckCode::System,
+        }
+    }
+}
+
 /// Commands can return different types of result
 #[derive(Debug)]
 pub enum HandlerOutput {
@@ -60,5 +101,7 @@ pub type HandlerResult = Result<HandlerOutput, HandlerError>;
 #[derive(Debug)]
 pub struct HandlerInput {
     pub command: Command,
+    // Set for clients connected from the loopback interface, allowed to run sensitive commands
+    pub is_local: bool,
     pub resp: Sender<HandlerResult>,
 }
@@ -1,10 +1,11 @@
-use enumset::EnumSetType;
+use enumset::{EnumSet, EnumSetType};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use strum::IntoStaticStr;
 use thiserror::Error;
 
 use crate::mpd_protocol::input::RelativeFloat::{Absolute, Relative};
-use crate::mpd_protocol::Command;
+use crate::mpd_protocol::{AckCode, Command};
 
 /// Errors caused by invalid client input
 #[derive(Error, Debug, PartialEq)]
@@ -21,6 +22,19 @@ pub enum InputError {
     InvalidArgument(&'static str, String),
     #[error("cannot nest command lists")]
     NestedLists,
+    #[error("list end without begin")]
+    UnexpectedCommandListEnd,
+}
+
+impl InputError {
+    pub fn ack_code(&self) -> AckCode {
+        match self {
+            InputError::InvalidSyntax(_)
+            | InputError::MissingArgument(_)
+            | InputError::InvalidArgument(_, _) => AckCode::Arg,
+            _ => AckCode::Unknown,
+        }
+    }
 }
 
 /// Supported subsystems for the idle command
@@ -28,6 +42,7 @@ pub enum InputError {
 #[derive(EnumSetType, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum IdleSubsystem {
+    Database,
     #[serde(rename = "playlist")]
     PlayQueue,
     #[serde(rename = "stored_playlist")]
@@ -39,6 +54,44 @@ pub enum IdleSubsystem {
     Outputs,
 }
 
+/// Song tags that clients can hide with the tagtypes command,
+/// variant names match the keys in SongResponse
+#[derive(EnumSetType, Debug, IntoStaticStr)]
+pub enum TagType {
+    Artist,
+    Album,
+    Title,
+    Date,
+    Track,
+    Disc,
+    #[strum(serialize = "X-SPOTIFY-ID")]
+    SpotifyId,
+    #[strum(serialize = "ISRC")]
+    Isrc,
+}
+
+impl FromStr for TagType {
+    type Err = ();
+
+    /// Tag names are case insensitive
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EnumSet::<TagType>::all()
+            .iter()
+            .find(|tag| s.eq_ignore_ascii_case(tag.into()))
+            .ok_or(())
+    }
+}
+
+/// Sub-commands of the tagtypes command
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagTypesAction {
+    List,
+    Enable(EnumSet<TagType>),
+    Disable(EnumSet<TagType>),
+    Clear,
+    All,
+}
+
 /// Parses a float, optionally prefixed by + or -
 #[derive(Debug, Clone, PartialEq)]
 pub enum RelativeFloat {
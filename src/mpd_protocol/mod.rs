@@ -1,3 +1,4 @@
+mod ack;
 mod commands;
 mod handlers;
 mod input;
@@ -5,6 +6,7 @@ mod output;
 mod path;
 mod ser;
 
+pub use ack::*;
 pub use commands::*;
 pub use handlers::*;
 pub use input::*;
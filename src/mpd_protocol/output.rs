@@ -15,19 +15,97 @@ pub enum PlaybackStatus {
     Stop,
 }
 
-/// Response for the status command
-#[derive(Debug, PartialEq, Serialize)]
+/// Response for the status command, serialized in the order of MPD's status output
+#[derive(Debug, PartialEq)]
 pub struct StatusResponse {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub volume: Option<u32>,
+    pub volume: Option<i32>,
     pub state: PlaybackStatus,
     pub random: bool,
     pub repeat: bool,
     pub single: bool,
-    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    /// Queue version, changing each time a new context is loaded
+    pub playlist: u32,
     pub durations: Option<StatusDurations>,
-    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub audio_info: Option<StatusAudioInfo>,
     pub playlist_info: Option<StatusPlaylistInfo>,
+    pub extensions: Option<StatusExtensions>,
+    pub updating_db: Option<usize>,
+    pub error: Option<String>,
+}
+
+/// Fields of StatusResponse in MPD's order, like MPD the bitrate is sent between
+/// elapsed and duration. Extensions are flattened last, with their own Serialize impl.
+#[derive(Serialize)]
+struct OrderedStatus<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<i32>,
+    repeat: bool,
+    random: bool,
+    single: bool,
+    consume: bool,
+    playlist: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playlistlength: Option<usize>,
+    state: &'a PlaybackStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    song: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    songid: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bitrate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updating_db: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nextsong: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nextsongid: Option<usize>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    extensions: Option<&'a StatusExtensions>,
+}
+
+impl Serialize for StatusResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        let durations = self.durations.as_ref();
+        let audio_info = self.audio_info.as_ref();
+        let playlist_info = self.playlist_info.as_ref();
+        OrderedStatus {
+            volume: self.volume,
+            repeat: self.repeat,
+            random: self.random,
+            single: self.single,
+            // Spotify has no consume mode
+            consume: false,
+            playlist: self.playlist,
+            playlistlength: playlist_info.map(|i| i.playlistlength),
+            state: &self.state,
+            song: playlist_info.and_then(|i| i.song),
+            songid: playlist_info.and_then(|i| i.songid),
+            time: durations.map(StatusDurations::rendered_time),
+            elapsed: durations.map(|d| d.elapsed.as_secs_f64()),
+            bitrate: audio_info.map(|i| i.bitrate),
+            duration: durations.map(|d| d.duration.as_secs_f64()),
+            audio: audio_info.map(|i| i.audio.as_str()),
+            updating_db: self.updating_db,
+            error: self.error.as_deref(),
+            nextsong: playlist_info.and_then(|i| i.nextsong),
+            nextsongid: playlist_info.and_then(|i| i.nextsongid),
+            extensions: self.extensions.as_ref(),
+        }
+        .serialize(serializer)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,29 +114,70 @@ pub struct StatusDurations {
     pub duration: Duration,
 }
 
+impl StatusDurations {
+    /// Legacy time field, as elapsed:duration in whole seconds
+    fn rendered_time(&self) -> String {
+        format![
+            "{}:{}",
+            self.elapsed.as_secs_f64().round(),
+            self.duration.as_secs_f64().round()
+        ]
+    }
+}
+
 impl Serialize for StatusDurations {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
     where
         S: Serializer,
     {
         let mut state = serializer.serialize_struct("durations", 3)?;
-        let rendered_time = format![
-            "{}:{}",
-            self.elapsed.as_secs_f64().round(),
-            self.duration.as_secs_f64().round()
-        ];
-        state.serialize_field("time", &rendered_time)?;
+        state.serialize_field("time", &self.rendered_time())?;
         state.serialize_field("elapsed", &self.elapsed.as_secs_f64())?;
         state.serialize_field("duration", &self.duration.as_secs_f64())?;
         state.end()
     }
 }
 
+/// Song duration, sent as both the legacy Time field in whole seconds and the precise duration
+#[derive(Debug, PartialEq)]
+pub struct SongDuration(pub Duration);
+
+impl Serialize for SongDuration {
+    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("duration", 2)?;
+        state.serialize_field("Time", &self.0.as_secs())?;
+        state.serialize_field("duration", &self.0.as_secs_f64())?;
+        state.end()
+    }
+}
+
+/// Best-effort stream format, as Spotify does not expose it
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct StatusAudioInfo {
+    pub bitrate: u32,
+    pub audio: String,
+}
+
+/// Non-standard fields, only sent if extensions are enabled
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatusExtensions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spotify_context: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spotify_saved: Option<bool>,
+}
+
+/// Queue fields, the current and next songs are omitted if the playing item is not in the queue
 #[derive(Debug, PartialEq, Serialize)]
 pub struct StatusPlaylistInfo {
     pub playlistlength: usize,
-    pub song: usize,
-    pub songid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub song: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub songid: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nextsong: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -66,23 +185,49 @@ pub struct StatusPlaylistInfo {
 }
 
 impl StatusPlaylistInfo {
-    pub fn new(length: usize, current_pos: usize) -> Self {
+    pub fn new(length: usize, current_pos: Option<usize>) -> Self {
         StatusPlaylistInfo {
             playlistlength: length,
             song: current_pos,
-            songid: current_pos + 1,
-            nextsong: Some(current_pos + 1),
-            nextsongid: Some(current_pos + 2),
+            songid: current_pos.map(song_id_for_pos),
+            nextsong: current_pos.map(|pos| pos + 1),
+            nextsongid: current_pos.map(|pos| song_id_for_pos(pos + 1)),
         }
     }
 }
 
+/// Response for the stats command
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatsResponse {
+    pub uptime: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spotify_product: Option<String>,
+}
+
+/// Response for the update and rescan commands
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UpdateResponse {
+    pub updating_db: usize,
+}
+
 #[derive(Debug, PartialEq, Serialize)]
 pub struct VolumeResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub volume: Option<u32>,
 }
 
+/// Song ids are derived from queue positions, as the queue is a Spotify context whose
+/// items have no id of their own: the id of an item is its position plus one, MPD
+/// reserving id 0. This holds as long as contexts are only replaced, never edited.
+pub fn song_id_for_pos(pos: usize) -> usize {
+    pos + 1
+}
+
+/// Reverse of song_id_for_pos, None for the reserved id 0
+pub fn song_pos_for_id(id: usize) -> Option<usize> {
+    id.checked_sub(1)
+}
+
 /// Response for the currentsong command
 #[derive(Debug, PartialEq, Serialize)]
 #[serde(rename_all = "PascalCase")]
@@ -93,14 +238,61 @@ pub struct SongResponse {
     pub album: String,
     pub title: String,
     pub date: Option<u32>,
-    pub pos: usize, // First item of playlist is 0
-    pub id: usize,  // First item of playlist is 1
-    #[serde(rename = "duration")]
-    pub duration: f64,
+    // Both are omitted when the song is not found in the playlist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pos: Option<usize>, // First item of playlist is 0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>, // First item of playlist is 1
+    #[serde(flatten)]
+    pub duration: SongDuration,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub disc: Option<usize>,
+    // Extension tags, as Spotify has no MusicBrainz ids
+    #[serde(rename = "X-SPOTIFY-ID", skip_serializing_if = "Option::is_none")]
+    pub spotify_id: Option<String>,
+    #[serde(rename = "ISRC", skip_serializing_if = "Option::is_none")]
+    pub isrc: Option<String>,
+}
+
+/// Compact queue entry for the plchangesposid command, without song metadata
+#[derive(Debug, PartialEq, Serialize)]
+pub struct QueuePosIdResponse {
+    pub cpos: usize,
+    #[serde(rename = "Id")]
+    pub id: usize, // First item of playlist is 1
+}
+
+/// Directory entry for the listall and listallinfo commands
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DirectoryResponse {
+    pub directory: Path,
+    #[serde(rename = "Last-Modified", skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+/// File entry for the listall command
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FileResponse {
+    pub file: Path,
+}
+
+/// Response for the auth command, auth_url is set if the user needs to authenticate
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AuthStatusResponse {
+    pub authenticated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_url: Option<String>,
+}
+
+/// Response for the config command, with extra fields about the cache
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ConfigResponse {
+    pub music_directory: String,
+    pub cache_path: String,
+    pub artwork_cache_bytes: u64,
+    pub artwork_cache_limit: u64,
 }
 
 /// Response for the outputs command
@@ -7,7 +7,10 @@ use std::str::FromStr;
 use strum::{AsRefStr, EnumString};
 
 const SEPARATOR: char = '/';
-const INTERNAL_PREFIX: &str = "internal";
+pub const INTERNAL_PREFIX: &str = "internal";
+const SPOTIFY_URI_PREFIX: &str = "spotify:";
+const SPOTIFY_URI_SEPARATOR: char = ':';
+const SPOTIFY_URL_PREFIX: &str = "https://open.spotify.com/";
 
 #[derive(Debug, Eq, PartialEq, EnumString, AsRefStr, Clone)]
 #[strum(serialize_all = "lowercase")]
@@ -29,24 +32,47 @@ impl FromStr for Path {
     type Err = InputError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(uri) = s.strip_prefix(SPOTIFY_URI_PREFIX) {
+            return parse_items(uri.split(SPOTIFY_URI_SEPARATOR), s).map(Internal);
+        }
+        if let Some(url) = s.strip_prefix(SPOTIFY_URL_PREFIX) {
+            let url_path = url.split('?').next().unwrap_or_default();
+            return parse_items(url_path.split(SEPARATOR), s).map(Internal);
+        }
+
         let mut tokens = s.split(SEPARATOR);
         match tokens.next() {
             None | Some("") => Ok(Empty),
-            Some(INTERNAL_PREFIX) => {
-                let mut items = vec![];
-                while let Some(Ok(item_type)) = tokens.next().map(ItemType::from_str) {
-                    match tokens.next() {
-                        None | Some("") => {}
-                        Some(id) => items.push((item_type, id.to_string())),
-                    }
-                }
-                Ok(Internal(items))
-            }
+            Some(INTERNAL_PREFIX) => parse_items(tokens, s).map(Internal),
             Some(other) => Err(InputError::InvalidArgument("path", other.to_string())),
         }
     }
 }
 
+/// Parses a list of alternating item types and ids
+fn parse_items<'a>(
+    tokens: impl Iterator<Item = &'a str>,
+    input: &str,
+) -> Result<Vec<(ItemType, String)>, InputError> {
+    let invalid = || InputError::InvalidArgument("path", input.to_string());
+    let mut tokens = tokens.peekable();
+    let mut items = vec![];
+    while let Some(token) = tokens.next() {
+        // Allow a trailing separator or a trailing empty id
+        if token.is_empty() && tokens.peek().is_none() {
+            break;
+        }
+        let item_type = ItemType::from_str(token).map_err(|_| invalid())?;
+        match tokens.next() {
+            None => {}
+            Some("") if tokens.peek().is_none() => {}
+            Some("") => return Err(invalid()),
+            Some(id) => items.push((item_type, id.to_string())),
+        }
+    }
+    Ok(items)
+}
+
 impl Serialize for Path {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
     where
@@ -134,10 +160,71 @@ mod tests {
                 "internal/album/4IOXEu8EgItKI8J9JDaEr4/track/",
                 Internal(vec![(Album, "4IOXEu8EgItKI8J9JDaEr4".to_string())]),
             ),
+            (
+                "internal/album/4IOXEu8EgItKI8J9JDaEr4/track",
+                Internal(vec![(Album, "4IOXEu8EgItKI8J9JDaEr4".to_string())]),
+            ),
+            // Trailing separator
+            (
+                "internal/album/4IOXEu8EgItKI8J9JDaEr4/",
+                Internal(vec![(Album, "4IOXEu8EgItKI8J9JDaEr4".to_string())]),
+            ),
+            ("internal/", Internal(vec![])),
         ];
 
         for (text, variant) in cases {
             assert_eq!(variant, Path::from_str(text).expect("Parsing error"));
         }
     }
+
+    #[test]
+    fn test_unmarshall_spotify_links() {
+        let cases = vec![
+            (
+                "spotify:album:4IOXEu8EgItKI8J9JDaEr4",
+                "internal/album/4IOXEu8EgItKI8J9JDaEr4",
+            ),
+            (
+                "spotify:track:5fQP3T652SI6zdDaEtgwOd",
+                "internal/track/5fQP3T652SI6zdDaEtgwOd",
+            ),
+            (
+                "https://open.spotify.com/album/4IOXEu8EgItKI8J9JDaEr4",
+                "internal/album/4IOXEu8EgItKI8J9JDaEr4",
+            ),
+            (
+                "https://open.spotify.com/episode/5fQP3T652SI6zdDaEtgwOd?si=a1b2c3",
+                "internal/episode/5fQP3T652SI6zdDaEtgwOd",
+            ),
+        ];
+
+        for (text, normalized) in cases {
+            let path = Path::from_str(text).expect("Parsing error");
+            assert_eq!(normalized, &path.to_string());
+            assert_eq!(path, Path::from_str(normalized).expect("Parsing error"));
+        }
+    }
+
+    #[test]
+    fn test_unmarshall_errors() {
+        let cases = vec![
+            "unknown/album/4IOXEu8EgItKI8J9JDaEr4",
+            "internal/album/4IOXEu8EgItKI8J9JDaEr4/bogus/5fQP3T652SI6zdDaEtgwOd",
+            "internal/bogus/4IOXEu8EgItKI8J9JDaEr4/track/5fQP3T652SI6zdDaEtgwOd",
+            "internal/album//track/5fQP3T652SI6zdDaEtgwOd",
+            "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+            "https://open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M",
+        ];
+
+        for text in cases {
+            assert!(
+                matches!(
+                    Path::from_str(text),
+                    Err(InputError::InvalidArgument("path", _))
+                ),
+                "{} should not parse",
+                text
+            );
+        }
+    }
 }
@@ -4,11 +4,24 @@ use thiserror::Error;
 
 /// Custom serializer for the MPD protocol
 pub fn to_string<T>(value: &T) -> Result<String, SerializerError>
+where
+    T: Serialize,
+{
+    to_string_without(value, vec![])
+}
+
+/// Custom serializer for the MPD protocol, omitting the fields with the given keys
+pub fn to_string_without<T>(
+    value: &T,
+    skipped: Vec<&'static str>,
+) -> Result<String, SerializerError>
 where
     T: Serialize,
 {
     let mut serializer = Serializer {
         output: String::new(),
+        skipped,
+        skip_value: false,
     };
     value.serialize(&mut serializer)?;
     Ok(serializer.output)
@@ -30,6 +43,9 @@ impl serde::ser::Error for SerializerError {
 
 pub struct Serializer {
     output: String,
+    skipped: Vec<&'static str>,
+    // Set when the key of the current map entry is skipped
+    skip_value: bool,
 }
 
 impl<'a> ser::Serializer for &'a mut Serializer {
@@ -224,6 +240,9 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     where
         T: Serialize,
     {
+        if self.skipped.contains(&key) {
+            return Ok(());
+        }
         key.serialize(&mut **self)?;
         self.output += ": ";
         value.serialize(&mut **self)?;
@@ -248,13 +267,23 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
     where
         T: Serialize,
     {
-        key.serialize(&mut **self)
+        let start = self.output.len();
+        key.serialize(&mut **self)?;
+        self.skip_value = self.skipped.contains(&&self.output[start..]);
+        if self.skip_value {
+            self.output.truncate(start);
+        }
+        Ok(())
     }
 
     fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: Serialize,
     {
+        if self.skip_value {
+            self.skip_value = false;
+            return Ok(());
+        }
         self.output += ": ";
         let ret = value.serialize(&mut **self);
         self.output += "\n";
@@ -354,8 +383,12 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mpd_protocol::{PlaybackStatus, StatusDurations, VolumeResponse};
+    use crate::mpd_protocol::{
+        AuthStatusResponse, OutputData, Path, PlaybackStatus, QueuePosIdResponse, SongDuration,
+        SongResponse, StatusAudioInfo, StatusDurations, VolumeResponse,
+    };
     use serde::Serialize;
+    use std::collections::BTreeMap;
     use std::time::Duration;
 
     #[derive(Debug, PartialEq, Serialize)]
@@ -418,4 +451,89 @@ mod tests {
             "time: 4:7\nelapsed: 4.444\nduration: 6.666\n".to_string()
         );
     }
+
+    #[test]
+    fn test_queue_pos_ids() {
+        let mut data = OutputData::empty();
+        for pos in 0..3 {
+            data.push(QueuePosIdResponse {
+                cpos: pos,
+                id: pos + 1,
+            });
+        }
+        let rendered = to_string(&data).expect("Serializer error");
+        assert_eq!(6, rendered.lines().count());
+        assert_eq!("cpos: 0\nId: 1\ncpos: 1\nId: 2\ncpos: 2\nId: 3\n", rendered);
+    }
+
+    #[test]
+    fn test_audio_info() {
+        assert_eq!(
+            to_string(&StatusAudioInfo {
+                bitrate: 320,
+                audio: "44100:16:2".to_string(),
+            })
+            .expect("Serializer error"),
+            "bitrate: 320\naudio: 44100:16:2\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_auth_status() {
+        assert_eq!(
+            to_string(&AuthStatusResponse {
+                authenticated: true,
+                auth_url: None,
+            })
+            .expect("Serializer error"),
+            "authenticated: 1\n".to_string()
+        );
+        assert_eq!(
+            to_string(&AuthStatusResponse {
+                authenticated: false,
+                auth_url: Some("https://accounts.spotify.com/authorize".to_string()),
+            })
+            .expect("Serializer error"),
+            "authenticated: 0\nauth_url: https://accounts.spotify.com/authorize\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_song_duration() {
+        assert_eq!(
+            to_string(&SongDuration(Duration::from_secs_f64(200.5))).expect("Serializer error"),
+            "Time: 200\nduration: 200.5\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_skipped_keys() {
+        let song = SongResponse {
+            file: Path::for_track("album", "track"),
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            title: "Title".to_string(),
+            date: None,
+            pos: Some(0),
+            id: Some(1),
+            duration: SongDuration(Duration::from_secs(60)),
+            track: Some(2),
+            disc: Some(1),
+            spotify_id: None,
+            isrc: None,
+        };
+        let output = to_string_without(&song, vec!["Track"]).expect("Serializer error");
+        assert!(!output.contains("Track:"), "Unexpected output {}", output);
+        assert!(output.contains("Title: Title\n"));
+        assert!(output.ends_with("Time: 60\nduration: 60\nDisc: 1\n"));
+
+        // Keys of flattened fields are serialized as map entries
+        let mut map = BTreeMap::new();
+        map.insert("Disc", 1);
+        map.insert("Track", 2);
+        assert_eq!(
+            to_string_without(&map, vec!["Track"]).expect("Serializer error"),
+            "Disc: 1\n".to_string()
+        );
+    }
 }
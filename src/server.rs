@@ -0,0 +1,266 @@
+use crate::handlers::artwork::ArtworkHandler;
+use crate::handlers::aspotify::{AuthStatus, SpotifyHandler};
+use crate::handlers::client::{build_aspotify_client, HandlerClient, MissingCredential};
+use crate::listeners::http::listener::HttpListener;
+use crate::listeners::http::responses::GenericError;
+use crate::listeners::mpd::MpdListener;
+use crate::mpd_protocol::HandlerError;
+use crate::util::{IdleBus, Settings};
+use aspotify::Client;
+use config::ConfigError;
+use log::debug;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("Cannot read settings: {0}")]
+    ReadSettings(ConfigError),
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(ConfigError),
+    #[error(transparent)]
+    Credentials(#[from] MissingCredential),
+    #[error("Cannot bind {0} listener on {1}: {2}")]
+    Bind(&'static str, String, std::io::Error),
+    #[error("Cannot load TLS certificate {0}: {1}")]
+    Tls(String, GenericError),
+}
+
+/// Assembles the Spotify and artwork handlers behind the MPD and HTTP listeners.
+///
+/// Settings are read from the environment and the Spotify client is built from
+/// its credentials, unless they are provided to the builder:
+///
+/// ```
+/// use aspotify::{Client, ClientCredentials};
+/// use config::Config;
+/// use mpdify::server::MpdifyServer;
+/// use mpdify::util::Settings;
+/// use std::sync::Arc;
+///
+/// let cache_path = std::env::temp_dir().join("mpdify-doctest");
+/// let mut config = Config::new();
+/// config.set("cache_path", cache_path.to_str()).unwrap();
+/// let client = Client::new(ClientCredentials {
+///     id: "client_id".to_string(),
+///     secret: "client_secret".to_string(),
+/// });
+///
+/// let server = MpdifyServer::builder()
+///     .with_settings(Settings::with(config).unwrap())
+///     .with_spotify(Arc::new(client))
+///     .with_artwork(false)
+///     .build()
+///     .expect("Cannot build server");
+/// // server.run().await binds the listeners then spawns them with the handlers,
+/// // it returns once they all exit, or right away if a listener cannot bind
+/// ```
+pub struct MpdifyServer {
+    settings: Settings,
+    client: Arc<Client>,
+    artwork: bool,
+}
+
+pub struct MpdifyServerBuilder {
+    settings: Option<Settings>,
+    client: Option<Arc<Client>>,
+    artwork: Option<bool>,
+}
+
+impl MpdifyServer {
+    pub fn builder() -> MpdifyServerBuilder {
+        MpdifyServerBuilder {
+            settings: None,
+            client: None,
+            artwork: None,
+        }
+    }
+
+    /// Checks a Spotify account is authenticated, without starting the listeners
+    pub async fn check_auth(&self) -> Result<(), HandlerError> {
+        let mut auth = AuthStatus::new(&self.settings, self.client.clone()).await;
+        auth.check().await.map(|_| ())
+    }
+
+    /// Binds the listeners before running anything, so that startup fails if one cannot
+    pub async fn run(self) -> Result<(), ServerError> {
+        let settings = self.settings;
+        let mut handlers = HandlerClient::default();
+        let idle_bus = IdleBus::with_capacity(settings.idle_bus_capacity);
+        let mut tasks = vec![];
+
+        let (mut spotify, spotify_tx) =
+            SpotifyHandler::new(&settings, self.client.clone(), idle_bus.clone()).await;
+        handlers.add(spotify_tx);
+        let authenticated = spotify.authenticated();
+
+        let artwork = match self.artwork {
+            true => {
+                let (artwork, artwork_tx) = ArtworkHandler::new(&settings, self.client).await;
+                handlers.add(artwork_tx);
+                Some(artwork)
+            }
+            false => None,
+        };
+
+        let mut mpd = MpdListener::new(&settings, handlers.clone(), idle_bus.clone()).await?;
+        let http = match settings.enable_http {
+            true => Some(HttpListener::new(
+                &settings,
+                handlers,
+                idle_bus,
+                mpd.connections(),
+                authenticated,
+            )?),
+            false => None,
+        };
+
+        for _ in 1..settings.spotify_workers {
+            let mut worker = spotify.clone();
+            tasks.push(tokio::spawn(async move { worker.run().await }));
+        }
+        tasks.push(tokio::spawn(async move { spotify.run().await }));
+        if let Some(mut artwork) = artwork {
+            tasks.push(tokio::spawn(async move { artwork.run().await }));
+        }
+        if let Some(mut http) = http {
+            tasks.push(tokio::spawn(async move { http.run().await }));
+        }
+        tasks.push(tokio::spawn(async move { mpd.run().await }));
+
+        futures::future::join_all(tasks).await;
+        Ok(())
+    }
+}
+
+impl MpdifyServerBuilder {
+    /// Uses the given settings instead of reading them from the environment
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Uses the given client instead of building one from the environment
+    pub fn with_spotify(mut self, client: Arc<Client>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Serves album art through the albumart and readpicture commands,
+    /// overriding the enable_artwork setting
+    pub fn with_artwork(mut self, enabled: bool) -> Self {
+        self.artwork = Some(enabled);
+        self
+    }
+
+    /// Validates the settings, creating the cache directories if needed
+    pub fn build(self) -> Result<MpdifyServer, ServerError> {
+        let settings = match self.settings {
+            Some(settings) => settings,
+            None => Settings::new().map_err(ServerError::ReadSettings)?,
+        };
+        settings.validate().map_err(ServerError::InvalidSettings)?;
+        debug!["Current settings: {:?}", settings];
+
+        let client = match self.client {
+            Some(client) => client,
+            None => build_aspotify_client(settings.spotify_credentials())?,
+        };
+        Ok(MpdifyServer {
+            client,
+            artwork: self.artwork.unwrap_or(settings.enable_artwork),
+            settings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aspotify::ClientCredentials;
+    use config::Config;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    fn test_config(enable_artwork: bool) -> Config {
+        let cache_path = std::env::temp_dir().join("mpdify-server-test");
+        let mut config = Config::new();
+        config.set("cache_path", cache_path.to_str()).unwrap();
+        config.set("enable_artwork", enable_artwork).unwrap();
+        config
+    }
+
+    fn test_client() -> Arc<Client> {
+        Arc::new(Client::new(ClientCredentials {
+            id: String::new(),
+            secret: String::new(),
+        }))
+    }
+
+    fn build_server(enable_artwork: bool, with_artwork: Option<bool>) -> MpdifyServer {
+        let mut builder = MpdifyServer::builder()
+            .with_settings(Settings::with(test_config(enable_artwork)).unwrap())
+            .with_spotify(test_client());
+        if let Some(enabled) = with_artwork {
+            builder = builder.with_artwork(enabled);
+        }
+        builder.build().expect("Cannot build server")
+    }
+
+    #[test]
+    fn it_disables_artwork_from_settings() {
+        assert!(build_server(true, None).artwork);
+        assert!(!build_server(false, None).artwork);
+        assert!(build_server(false, Some(true)).artwork);
+        assert!(!build_server(true, Some(false)).artwork);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_serve_artwork_when_disabled() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Cannot find a free port")
+            .port();
+        let mut config = test_config(false);
+        config.set("enable_http", false).unwrap();
+        config.set("bind_address", "127.0.0.1").unwrap();
+        config.set("mpd_port", port as i64).unwrap();
+        let server = MpdifyServer::builder()
+            .with_settings(Settings::with(config).unwrap())
+            .with_spotify(test_client())
+            .build()
+            .expect("Cannot build server");
+        tokio::spawn(async move { server.run().await.expect("Cannot run server") });
+
+        // The listener binds once the server runs
+        let mut stream = None;
+        for _ in 0..100 {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        let mut lines = BufReader::new(stream.expect("Cannot connect")).lines();
+        let greeting = lines.next_line().await.unwrap().unwrap();
+        assert!(greeting.starts_with("OK MPD "), "{}", greeting);
+
+        let command = "albumart internal/album/4IOXEu8EgItKI8J9JDaEr4 0\n";
+        lines.get_mut().write_all(command.as_bytes()).await.unwrap();
+        let response = timeout(Duration::from_secs(1), lines.next_line())
+            .await
+            .expect("No response")
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("ACK "), "{}", response);
+        assert!(
+            response.ends_with("unsupported command: albumart"),
+            "{}",
+            response
+        );
+    }
+}
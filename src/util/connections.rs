@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Counts active MPD connections, optionally capped to a maximum
+pub struct ConnectionTracker {
+    active: AtomicUsize,
+    max: Option<usize>,
+}
+
+/// Holds a connection slot, released when dropped
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl ConnectionTracker {
+    #[must_use]
+    pub fn new(max: Option<usize>) -> Arc<ConnectionTracker> {
+        Arc::new(ConnectionTracker {
+            active: AtomicUsize::new(0),
+            max,
+        })
+    }
+
+    /// Returns the number of active connections
+    pub fn count(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Reserves a slot for a new connection, returns None if the maximum is reached
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                if active < max {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ConnectionGuard {
+                tracker: self.clone(),
+            })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_counts_connections() {
+        let tracker = ConnectionTracker::new(None);
+        let first = tracker.try_acquire();
+        let second = tracker.try_acquire();
+        assert!(first.is_some() && second.is_some());
+        assert_eq!(2, tracker.count());
+
+        drop(first);
+        assert_eq!(1, tracker.count());
+        drop(second);
+        assert_eq!(0, tracker.count());
+    }
+
+    #[test]
+    fn it_caps_connections() {
+        let tracker = ConnectionTracker::new(Some(1));
+        let first = tracker.try_acquire();
+        assert!(first.is_some());
+        assert!(tracker.try_acquire().is_none());
+        assert_eq!(1, tracker.count());
+
+        drop(first);
+        assert!(tracker.try_acquire().is_some());
+    }
+}
@@ -1,7 +1,9 @@
 use crate::mpd_protocol::IdleSubsystem;
+use enumset::EnumSet;
 use log::debug;
-use std::sync::Arc;
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
 pub type IdleMessages = broadcast::Receiver<IdleMessage>;
@@ -10,17 +12,44 @@ pub type IdleMessages = broadcast::Receiver<IdleMessage>;
 pub struct IdleMessage {
     pub what: IdleSubsystem,
     pub when: Instant,
+    // Increases with each message, for HTTP clients to resume from
+    pub seq: u64,
 }
 
 pub struct IdleBus {
     channel: broadcast::Sender<IdleMessage>,
+    history: Mutex<IdleHistory>,
+    // Clients without a subscription can ask for updates to continue until then
+    warm_until: Mutex<Option<Instant>>,
+}
+
+/// Latest messages, for HTTP clients to catch up on changes between polls
+struct IdleHistory {
+    capacity: usize,
+    last_seq: u64,
+    messages: VecDeque<IdleMessage>,
 }
 
 impl IdleBus {
     #[must_use]
     pub fn new() -> Arc<IdleBus> {
-        let (channel, _) = broadcast::channel(16);
-        Arc::new(IdleBus { channel })
+        IdleBus::with_capacity(16)
+    }
+
+    /// Creates a bus holding up to `capacity` messages for each subscriber,
+    /// slower subscribers will lag and miss older messages
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Arc<IdleBus> {
+        let (channel, _) = broadcast::channel(capacity);
+        Arc::new(IdleBus {
+            channel,
+            history: Mutex::new(IdleHistory {
+                capacity,
+                last_seq: 0,
+                messages: VecDeque::with_capacity(capacity),
+            }),
+            warm_until: Mutex::new(None),
+        })
     }
 
     /// Returns a channel for notifications, that can be safely dropped
@@ -28,18 +57,135 @@ impl IdleBus {
         self.channel.subscribe()
     }
 
-    /// Returns true if at least one client is subscribed to updates
+    /// Returns true if at least one client is subscribed to updates,
+    /// or if a client asked to keep them warm
     pub fn has_subscribers(&self) -> bool {
-        self.channel.receiver_count() > 0
+        if self.subscriber_count() > 0 {
+            return true;
+        }
+        let warm_until = self.warm_until.lock().unwrap_or_else(|e| e.into_inner());
+        matches!(*warm_until, Some(until) if until > Instant::now())
+    }
+
+    /// Registers interest in updates for the given duration, for clients
+    /// polling the state without subscribing, extends any previous request
+    pub fn keep_warm(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut warm_until = self.warm_until.lock().unwrap_or_else(|e| e.into_inner());
+        if !matches!(*warm_until, Some(previous) if previous >= until) {
+            *warm_until = Some(until);
+        }
+    }
+
+    /// Number of live subscriptions, dropped channels are not counted
+    pub fn subscriber_count(&self) -> usize {
+        self.channel.receiver_count()
     }
 
     /// Send a notification with the current timestamp,
     /// ignores channel errors caused by no subscriber
     pub fn notify(&self, system: IdleSubsystem) {
         debug!["Notifying change in {:?}", system];
-        let _ = self.channel.send(IdleMessage {
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.last_seq += 1;
+        let message = IdleMessage {
             what: system,
             when: Instant::now(),
-        });
+            seq: history.last_seq,
+        };
+        if history.messages.len() >= history.capacity {
+            history.messages.pop_front();
+        }
+        history.messages.push_back(message);
+        // Sent while holding the lock, for subscribers to receive messages in seq order
+        let _ = self.channel.send(message);
+    }
+
+    /// Sequence number of the latest message
+    pub fn last_seq(&self) -> u64 {
+        self.history
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_seq
+    }
+
+    /// Returns the latest sequence number and the subsystems changed after `since`.
+    /// If some of these changes are not in the history anymore, or `since` is unknown,
+    /// all subsystems are reported as changed.
+    pub fn changes_since(&self, since: u64) -> (u64, EnumSet<IdleSubsystem>) {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = history
+            .messages
+            .front()
+            .map_or(history.last_seq + 1, |m| m.seq);
+        let changed = match since {
+            since if since == history.last_seq => EnumSet::empty(),
+            since if since > history.last_seq || since + 1 < oldest => EnumSet::all(),
+            since => history
+                .messages
+                .iter()
+                .filter(|m| m.seq > since)
+                .map(|m| m.what)
+                .collect(),
+        };
+        (history.last_seq, changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_changes_between_polls() {
+        let bus = IdleBus::with_capacity(4);
+        let (cursor, changed) = bus.changes_since(0);
+        assert_eq!((0, EnumSet::empty()), (cursor, changed));
+
+        // Changes happening between two polls are returned by the next one
+        bus.notify(IdleSubsystem::Player);
+        bus.notify(IdleSubsystem::Mixer);
+        let (cursor, changed) = bus.changes_since(cursor);
+        assert_eq!(2, cursor);
+        assert_eq!(IdleSubsystem::Player | IdleSubsystem::Mixer, changed);
+
+        bus.notify(IdleSubsystem::Options);
+        assert_eq!(
+            (3, EnumSet::only(IdleSubsystem::Options)),
+            bus.changes_since(cursor)
+        );
+        assert_eq!((3, EnumSet::empty()), bus.changes_since(3));
+    }
+
+    #[test]
+    fn it_keeps_warm_without_subscribers() {
+        let bus = IdleBus::new();
+        assert!(!bus.has_subscribers());
+
+        bus.keep_warm(Duration::from_secs(60));
+        bus.keep_warm(Duration::default());
+        assert!(bus.has_subscribers());
+        assert_eq!(0, bus.subscriber_count());
+
+        let bus = IdleBus::new();
+        bus.keep_warm(Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!bus.has_subscribers());
+    }
+
+    #[test]
+    fn it_reports_everything_for_lost_changes() {
+        let bus = IdleBus::with_capacity(2);
+        for _ in 0..3 {
+            bus.notify(IdleSubsystem::Player);
+        }
+        assert_eq!(
+            (3, EnumSet::only(IdleSubsystem::Player)),
+            bus.changes_since(1)
+        );
+        assert_eq!((3, EnumSet::all()), bus.changes_since(0));
+
+        // Cursors from before a restart are unknown
+        assert_eq!((3, EnumSet::all()), bus.changes_since(42));
     }
 }
@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+
+/// Holds the error of the last failed playback command, reported in status until cleared
+#[derive(Default)]
+pub struct LastError {
+    message: Mutex<Option<String>>,
+}
+
+impl LastError {
+    pub fn record(&self, message: String) {
+        if let Ok(mut current) = self.message.lock() {
+            *current = Some(message);
+        }
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.message.lock().ok().and_then(|current| current.clone())
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut current) = self.message.lock() {
+            *current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_the_last_error_until_cleared() {
+        let last_error = LastError::default();
+        assert_eq!(None, last_error.get());
+
+        last_error.record("first".to_string());
+        last_error.record("second".to_string());
+        assert_eq!(Some("second".to_string()), last_error.get());
+
+        last_error.clear();
+        assert_eq!(None, last_error.get());
+    }
+}
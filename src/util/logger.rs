@@ -0,0 +1,26 @@
+use crate::util::{LogFormat, Settings};
+use serde_json::json;
+use std::io::Write;
+
+/// Sets up logging as configured, RUST_LOG filters take precedence over log_level
+pub fn init_logger(settings: &Settings) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Some(level) = settings.log_level {
+        builder.filter_level(level);
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if settings.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
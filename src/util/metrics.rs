@@ -0,0 +1,177 @@
+use crate::mpd_protocol::Command;
+use crate::util::ConnectionTracker;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Process-wide counters, exposed in the Prometheus text format
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    spotify_requests: AtomicU64,
+    spotify_errors: AtomicU64,
+    artwork_hits: AtomicU64,
+    artwork_misses: AtomicU64,
+    artwork_disk_bytes: AtomicU64,
+    context_hits: AtomicU64,
+    context_misses: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            commands: Mutex::new(BTreeMap::new()),
+            spotify_requests: AtomicU64::new(0),
+            spotify_errors: AtomicU64::new(0),
+            artwork_hits: AtomicU64::new(0),
+            artwork_misses: AtomicU64::new(0),
+            artwork_disk_bytes: AtomicU64::new(0),
+            context_hits: AtomicU64::new(0),
+            context_misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn command(&self, command: &Command) {
+        if let Ok(mut commands) = self.commands.lock() {
+            *commands.entry(command.into()).or_insert(0) += 1;
+        }
+    }
+
+    /// Counts a request to the Spotify API, and its failure if any
+    pub fn spotify_request(&self, failed: bool) {
+        self.spotify_requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.spotify_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn artwork_cache(&self, hit: bool) {
+        match hit {
+            true => self.artwork_hits.fetch_add(1, Ordering::Relaxed),
+            false => self.artwork_misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Sets the size of the artwork cache directory, as found on startup
+    pub fn artwork_disk_usage(&self, bytes: u64) {
+        self.artwork_disk_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts an image written to the artwork cache directory
+    pub fn artwork_written(&self, bytes: u64) {
+        self.artwork_disk_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn artwork_disk_bytes(&self) -> u64 {
+        self.artwork_disk_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn context_cache(&self, hit: bool) {
+        match hit {
+            true => self.context_hits.fetch_add(1, Ordering::Relaxed),
+            false => self.context_misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn render(&self, connections: &ConnectionTracker) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE mpdify_commands_total counter\n");
+        if let Ok(commands) = self.commands.lock() {
+            for (command, count) in commands.iter() {
+                let _ = writeln!(
+                    out,
+                    "mpdify_commands_total{{command=\"{}\"}} {}",
+                    command, count
+                );
+            }
+        }
+
+        render_value(
+            &mut out,
+            "spotify_requests_total",
+            "counter",
+            &[("", &self.spotify_requests)],
+        );
+        render_value(
+            &mut out,
+            "spotify_errors_total",
+            "counter",
+            &[("", &self.spotify_errors)],
+        );
+        render_value(
+            &mut out,
+            "artwork_cache_total",
+            "counter",
+            &[
+                ("{result=\"hit\"}", &self.artwork_hits),
+                ("{result=\"miss\"}", &self.artwork_misses),
+            ],
+        );
+        render_value(
+            &mut out,
+            "artwork_cache_bytes",
+            "gauge",
+            &[("", &self.artwork_disk_bytes)],
+        );
+        render_value(
+            &mut out,
+            "context_cache_total",
+            "counter",
+            &[
+                ("{result=\"hit\"}", &self.context_hits),
+                ("{result=\"miss\"}", &self.context_misses),
+            ],
+        );
+        let _ = writeln!(out, "# TYPE mpdify_connections gauge");
+        let _ = writeln!(out, "mpdify_connections {}", connections.count());
+        out
+    }
+}
+
+fn render_value(out: &mut String, name: &str, kind: &str, values: &[(&str, &AtomicU64)]) {
+    let _ = writeln!(out, "# TYPE mpdify_{} {}", name, kind);
+    for (labels, value) in values {
+        let _ = writeln!(
+            out,
+            "mpdify_{}{} {}",
+            name,
+            labels,
+            value.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_counters() {
+        let metrics = Metrics::new();
+        metrics.command(&Command::Status);
+        metrics.command(&Command::Status);
+        metrics.command(&Command::Ping);
+        metrics.spotify_request(false);
+        metrics.context_cache(true);
+        metrics.context_cache(false);
+        metrics.artwork_disk_usage(1000);
+        metrics.artwork_written(24);
+        let connections = ConnectionTracker::new(None);
+        let _guard = connections.try_acquire();
+
+        let rendered = metrics.render(&connections);
+        assert!(rendered.contains("mpdify_commands_total{command=\"status\"} 2\n"));
+        assert!(rendered.contains("mpdify_commands_total{command=\"ping\"} 1\n"));
+        assert!(rendered.contains("mpdify_spotify_requests_total 1\n"));
+        assert!(rendered.contains("mpdify_spotify_errors_total 0\n"));
+        assert!(rendered.contains("mpdify_context_cache_total{result=\"hit\"} 1\n"));
+        assert!(rendered.contains("mpdify_context_cache_total{result=\"miss\"} 1\n"));
+        assert!(rendered.contains(
+            "# TYPE mpdify_artwork_cache_bytes gauge\nmpdify_artwork_cache_bytes 1024\n"
+        ));
+        assert!(rendered.contains("# TYPE mpdify_connections gauge\nmpdify_connections 1\n"));
+    }
+}
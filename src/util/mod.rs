@@ -1,5 +1,15 @@
+mod connections;
 mod idle;
+mod last_error;
+mod logger;
+mod metrics;
 mod settings;
+mod update_jobs;
 
+pub use connections::*;
 pub use idle::*;
+pub use last_error::*;
+pub use logger::*;
+pub use metrics::*;
 pub use settings::*;
+pub use update_jobs::*;
@@ -1,33 +1,113 @@
-use config::{Config, ConfigError, Environment};
-use serde::Deserialize;
+use aspotify::ClientCredentials;
+use config::{Config, ConfigError, Environment, Source};
+use log::{warn, LevelFilter};
+use serde::de::{Error, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize, Deserializer};
+use std::fs;
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     mpd_port: u16,
+    pub mpd_max_connections: Option<usize>,
+    pub mpd_extensions: bool,
+    idle_keepalive_secs: Option<u64>,
+    pub enable_http: bool,
     http_port: u16,
     http_host: String,
-    bind_address: IpAddr,
+    pub http_healthz_requires_auth: bool,
+    pub http_max_body_bytes: u64,
+    http_request_timeout_ms: u64,
+    http_keep_warm_seconds: u64,
+    pub http_username: Option<String>,
+    pub http_password: Option<String>,
+    http_tls_cert: Option<String>,
+    http_tls_key: Option<String>,
+    http_static_dir: Option<String>,
+    spotify_client_id: Option<String>,
+    spotify_client_secret: Option<String>,
+    #[serde(deserialize_with = "deserialize_addresses")]
+    bind_address: Vec<IpAddr>,
+    #[serde(default, deserialize_with = "deserialize_optional_addresses")]
+    mpd_bind_address: Option<Vec<IpAddr>>,
+    #[serde(default, deserialize_with = "deserialize_optional_addresses")]
+    http_bind_address: Option<Vec<IpAddr>>,
     cache_path: String,
+    pub enable_artwork: bool,
     artwork_cache_size_mb: u64,
     artwork_chunk_size_kb: u64,
-    pub playback_pool_freq_base_seconds: u64,
-    pub playback_pool_freq_fast_seconds: u64,
+    pub artwork_placeholder: bool,
+    pub artwork_missing_ttl_seconds: u64,
+    artwork_memory_cache_size_mb: u64,
+    artwork_memory_max_image_kb: u64,
+    playback_pool_freq_base_seconds: u64,
+    playback_pool_freq_fast_seconds: u64,
+    playback_pool_freq_base_ms: Option<u64>,
+    playback_pool_freq_fast_ms: Option<u64>,
+    pub library_max_albums: usize,
+    pub artist_context_albums: usize,
+    pub default_device: Option<String>,
+    pub status_audio_format: String,
+    pub status_bitrate_free: u32,
+    pub status_bitrate_premium: u32,
+    pub status_volume_unknown_as_minus_one: bool,
+    pub status_monotonic_elapsed: bool,
+    pub idle_bus_capacity: usize,
+    pub handler_queue_size: usize,
+    pub spotify_workers: usize,
+    #[serde(default, deserialize_with = "deserialize_log_level")]
+    pub log_level: Option<LevelFilter>,
+    pub log_format: LogFormat,
+    settings_strict: bool,
+    #[serde(skip)]
+    unknown_keys: Vec<String>,
 }
 
 impl Settings {
     fn init() -> Result<Config, ConfigError> {
         let mut s = Config::new();
         s.set_default("mpd_port", 6600)?;
+        s.set_default("mpd_extensions", false)?;
+        s.set_default("enable_http", true)?;
         s.set_default("http_port", 6601)?;
         s.set_default("http_host", "localhost")?;
+        s.set_default("http_healthz_requires_auth", false)?;
+        s.set_default("http_max_body_bytes", 65536)?;
+        s.set_default("http_request_timeout_ms", 10000)?;
+        s.set_default("http_keep_warm_seconds", 60)?;
         s.set_default("bind_address", "0.0.0.0")?;
         s.set_default("playback_pool_freq_base_seconds", "15")?;
         s.set_default("playback_pool_freq_fast_seconds", "1")?;
         s.set_default("cache_path", "caches/")?;
+        s.set_default("enable_artwork", true)?;
         s.set_default("artwork_cache_size_mb", 500)?;
         s.set_default("artwork_chunk_size_kb", 128)?; // MPDs default is 8kB
+        s.set_default("artwork_placeholder", false)?;
+        s.set_default("artwork_missing_ttl_seconds", 3600)?;
+        s.set_default("artwork_memory_cache_size_mb", 8)?;
+        s.set_default("artwork_memory_max_image_kb", 512)?;
+        s.set_default("library_max_albums", 200)?;
+        s.set_default("artist_context_albums", 0)?;
+        s.set_default("status_audio_format", "44100:16:2")?;
+        s.set_default("status_bitrate_free", 160)?;
+        s.set_default("status_bitrate_premium", 320)?;
+        s.set_default("status_volume_unknown_as_minus_one", false)?;
+        s.set_default("status_monotonic_elapsed", false)?;
+        s.set_default("idle_bus_capacity", 16)?;
+        s.set_default("handler_queue_size", 16)?;
+        s.set_default("spotify_workers", 1)?;
+        s.set_default("log_format", "pretty")?;
+        s.set_default("settings_strict", false)?;
         Ok(s)
     }
 
@@ -35,32 +115,137 @@ impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let mut s = Settings::init()?;
         s.merge(Environment::with_prefix("mpdify"))?;
-        s.try_into()
+        Settings::build(s)
     }
 
     /// Combines defaults with provided values, for tests
     pub fn with(source: Config) -> Result<Self, ConfigError> {
         let mut s = Settings::init()?;
         s.merge(source)?;
-        s.try_into()
+        Settings::build(s)
+    }
+
+    /// Rejects unknown keys, usually typos, in strict mode.
+    /// Otherwise, they are kept to be reported by validate, once logging is set up.
+    fn build(s: Config) -> Result<Self, ConfigError> {
+        let unknown = unknown_keys(&s)?;
+        let mut settings: Settings = s.try_into()?;
+        if settings.settings_strict && !unknown.is_empty() {
+            return Err(ConfigError::Message(format![
+                "unknown settings: {}",
+                unknown.join(", ")
+            ]));
+        }
+        settings.unknown_keys = unknown;
+        Ok(settings)
     }
 
     pub fn auth_path(&self) -> String {
         format!["http://{}:{}/auth", self.http_host, self.http_port]
     }
 
-    pub fn http_address(&self) -> SocketAddr {
-        SocketAddr::new(self.bind_address, self.http_port)
+    /// HTTP listener addresses, http_bind_address overrides bind_address
+    pub fn http_addresses(&self) -> Vec<SocketAddr> {
+        socket_addresses(&self.http_bind_address, &self.bind_address, self.http_port)
+    }
+
+    /// MPD listener addresses, mpd_bind_address overrides bind_address
+    pub fn mpd_addresses(&self) -> Vec<SocketAddr> {
+        socket_addresses(&self.mpd_bind_address, &self.bind_address, self.mpd_port)
+    }
+
+    /// Sends TCP keepalive probes on idle MPD connections, so that NAT and firewalls
+    /// don't drop them. Probes are below the protocol level, they are never seen by clients,
+    /// but they might not be forwarded by proxies.
+    pub fn idle_keepalive(&self) -> Option<Duration> {
+        self.idle_keepalive_secs.map(Duration::from_secs)
+    }
+
+    /// Spotify application credentials, if both the id and secret are set.
+    /// Otherwise, they are read from the CLIENT_ID and CLIENT_SECRET environment variables.
+    pub fn spotify_credentials(&self) -> Option<ClientCredentials> {
+        match (&self.spotify_client_id, &self.spotify_client_secret) {
+            (Some(id), Some(secret)) => Some(ClientCredentials {
+                id: id.clone(),
+                secret: secret.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn http_request_timeout(&self) -> Duration {
+        Duration::from_millis(self.http_request_timeout_ms)
     }
 
-    pub fn mpd_address(&self) -> SocketAddr {
-        SocketAddr::new(self.bind_address, self.mpd_port)
+    /// Keeps refreshing the playback state for this long after an HTTP command,
+    /// for HTTP clients to get it from the cache like idle MPD clients
+    pub fn http_keep_warm(&self) -> Duration {
+        Duration::from_secs(self.http_keep_warm_seconds)
+    }
+
+    /// Paths to the PEM certificate chain and private key to serve HTTPS with,
+    /// HTTP is served in plaintext if unset
+    pub fn http_tls(&self) -> Option<(&str, &str)> {
+        match (&self.http_tls_cert, &self.http_tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    /// Directory of a web UI to serve on paths not used by the HTTP API
+    pub fn http_static_dir(&self) -> Option<&Path> {
+        self.http_static_dir.as_deref().map(Path::new)
     }
 
     pub fn cache_root_path(&self) -> &Path {
         Path::new(&self.cache_path)
     }
 
+    pub fn artwork_cache_path(&self) -> PathBuf {
+        self.cache_root_path().join("artwork")
+    }
+
+    /// Warns about unknown settings, creates the cache directories if needed
+    /// and checks they are writable
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for key in self.unknown_keys.iter() {
+            warn!["Unknown setting {}", key];
+        }
+        if self.idle_bus_capacity == 0 {
+            return Err(ConfigError::Message(
+                "idle_bus_capacity must be positive".to_string(),
+            ));
+        }
+        if self.handler_queue_size == 0 {
+            return Err(ConfigError::Message(
+                "handler_queue_size must be positive".to_string(),
+            ));
+        }
+        if self.spotify_workers == 0 {
+            return Err(ConfigError::Message(
+                "spotify_workers must be positive".to_string(),
+            ));
+        }
+        if self.http_tls_cert.is_some() != self.http_tls_key.is_some() {
+            return Err(ConfigError::Message(
+                "http_tls_cert and http_tls_key must be set together".to_string(),
+            ));
+        }
+        for path in &[
+            self.cache_root_path().to_path_buf(),
+            self.artwork_cache_path(),
+        ] {
+            check_writable(path).map_err(|err| {
+                ConfigError::Message(format![
+                    "cache_path {} is not writable: {}",
+                    path.display(),
+                    err
+                ])
+            })?;
+        }
+        Ok(())
+    }
+
     pub fn artwork_cache_size(&self) -> u64 {
         self.artwork_cache_size_mb * 1024 * 1024
     }
@@ -68,4 +253,353 @@ impl Settings {
     pub fn artwork_chunk_size(&self) -> u64 {
         self.artwork_chunk_size_kb * 1024
     }
+
+    pub fn artwork_memory_cache_size(&self) -> usize {
+        (self.artwork_memory_cache_size_mb * 1024 * 1024) as usize
+    }
+
+    pub fn artwork_memory_max_image_size(&self) -> usize {
+        (self.artwork_memory_max_image_kb * 1024) as usize
+    }
+
+    /// Playback pooling frequency when idle, millisecond values
+    /// take precedence over the legacy seconds ones
+    pub fn playback_pool_freq_base(&self) -> Duration {
+        match self.playback_pool_freq_base_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => Duration::from_secs(self.playback_pool_freq_base_seconds),
+        }
+    }
+
+    /// Playback pooling frequency after a command, millisecond values
+    /// take precedence over the legacy seconds ones
+    pub fn playback_pool_freq_fast(&self) -> Duration {
+        match self.playback_pool_freq_fast_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => Duration::from_secs(self.playback_pool_freq_fast_seconds),
+        }
+    }
+}
+
+fn check_writable(path: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(path)?;
+    let probe = path.join(".write_test");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+/// Returns the sorted list of keys not matching a Settings field
+fn unknown_keys(s: &Config) -> Result<Vec<String>, ConfigError> {
+    let known = struct_fields::<Settings>();
+    let mut unknown: Vec<String> = s
+        .collect()?
+        .into_keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .collect();
+    unknown.sort();
+    Ok(unknown)
+}
+
+/// Lists the fields of a struct, as known by its Deserialize implementation
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldsDeserializer<'a> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(Self::Error::custom("only structs are supported"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(Self::Error::custom("fields collected"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
+}
+
+fn socket_addresses(
+    specific: &Option<Vec<IpAddr>>,
+    shared: &[IpAddr],
+    port: u16,
+) -> Vec<SocketAddr> {
+    specific
+        .as_deref()
+        .unwrap_or(shared)
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect()
+}
+
+/// Addresses can be provided as a list, or a comma-separated string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Addresses {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl Addresses {
+    fn parse<E: serde::de::Error>(self) -> Result<Vec<IpAddr>, E> {
+        let values = match self {
+            Addresses::List(values) => values,
+            Addresses::Joined(value) => value.split(',').map(String::from).collect(),
+        };
+        let addresses = values
+            .iter()
+            .map(|value| value.trim().parse().map_err(E::custom))
+            .collect::<Result<Vec<IpAddr>, E>>()?;
+        if addresses.is_empty() {
+            return Err(E::custom("bind address list cannot be empty"));
+        }
+        Ok(addresses)
+    }
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(level) => LevelFilter::from_str(&level)
+            .map(Some)
+            .map_err(|_| D::Error::custom(format!["invalid log_level {}", level])),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_addresses<'de, D>(deserializer: D) -> Result<Vec<IpAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Addresses::deserialize(deserializer)?.parse()
+}
+
+fn deserialize_optional_addresses<'de, D>(deserializer: D) -> Result<Option<Vec<IpAddr>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Addresses>::deserialize(deserializer)? {
+        Some(addresses) => addresses.parse().map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_pool_frequencies_in_seconds() {
+        let settings = Settings::with(Config::new()).unwrap();
+        assert_eq!(Duration::from_secs(15), settings.playback_pool_freq_base());
+        assert_eq!(Duration::from_secs(1), settings.playback_pool_freq_fast());
+    }
+
+    #[test]
+    fn it_prefers_pool_frequencies_in_milliseconds() {
+        let mut config = Config::new();
+        config.set("playback_pool_freq_fast_seconds", 2).unwrap();
+        config.set("playback_pool_freq_fast_ms", 250).unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(Duration::from_secs(15), settings.playback_pool_freq_base());
+        assert_eq!(
+            Duration::from_millis(250),
+            settings.playback_pool_freq_fast()
+        );
+    }
+
+    #[test]
+    fn it_creates_cache_directories() {
+        let root = std::env::temp_dir().join(format!["mpdify-settings-{}", std::process::id()]);
+        let mut config = Config::new();
+        config.set("cache_path", root.to_str().unwrap()).unwrap();
+        let settings = Settings::with(config).unwrap();
+
+        settings.validate().expect("Cannot validate settings");
+        assert!(settings.artwork_cache_path().is_dir());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_unwritable_cache_path() {
+        let file =
+            std::env::temp_dir().join(format!["mpdify-settings-file-{}", std::process::id()]);
+        fs::write(&file, b"").unwrap();
+        let mut config = Config::new();
+        config.set("cache_path", file.to_str().unwrap()).unwrap();
+        let settings = Settings::with(config).unwrap();
+
+        match settings.validate() {
+            Err(ConfigError::Message(message)) => assert!(
+                message.starts_with(&format!["cache_path {} is not writable", file.display()]),
+                "Unexpected message {}",
+                message
+            ),
+            other => panic!["Unexpected result {:?}", other],
+        }
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn it_requires_both_tls_files() {
+        let mut config = Config::new();
+        config.set("http_tls_cert", "cert.pem").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(None, settings.http_tls());
+        assert!(settings.validate().is_err());
+
+        let mut config = Config::new();
+        config.set("http_tls_cert", "cert.pem").unwrap();
+        config.set("http_tls_key", "key.pem").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(Some(("cert.pem", "key.pem")), settings.http_tls());
+    }
+
+    #[test]
+    fn it_reports_unknown_keys() {
+        let mut config = Config::new();
+        config.set("mdp_port", 6000).unwrap();
+        config.set("mpd_port", 6000).unwrap();
+        assert!(Settings::with(config).is_ok());
+
+        let mut config = Settings::init().unwrap();
+        config.set("mdp_port", 6000).unwrap();
+        config.set("http_prot", 6000).unwrap();
+        assert_eq!(
+            vec!["http_prot".to_string(), "mdp_port".to_string()],
+            unknown_keys(&config).unwrap()
+        );
+        assert!(unknown_keys(&Settings::init().unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys_in_strict_mode() {
+        let mut config = Config::new();
+        config.set("settings_strict", true).unwrap();
+        config.set("mdp_port", 6000).unwrap();
+        match Settings::with(config) {
+            Err(ConfigError::Message(message)) => assert_eq!("unknown settings: mdp_port", message),
+            other => panic!["Unexpected result {:?}", other],
+        }
+    }
+
+    #[test]
+    fn it_reads_a_single_bind_address() {
+        let mut config = Config::new();
+        config.set("mpd_port", 6600).unwrap();
+        config.set("bind_address", "127.0.0.1").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(
+            vec!["127.0.0.1:6600".parse::<SocketAddr>().unwrap()],
+            settings.mpd_addresses()
+        );
+    }
+
+    #[test]
+    fn it_reads_multiple_bind_addresses() {
+        let mut config = Config::new();
+        config.set("http_port", 6601).unwrap();
+        config.set("bind_address", "127.0.0.1, ::1").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(
+            vec![
+                "127.0.0.1:6601".parse::<SocketAddr>().unwrap(),
+                "[::1]:6601".parse::<SocketAddr>().unwrap(),
+            ],
+            settings.http_addresses()
+        );
+
+        let mut config = Config::new();
+        config
+            .set("bind_address", vec!["127.0.0.1", "::1"])
+            .unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(2, settings.mpd_addresses().len());
+    }
+
+    #[test]
+    fn it_rejects_invalid_bind_addresses() {
+        let mut config = Config::new();
+        config.set("bind_address", "127.0.0.1,localhost").unwrap();
+        assert!(Settings::with(config).is_err());
+    }
+
+    #[test]
+    fn it_overrides_bind_address_by_listener() {
+        let mut config = Config::new();
+        config.set("bind_address", "0.0.0.0").unwrap();
+        config.set("http_bind_address", "127.0.0.1").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(
+            vec!["0.0.0.0:6600".parse::<SocketAddr>().unwrap()],
+            settings.mpd_addresses()
+        );
+        assert_eq!(
+            vec!["127.0.0.1:6601".parse::<SocketAddr>().unwrap()],
+            settings.http_addresses()
+        );
+
+        let mut config = Config::new();
+        config.set("mpd_bind_address", "127.0.0.1,::1").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(2, settings.mpd_addresses().len());
+        assert_eq!(
+            vec!["0.0.0.0:6601".parse::<SocketAddr>().unwrap()],
+            settings.http_addresses()
+        );
+    }
+
+    #[test]
+    fn it_reads_spotify_credentials() {
+        let mut config = Config::new();
+        config.set("spotify_client_id", "client_id").unwrap();
+        let settings = Settings::with(config.clone()).unwrap();
+        assert_eq!(None, settings.spotify_credentials());
+
+        config
+            .set("spotify_client_secret", "client_secret")
+            .unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(
+            Some(ClientCredentials {
+                id: "client_id".to_string(),
+                secret: "client_secret".to_string(),
+            }),
+            settings.spotify_credentials()
+        );
+    }
+
+    #[test]
+    fn it_reads_log_settings() {
+        let settings = Settings::with(Config::new()).unwrap();
+        assert_eq!(None, settings.log_level);
+        assert_eq!(LogFormat::Pretty, settings.log_format);
+
+        let mut config = Config::new();
+        config.set("log_level", "debug").unwrap();
+        config.set("log_format", "json").unwrap();
+        let settings = Settings::with(config).unwrap();
+        assert_eq!(Some(LevelFilter::Debug), settings.log_level);
+        assert_eq!(LogFormat::Json, settings.log_format);
+
+        let mut config = Config::new();
+        config.set("log_level", "loud").unwrap();
+        assert!(Settings::with(config).is_err());
+    }
 }
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks the cache refreshes started by the update command, the running one
+/// being reported in status as updating_db until it completes
+#[derive(Default)]
+pub struct UpdateJobs {
+    last_id: AtomicUsize,
+    running: AtomicUsize,
+}
+
+impl UpdateJobs {
+    /// Starts a new job, replacing any running one, and returns its id
+    pub fn start(&self) -> usize {
+        let id = self.last_id.fetch_add(1, Ordering::AcqRel) + 1;
+        self.running.store(id, Ordering::Release);
+        id
+    }
+
+    /// Marks a job as completed, unless a newer one was started meanwhile
+    pub fn finish(&self, id: usize) {
+        let _ = self
+            .running
+            .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    pub fn running(&self) -> Option<usize> {
+        match self.running.load(Ordering::Acquire) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_the_running_job() {
+        let jobs = UpdateJobs::default();
+        assert_eq!(None, jobs.running());
+
+        let first = jobs.start();
+        assert_eq!(Some(1), jobs.running());
+        jobs.finish(first);
+        assert_eq!(None, jobs.running());
+
+        // Older jobs completing don't hide the newer one
+        let second = jobs.start();
+        let third = jobs.start();
+        jobs.finish(second);
+        assert_eq!(Some(3), jobs.running());
+        jobs.finish(third);
+        assert_eq!(None, jobs.running());
+    }
+}
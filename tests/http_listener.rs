@@ -0,0 +1,240 @@
+use config::Config;
+use mpdify::handlers::client::HandlerClient;
+use mpdify::listeners::http::listener::HttpListener;
+use mpdify::mpd_protocol::{Command, HandlerError, HandlerInput, HandlerOutput, PlaybackStatus};
+use mpdify::util::{ConnectionTracker, IdleBus, Settings};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn it_executes_commands() {
+    init_logger();
+    let address = init_listener().await;
+
+    let response = reqwest::get(format!["http://{}/command/status", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    let body: serde_json::Value = response.json().await.expect("Invalid json");
+    assert_eq!(json!([{"volume": 20, "state": "pause"}]), body);
+}
+
+#[tokio::test]
+async fn it_returns_lines_as_json_list() {
+    init_logger();
+    let address = init_listener().await;
+
+    let response = reqwest::get(format!["http://{}/command/commands", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    let body: serde_json::Value = response.json().await.expect("Invalid json");
+    assert_eq!(json!(["command: status", "command: commands"]), body);
+}
+
+#[tokio::test]
+async fn it_rejects_unknown_commands() {
+    init_logger();
+    let address = init_listener().await;
+
+    let response = reqwest::get(format!["http://{}/command/unknowncmd", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::BAD_REQUEST, response.status());
+    assert_eq!(
+        "UnknownCommand(\"unknowncmd\")",
+        response.text().await.expect("Invalid body")
+    );
+}
+
+#[tokio::test]
+async fn it_maps_handler_errors_to_status_codes() {
+    init_logger();
+    let address = init_listener().await;
+
+    let response = reqwest::get(format!["http://{}/command/pause", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+    assert_eq!(
+        "unsupported command: pause",
+        response.text().await.expect("Invalid body")
+    );
+
+    let response = reqwest::get(format!["http://{}/command/random/1", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::NOT_IMPLEMENTED, response.status());
+    assert_eq!(
+        "random is not supported by Spotify",
+        response.text().await.expect("Invalid body")
+    );
+}
+
+#[tokio::test]
+async fn it_stops_idling_when_clients_disconnect() {
+    init_logger();
+    let idle_bus = IdleBus::new();
+    let address = init_listener_with(idle_bus.clone()).await;
+
+    let mut client = TcpStream::connect(&address).await.expect("Cannot connect");
+    client
+        .write_all(b"GET /idle HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Cannot send request");
+    wait_for_subscribers(&idle_bus, 1).await;
+
+    // Closing the connection drops the subscription well before the request timeout
+    drop(client);
+    wait_for_subscribers(&idle_bus, 0).await;
+}
+
+#[tokio::test]
+async fn it_keeps_the_playback_warm_for_http_clients() {
+    init_logger();
+    let idle_bus = IdleBus::new();
+    let address = init_listener_with(idle_bus.clone()).await;
+    assert!(!idle_bus.has_subscribers());
+
+    // Status calls register interest, for the playback watcher to keep pooling
+    let response = reqwest::get(format!["http://{}/command/status", address])
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    assert!(idle_bus.has_subscribers());
+    assert_eq!(0, idle_bus.subscriber_count());
+}
+
+async fn wait_for_subscribers(idle_bus: &IdleBus, count: usize) {
+    timeout(Duration::from_secs(5), async {
+        while idle_bus.subscriber_count() != count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Subscriber count not reached");
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn it_serves_https_with_a_self_signed_cert() {
+    init_logger();
+    let fixtures = concat![env!("CARGO_MANIFEST_DIR"), "/tests/fixtures"];
+    let mut config = listener_config();
+    let cert = format!["{}/tls_cert.pem", fixtures];
+    config.set("http_tls_cert", cert.as_str()).unwrap();
+    let key = format!["{}/tls_key.pem", fixtures];
+    config.set("http_tls_key", key.as_str()).unwrap();
+    let address = start_listener(config, IdleBus::new());
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!["https://{}/command/status", address])
+        .send()
+        .await
+        .expect("Request failed");
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+    let body: serde_json::Value = response.json().await.expect("Invalid json");
+    assert_eq!(json!([{"volume": 20, "state": "pause"}]), body);
+
+    // Plaintext requests are not answered
+    let plaintext = reqwest::get(format!["http://{}/healthz", address]).await;
+    assert!(plaintext.is_err());
+}
+
+#[tokio::test]
+async fn it_reports_certificates_it_cannot_load() {
+    init_logger();
+    let mut config = listener_config();
+    config
+        .set("http_tls_cert", "/nonexistent/cert.pem")
+        .unwrap();
+    config.set("http_tls_key", "/nonexistent/key.pem").unwrap();
+    let settings = Settings::with(config).unwrap();
+
+    let (tx, _rx) = mpsc::channel(16);
+    let result = HttpListener::new(
+        &settings,
+        HandlerClient::new(vec![tx]),
+        IdleBus::new(),
+        ConnectionTracker::new(None),
+        Arc::new(AtomicBool::new(true)),
+    );
+    let err = result.err().expect("Listener should not start");
+    assert!(err
+        .to_string()
+        .starts_with("Cannot load TLS certificate /nonexistent/cert.pem: "));
+}
+
+fn init_logger() {
+    let _ = pretty_env_logger::try_init();
+}
+
+async fn init_listener() -> String {
+    init_listener_with(IdleBus::new()).await
+}
+
+async fn init_listener_with(idle_bus: Arc<IdleBus>) -> String {
+    start_listener(listener_config(), idle_bus)
+}
+
+fn listener_config() -> Config {
+    let mut config = Config::new();
+    config.set("http_port", 0).unwrap();
+    config.set("bind_address", "127.0.0.1").unwrap();
+    config.set("http_request_timeout_ms", 60000).unwrap();
+    config
+}
+
+fn start_listener(config: Config, idle_bus: Arc<IdleBus>) -> String {
+    let settings = Settings::with(config).unwrap();
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move { run_handler(rx).await });
+    let mut listener = HttpListener::new(
+        &settings,
+        HandlerClient::new(vec![tx]),
+        idle_bus,
+        ConnectionTracker::new(None),
+        Arc::new(AtomicBool::new(true)),
+    )
+    .expect("Cannot bind HTTP listener");
+    let address = listener.get_address();
+    tokio::spawn(async move { listener.run().await });
+    address
+}
+
+#[derive(Serialize)]
+struct CustomStatus {
+    volume: Option<u32>,
+    state: PlaybackStatus,
+}
+
+async fn run_handler(mut rx: Receiver<HandlerInput>) {
+    while let Some(input) = rx.recv().await {
+        let resp = match input.command {
+            Command::Status => Ok(HandlerOutput::from(CustomStatus {
+                volume: Some(20),
+                state: PlaybackStatus::Pause,
+            })),
+            Command::Commands => Ok(HandlerOutput::Lines(vec![
+                "command: status".to_string(),
+                "command: commands".to_string(),
+            ])),
+            Command::Random(_) => Err(HandlerError::NotImplemented("random".to_string())),
+            _ => Err(HandlerError::Unsupported),
+        };
+        let _ = input.resp.send(resp);
+    }
+}
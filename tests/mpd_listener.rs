@@ -2,7 +2,10 @@ use config::Config;
 use log::{debug, warn};
 use mpdify::handlers::client::HandlerClient;
 use mpdify::listeners::mpd::MpdListener;
-use mpdify::mpd_protocol::{Command, HandlerError, HandlerInput, HandlerOutput, PlaybackStatus};
+use mpdify::mpd_protocol::{
+    Command, HandlerError, HandlerInput, HandlerOutput, IdleSubsystem, Path, PlaybackStatus,
+    SongDuration, SongResponse, UpdateResponse,
+};
 use mpdify::util::{IdleBus, Settings};
 use serde::Serialize;
 use std::sync::atomic::AtomicBool;
@@ -17,7 +20,7 @@ use tokio::time::{timeout, Duration};
 fn test_settings() -> Settings {
     let mut config = Config::new();
     config.set("mpd_port", 0).unwrap();
-    config.set("bind_host", "127.0.0.1").unwrap();
+    config.set("bind_address", "127.0.0.1").unwrap();
     Settings::with(config).unwrap()
 }
 
@@ -42,6 +45,102 @@ async fn it_handles_two_connections() {
     }
 }
 
+#[tokio::test]
+async fn it_reports_addresses_it_cannot_bind() {
+    init_logger();
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let mut config = Config::new();
+    config.set("mpd_port", port as i64).unwrap();
+    config.set("bind_address", "127.0.0.1").unwrap();
+    let settings = Settings::with(config).unwrap();
+
+    match MpdListener::new(&settings, HandlerClient::new(vec![]), IdleBus::new()).await {
+        Ok(_) => panic!["Bound a port already in use"],
+        Err(err) => assert!(
+            err.to_string()
+                .starts_with(&format!["Cannot bind MPD listener on 127.0.0.1:{}: ", port]),
+            "{}",
+            err
+        ),
+    }
+}
+
+#[tokio::test]
+async fn it_listens_on_multiple_addresses() {
+    init_logger();
+    let mut config = Config::new();
+    config.set("mpd_port", 0).unwrap();
+    config.set("bind_address", "127.0.0.1,127.0.0.1").unwrap();
+    let settings = Settings::with(config).unwrap();
+
+    let mut listener = MpdListener::new(&settings, HandlerClient::new(vec![]), IdleBus::new())
+        .await
+        .expect("Cannot bind MPD listener");
+    let addresses = listener
+        .get_addresses()
+        .expect("Cannot get server addresses");
+    assert_eq!(2, addresses.len());
+    tokio::spawn(async move { listener.run().await });
+
+    for address in addresses {
+        let mut client = Client::new(address).await;
+        client.send_command("ping").await;
+        client.assert_response("OK\n".to_string()).await;
+    }
+}
+
+#[tokio::test]
+async fn it_caps_active_connections() {
+    init_logger();
+    let mut config = Config::new();
+    config.set("mpd_port", 0).unwrap();
+    config.set("bind_address", "127.0.0.1").unwrap();
+    config.set("mpd_max_connections", 1).unwrap();
+    let settings = Settings::with(config).unwrap();
+
+    let mut listener = MpdListener::new(&settings, HandlerClient::new(vec![]), IdleBus::new())
+        .await
+        .expect("Cannot bind MPD listener");
+    let address = listener.get_address().expect("Cannot get server address");
+    let connections = listener.connections();
+    assert_eq!(0, listener.connection_count());
+    tokio::spawn(async move { listener.run().await });
+
+    // First client is accepted
+    let mut client = Client::new(address.clone()).await;
+    assert_eq!(1, connections.count());
+
+    // Second client is closed right away
+    let mut rejected = TcpStream::connect(address.clone()).await.unwrap();
+    let mut read_buffer = [0; 32];
+    let read = timeout(Duration::from_millis(250), rejected.read(&mut read_buffer));
+    assert_eq!(0, read.await.expect("Read timeout").unwrap_or(0));
+
+    // Slot is released when the first client closes
+    client.send_command("close").await;
+    client.assert_no_response().await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(0, connections.count());
+    Client::new(address).await;
+}
+
+#[tokio::test]
+async fn it_returns_config_to_local_clients() {
+    init_logger();
+    let address = init_listener(vec![]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("config").await;
+    client
+        .assert_response(
+            "music_directory: internal\ncache_path: caches/\nartwork_cache_bytes: 0\n\
+            artwork_cache_limit: 524288000\nOK\n"
+                .to_string(),
+        )
+        .await;
+}
+
 #[tokio::test]
 async fn it_calls_custom_handler() {
     init_logger();
@@ -69,6 +168,12 @@ async fn it_calls_custom_handler() {
         .assert_response("volume: 20\nstate: pause\nOK\n".to_string())
         .await;
 
+    // Errors are reported with their ACK code
+    client.send_command("setvol 50").await;
+    client
+        .assert_response("ACK [52@0] {} problems setting volume\n".to_string())
+        .await;
+
     // Ping is still handled by the default handler
     client.send_command("ping").await;
     client.assert_response("OK\n".to_string()).await;
@@ -103,14 +208,274 @@ async fn it_supports_command_lists() {
         .await;
 }
 
+#[tokio::test]
+async fn it_aborts_command_lists_on_error() {
+    init_logger();
+
+    // Run custom handler
+    let (mut handler, pause_tx, is_paused) = CustomHandler::new();
+    tokio::spawn(async move { handler.run().await });
+
+    // Run listener
+    let address = init_listener(vec![pause_tx]).await;
+
+    let mut client = Client::new(address.clone()).await;
+    let status = "volume: 20\nstate: pause\n";
+    let ack = "ACK [52@1] {} problems setting volume\n";
+
+    // Commands after the failing one are not executed
+    for verbose in &[false, true] {
+        client
+            .send_commands(vec!["status", "setvol 10", "pause 1", "status"], *verbose)
+            .await;
+        match verbose {
+            true => client.assert_response(format!["{}list_OK\n{}", status, ack]),
+            false => client.assert_response(format!["{}{}", status, ack]),
+        }
+        .await;
+        assert!(!is_paused.load(Acquire));
+    }
+
+    // The connection is still usable
+    client.send_command("status").await;
+    client.assert_response(format!["{}OK\n", status]).await;
+}
+
+#[tokio::test]
+async fn it_notifies_database_idle_after_update() {
+    init_logger();
+    let bus = IdleBus::new();
+
+    // Run update handler
+    let (tx, rx) = mpsc::channel(16);
+    let handler_bus = bus.clone();
+    tokio::spawn(async move { run_update_handler(rx, handler_bus).await });
+
+    // Run listener
+    let address = init_listener_with_bus(vec![tx], bus).await;
+    let mut idle_client = Client::new(address.clone()).await;
+    let mut client = Client::new(address.clone()).await;
+
+    idle_client.send_command("idle database").await;
+    idle_client.assert_no_response().await;
+
+    client.send_command("update").await;
+    client
+        .assert_response("updating_db: 1\nOK\n".to_string())
+        .await;
+    idle_client
+        .assert_response("changed: database\nOK\n".to_string())
+        .await;
+}
+
+#[tokio::test]
+async fn it_returns_idle_immediately_for_pending_changes() {
+    init_logger();
+    let bus = IdleBus::new();
+
+    // Run status handler, notifying a change while it is called
+    let (tx, rx) = mpsc::channel(16);
+    let handler_bus = bus.clone();
+    tokio::spawn(async move { run_status_handler(rx, handler_bus).await });
+
+    // Run listener
+    let address = init_listener_with_bus(vec![tx], bus).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("status").await;
+    client
+        .assert_response("state: play\nOK\n".to_string())
+        .await;
+
+    // Change happened before the idle command, it returns right away
+    client.send_command("idle player").await;
+    client
+        .assert_response("changed: player\nOK\n".to_string())
+        .await;
+
+    // Change was consumed by the previous idle
+    client.send_command("idle player").await;
+    client.assert_no_response().await;
+}
+
+#[tokio::test]
+async fn it_keeps_idling_with_keepalive() {
+    init_logger();
+    let bus = IdleBus::new();
+    let mut config = Config::new();
+    config.set("mpd_port", 0).unwrap();
+    config.set("bind_address", "127.0.0.1").unwrap();
+    config.set("idle_keepalive_secs", 1).unwrap();
+    let settings = Settings::with(config).unwrap();
+    assert_eq!(Some(Duration::from_secs(1)), settings.idle_keepalive());
+
+    let mut listener = MpdListener::new(&settings, HandlerClient::new(vec![]), bus.clone())
+        .await
+        .expect("Cannot bind MPD listener");
+    let address = listener.get_address().expect("Cannot get server address");
+    tokio::spawn(async move { listener.run().await });
+    let mut client = Client::new(address).await;
+
+    // Idle survives past the keepalive interval, no bytes are sent
+    client.send_command("idle player").await;
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    client.assert_no_response().await;
+    bus.notify(IdleSubsystem::Player);
+    client
+        .assert_response("changed: player\nOK\n".to_string())
+        .await;
+}
+
+#[tokio::test]
+async fn it_accepts_noidle_outside_of_idle() {
+    init_logger();
+    let address = init_listener(vec![]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("noidle").await;
+    client.assert_response("OK\n".to_string()).await;
+    client.send_command("ping").await;
+    client.assert_response("OK\n".to_string()).await;
+}
+
+#[tokio::test]
+async fn it_rejects_unhandled_commands() {
+    init_logger();
+    let address = init_listener(vec![]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("stats").await;
+    client
+        .assert_response("ACK [5@0] {} unsupported command: stats\n".to_string())
+        .await;
+    client.send_command("ping").await;
+    client.assert_response("OK\n".to_string()).await;
+}
+
+#[tokio::test]
+async fn it_rejects_albumart_without_artwork_handler() {
+    init_logger();
+    let (mut handler, tx, _) = CustomHandler::new();
+    tokio::spawn(async move { handler.run().await });
+    let address = init_listener(vec![tx]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("albumart spotify:album:id 0").await;
+    client
+        .assert_response("ACK [5@0] {} unsupported command: albumart\n".to_string())
+        .await;
+}
+
+#[tokio::test]
+async fn it_rejects_tag_edition() {
+    init_logger();
+    let address = init_listener(vec![]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("addtagid 1 artist Artist").await;
+    client
+        .assert_response("ACK [4@0] {} you don't have permission for \"addtagid\"\n".to_string())
+        .await;
+    client.send_command("cleartagid 1").await;
+    client
+        .assert_response("ACK [4@0] {} you don't have permission for \"cleartagid\"\n".to_string())
+        .await;
+    client.send_command("ping").await;
+    client.assert_response("OK\n".to_string()).await;
+}
+
+#[tokio::test]
+async fn it_closes_during_slow_commands() {
+    init_logger();
+
+    // Run slow handler
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move { run_slow_handler(rx, Duration::from_secs(5)).await });
+
+    // Run listener
+    let address = init_listener(vec![tx]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    client.send_command("status").await;
+    client.send_command("close").await;
+    client.assert_closed().await;
+}
+
+#[tokio::test]
+async fn it_runs_commands_sent_during_slow_commands() {
+    init_logger();
+
+    // Run slow handler
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move { run_slow_handler(rx, Duration::from_millis(100)).await });
+
+    // Run listener
+    let address = init_listener(vec![tx]).await;
+    let mut client = Client::new(address.clone()).await;
+
+    // Ping is only answered after status: an early OK would end the status response,
+    // and the client would read the status fields as the response to its ping
+    client.send_command("status").await;
+    client.send_command("ping").await;
+    client
+        .assert_response("state: pause\nOK\nOK\n".to_string())
+        .await;
+
+    // Command lists sent during a slow command are read completely
+    client.send_command("status").await;
+    client.send_commands(vec!["ping", "ping"], true).await;
+    client
+        .assert_response("state: pause\nOK\nlist_OK\nlist_OK\nOK\n".to_string())
+        .await;
+}
+
+#[tokio::test]
+async fn it_hides_disabled_tags() {
+    init_logger();
+
+    // Run custom handler
+    let (mut handler, tx, _) = CustomHandler::new();
+    tokio::spawn(async move { handler.run().await });
+
+    // Run listener
+    let address = init_listener(vec![tx]).await;
+    let mut client = Client::new(address.clone()).await;
+    let song = "file: internal/album/album/track/track\nArtist: Artist\nAlbum: Album\n\
+                Title: Title\nDate: 2020\nPos: 0\nId: 1\nTime: 60\nduration: 60\n";
+
+    client.send_command("currentsong").await;
+    client
+        .assert_response(format!["{}Track: 2\nOK\n", song])
+        .await;
+
+    client.send_command("tagtypes disable Track").await;
+    client.assert_response("OK\n".to_string()).await;
+    client.send_command("currentsong").await;
+    client.assert_response(format!["{}OK\n", song]).await;
+
+    client.send_command("tagtypes clear").await;
+    client.assert_response("OK\n".to_string()).await;
+    client.send_command("tagtypes enable artist").await;
+    client.assert_response("OK\n".to_string()).await;
+    client.send_command("tagtypes").await;
+    client
+        .assert_response("tagtype: Artist\nOK\n".to_string())
+        .await;
+}
+
 fn init_logger() {
     let _ = pretty_env_logger::try_init();
 }
 
 async fn init_listener(handlers: Vec<Sender<HandlerInput>>) -> String {
-    let bus = IdleBus::new();
+    init_listener_with_bus(handlers, IdleBus::new()).await
+}
+
+async fn init_listener_with_bus(handlers: Vec<Sender<HandlerInput>>, bus: Arc<IdleBus>) -> String {
     let handlers = HandlerClient::new(handlers);
-    let mut listener = MpdListener::new(&test_settings(), handlers, bus).await;
+    let mut listener = MpdListener::new(&test_settings(), handlers, bus)
+        .await
+        .expect("Cannot bind MPD listener");
     let address = listener.get_address().expect("Cannot get server address");
     debug!("Listening on random port {}", address);
     tokio::spawn(async move { listener.run().await });
@@ -153,6 +518,21 @@ impl CustomHandler {
                         state: PlaybackStatus::Pause,
                     }))
                 }
+                Command::CurrentSong => Ok(HandlerOutput::from(SongResponse {
+                    file: Path::for_track("album", "track"),
+                    artist: "Artist".to_string(),
+                    album: "Album".to_string(),
+                    title: "Title".to_string(),
+                    date: Some(2020),
+                    pos: Some(0),
+                    id: Some(1),
+                    duration: SongDuration(Duration::from_secs(60)),
+                    track: Some(2),
+                    disc: None,
+                    spotify_id: None,
+                    isrc: None,
+                })),
+                Command::SetVolume(_) => Err(HandlerError::NoMixer),
                 _ => Err(HandlerError::Unsupported),
             };
             match input.resp.send(resp) {
@@ -165,6 +545,60 @@ impl CustomHandler {
     }
 }
 
+/// Mimics the update command of the spotify handler
+async fn run_update_handler(mut rx: Receiver<HandlerInput>, bus: Arc<IdleBus>) {
+    while let Some(input) = rx.recv().await {
+        let resp = match input.command {
+            Command::Update(_) => {
+                bus.notify(IdleSubsystem::Database);
+                Ok(HandlerOutput::from(UpdateResponse { updating_db: 1 }))
+            }
+            _ => Err(HandlerError::Unsupported),
+        };
+        if let Err(err) = input.resp.send(resp) {
+            warn!["Cannot send response: {:?}", err];
+        }
+    }
+}
+
+/// Notifies a player change while returning the status
+async fn run_status_handler(mut rx: Receiver<HandlerInput>, bus: Arc<IdleBus>) {
+    while let Some(input) = rx.recv().await {
+        let resp = match input.command {
+            Command::Status => {
+                bus.notify(IdleSubsystem::Player);
+                Ok(HandlerOutput::from(CustomStatus {
+                    volume: None,
+                    state: PlaybackStatus::Play,
+                }))
+            }
+            _ => Err(HandlerError::Unsupported),
+        };
+        if let Err(err) = input.resp.send(resp) {
+            warn!["Cannot send response: {:?}", err];
+        }
+    }
+}
+
+/// Waits before returning the status
+async fn run_slow_handler(mut rx: Receiver<HandlerInput>, delay: Duration) {
+    while let Some(input) = rx.recv().await {
+        let resp = match input.command {
+            Command::Status => {
+                tokio::time::sleep(delay).await;
+                Ok(HandlerOutput::from(CustomStatus {
+                    volume: None,
+                    state: PlaybackStatus::Pause,
+                }))
+            }
+            _ => Err(HandlerError::Unsupported),
+        };
+        if let Err(err) = input.resp.send(resp) {
+            warn!["Cannot send response: {:?}", err];
+        }
+    }
+}
+
 struct Client {
     stream: TcpStream,
 }
@@ -238,6 +672,21 @@ impl Client {
         }
     }
 
+    /// Check that the server closed the connection
+    async fn assert_closed(&mut self) {
+        let mut read_buffer = [0; 32];
+        let read_or_timeout = timeout(
+            Duration::from_millis(250),
+            self.stream.read(&mut read_buffer),
+        );
+        match read_or_timeout.await {
+            Err(_) => panic!("Connection is still open"),
+            Ok(Err(err)) => panic!("Read error {:?}", err),
+            Ok(Ok(0)) => {}
+            Ok(Ok(remaining)) => panic!("Found {} extra bytes", remaining),
+        }
+    }
+
     /// Reads the response and compare it to the expected one
     async fn assert_response(&mut self, expected: String) {
         // Wait for complete response and check equality